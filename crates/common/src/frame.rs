@@ -0,0 +1,109 @@
+//! Framing of messages on a connection.
+//!
+//! Each message is sent as a frame: its length as a protobuf varint, followed by the encoded message,
+//! the way prost's `encode_length_delimited` writes it. Frames follow each other on the connection,
+//! so it can carry any number of requests and responses, and closing it between two frames ends the exchange.
+
+use prost::Message;
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest message accepted, anything above is taken for a corrupted length
+pub const MAX_FRAME_LEN: u64 = 64 << 20;
+/// A varint holding a `u64` takes at most this many bytes
+const MAX_VARINT_LEN: usize = 10;
+
+/// Write `message` as a frame and flush it
+pub fn write_message(writer: &mut impl Write, message: &impl Message) -> io::Result<()> {
+    writer.write_all(&message.encode_length_delimited_to_vec())?;
+    writer.flush()
+}
+
+/// Read the next frame, `None` when the connection was closed before it started
+pub fn read_message<M: Message + Default>(reader: &mut impl Read) -> io::Result<Option<M>> {
+    let mut varint = Varint::default();
+    let mut byte = [0];
+    let len = loop {
+        if reader.read(&mut byte)? == 0 {
+            return match varint.len {
+                0 => Ok(None),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        if let Some(len) = varint.push(byte[0])? {
+            break len;
+        }
+    };
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    decode(&bytes).map(Some)
+}
+
+/// Write `message` as a frame and flush it
+pub async fn write_message_async(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &impl Message,
+) -> io::Result<()> {
+    writer
+        .write_all(&message.encode_length_delimited_to_vec())
+        .await?;
+    writer.flush().await
+}
+
+/// Read the next frame, `None` when the connection was closed before it started
+pub async fn read_message_async<M: Message + Default>(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<M>> {
+    let mut varint = Varint::default();
+    let len = loop {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && varint.len == 0 => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        if let Some(len) = varint.push(byte)? {
+            break len;
+        }
+    };
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).await?;
+    decode(&bytes).map(Some)
+}
+
+/// Length prefix being read, one byte at a time
+#[derive(Default)]
+struct Varint {
+    value: u64,
+    len: usize,
+}
+
+impl Varint {
+    /// Add the next byte, returning the frame length once the varint is complete
+    fn push(&mut self, byte: u8) -> io::Result<Option<usize>> {
+        self.value |= u64::from(byte & 0x7F) << (7 * self.len);
+        self.len += 1;
+        if byte & 0x80 != 0 {
+            return match self.len < MAX_VARINT_LEN {
+                true => Ok(None),
+                false => Err(invalid("frame length is not a valid varint".to_owned())),
+            };
+        }
+        if self.value > MAX_FRAME_LEN {
+            return Err(invalid(format!(
+                "frame of {} bytes exceeds the limit of {MAX_FRAME_LEN}",
+                self.value
+            )));
+        }
+        Ok(Some(self.value as usize))
+    }
+}
+
+fn decode<M: Message + Default>(bytes: &[u8]) -> io::Result<M> {
+    M::decode(bytes).map_err(|err| invalid(err.to_string()))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,26 @@
+//! Versions of the protocol, and the handshake agreeing on one.
+//!
+//! A client opens its connection with a [`Hello`](crate::Hello) holding [`PROTOCOL_VERSION`] and its
+//! capabilities. The server answers with the version both speak and its own capabilities, and turns
+//! down versions older than [`MIN_PROTOCOL_VERSION`] with `UNIMPLEMENTED`. Connections skipping the
+//! handshake are served as version 1, unless the server requires a token: the HELLO then has to carry
+//! it, and requests are answered `UNAUTHENTICATED` until it did.
+//!
+//! Version 1 is the framed protocol with request ids and statuses.
+
+/// Newest version spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version spoken by this build
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Requests may be sent before the previous ones are answered
+pub const PIPELINING: &str = "pipelining";
+/// `BACKUP` is served
+pub const BACKUP: &str = "backup";
+/// `STATS` is served
+pub const STATS: &str = "stats";
+
+/// Version agreed on with a peer speaking up to `version`, `None` when there is none
+pub fn negotiate(version: u32) -> Option<u32> {
+    (version >= MIN_PROTOCOL_VERSION).then(|| version.min(PROTOCOL_VERSION))
+}
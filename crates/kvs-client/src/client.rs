@@ -1,105 +1,145 @@
 use anyhow::Context;
 use common::message::Payload;
-use common::{Get, Message, MessageType, Response, Rm, Set};
+use common::{Backup, Get, Rm, Set, Stats, Status};
 use kvs::cli::{Action, GetCmd, RmCmd, SetCmd};
-use kvs::exit_program;
-use log::trace;
-use prost::Message as ProstMessage;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
+use kvs::{exit_program, EngineStats};
+use kvs_client::{KvsClient, ServerError};
+use std::net::SocketAddr;
+
+/// Exit code of requests the server answered with `status`: 0 for `OK`, `10 + status` otherwise.
+/// Failures to reach the server at all exit with 1.
+fn exit_code(status: Status) -> i32 {
+    match status {
+        Status::Ok => 0,
+        status => 10 + status as i32,
+    }
+}
 
 fn main() -> anyhow::Result<()> {
     ::env_logger::init();
     let cli = <Cli as clap::Parser>::parse();
-    let server = cli.addr.parse::<SocketAddr>()?;
-    let mut server = TcpStream::connect(server)?;
-    log::info!("🌐 Connected to server [{}]", server.peer_addr()?);
-    if match cli.action {
-        Action::Set(SetCmd { key, value }) => {
-            log::debug!("✉️ Requesting -> Set {} = {}", key, value);
-            send(Payload::Set(Set { key, value }), &mut server)
-        }
-        Action::Get(GetCmd { key }) => {
-            log::debug!("✉️ Requesting -> Get {}", key);
-            send(Payload::Get(Get { key }), &mut server)
-        }
-        Action::Remove(RmCmd { key }) => {
-            log::debug!("✉️ Requesting -> Rm {}", key);
-            send(Payload::Rm(Rm { key }), &mut server)
+    let addr = cli.addr.parse::<SocketAddr>()?;
+    let outcome =
+        KvsClient::connect_with_token(addr, cli.token).and_then(|mut server| match cli.command {
+            Command::Action(Action::Set(SetCmd { key, value })) => {
+                log::debug!("✉️ Requesting -> Set {} = {}", key, value);
+                send(Payload::Set(Set { key, value }), &mut server)
+            }
+            Command::Action(Action::Get(GetCmd { key })) => {
+                log::debug!("✉️ Requesting -> Get {}", key);
+                send(Payload::Get(Get { key }), &mut server)
+            }
+            Command::Action(Action::Remove(RmCmd { key })) => {
+                log::debug!("✉️ Requesting -> Rm {}", key);
+                send(Payload::Rm(Rm { key }), &mut server)
+            }
+            Command::Backup { dir, since, .. } => {
+                log::debug!("✉️ Requesting -> Backup into {}", dir);
+                send(Payload::Backup(Backup { dir, since }), &mut server)
+            }
+            Command::Stats { json } => {
+                log::debug!("✉️ Requesting -> Stats");
+                stats(json, &mut server)
+            }
+        });
+    match outcome {
+        Ok(status) => exit_program(exit_code(status)),
+        Err(err) => {
+            eprintln!("❌ {err:#}");
+            // A refused handshake is an answer of the server
+            match err.downcast_ref::<ServerError>() {
+                Some(err) => exit_program(exit_code(err.status)),
+                None => exit_program(1),
+            }
         }
     }
-    .is_err()
-    {
-        exit_program(1);
-    }
-    exit_program(0);
 }
 
-fn send(payload: Payload, server: &mut TcpStream) -> anyhow::Result<()> {
-    let mut message_bytes: Vec<u8> = vec![];
-    let r#type = match payload {
-        Payload::Set { .. } => MessageType::Set as i32, // 0
-        Payload::Get { .. } => MessageType::Get as i32, // 1
-        Payload::Rm { .. } => MessageType::Rm as i32,   // 2
-    };
-    let message = Message {
-        r#type,
-        payload: Some(payload),
-    };
-    trace!("Message request -> {:#?}", message);
-    {
-        // Send message towards server
-        message
-            .encode(&mut message_bytes)
-            .context("failed to encode message into bytes")?;
-        server.write_all(&message_bytes)?;
-        server.flush()?;
-        log::debug!("Written {} bytes to server stream", message_bytes.len());
-        log::trace!("Bytes -> {message_bytes:?}");
-        server.shutdown(std::net::Shutdown::Write)?;
+/// Ask for the server's statistics, printed as text unless `json` is set
+fn stats(json: bool, server: &mut KvsClient) -> anyhow::Result<Status> {
+    let response = server.request(Payload::Stats(Stats {}))?;
+    if let Some(err) = ServerError::from_response(&response) {
+        eprintln!("❌ Server Error: {}", err.message);
+        return Ok(err.status);
+    }
+    let stats = response.value.unwrap_or_default();
+    if json {
+        println!("{stats}");
+    } else {
+        let stats: EngineStats =
+            serde_json::from_str(&stats).context("failed to parse statistics sent by server")?;
+        print!("{stats}");
     }
-    // Clear buffer, await response
-    message_bytes.clear();
-    {
-        // We depend on the server to shutdown the stream after it's finished sending a response
-        let bytes_read = server.read_to_end(&mut message_bytes)?;
-        log::debug!("Got {} bytes back ", bytes_read);
-        let response = Response::decode(&message_bytes[0..bytes_read])
-            .context("failed to decode message response from server")?;
-        if response.success {
+    Ok(Status::Ok)
+}
+
+fn send(payload: Payload, server: &mut KvsClient) -> anyhow::Result<Status> {
+    let get = matches!(payload, Payload::Get(_));
+    let response = server.request(payload)?;
+    match ServerError::from_response(&response) {
+        None => {
             if let Some(v) = response.value {
                 println!("{}", v);
-            } else {
-                // If we fetch an unset key, we can print <empty>
-                if r#type == MessageType::Get as i32 {
-                    eprintln!("Key not found");
-                }
-            }
-            // println!(
-            //     "✅ {} Success",
-            //     match MessageType::try_from(r#type).expect("message type is valid") {
-            //         MessageType::Get => "GET",
-            //         MessageType::Set => "SET",
-            //         MessageType::Rm => "RM",
-            //     }
-            // );
-        } else {
-            if let Some(err) = response.value {
-                eprintln!("❌ Server Error: {err}");
             }
+            Ok(Status::Ok)
+        }
+        // Fetching an unset key is an answer like any other
+        Some(ServerError {
+            status: Status::NotFound,
+            ..
+        }) if get => {
+            println!("Key not found");
+            Ok(Status::Ok)
+        }
+        Some(ServerError {
+            status: Status::NotFound,
+            ..
+        }) => {
+            eprintln!("Key not found");
+            Ok(Status::NotFound)
+        }
+        Some(err) => {
+            eprintln!("❌ Server Error: {}", err.message);
+            Ok(err.status)
         }
     }
-    Ok(())
 }
 
 #[derive(Debug, clap::Parser)]
 #[command(version)]
 struct Cli {
     #[clap(subcommand)]
-    action: Action,
+    command: Command,
     /// Server location
     #[arg(short, long, default_value = "127.0.0.1:4000")]
     // Propagate `--addr` to all subcommands
-    #[arg(global = true )]
+    #[arg(global = true)]
     addr: String,
+    /// Token of servers requiring one
+    #[arg(long, global = true, env = "KVS_AUTH_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Action(Action),
+    /// Back up the server's live store
+    Backup {
+        /// Backup directory, relative to the server's backup directory
+        #[arg(name = "DIR")]
+        dir: String,
+        /// Only copy what changed since the backup given by `--since`
+        #[arg(long, requires = "since")]
+        incremental: bool,
+        /// Manifest, or directory, of the earlier backup, relative to the server's backup directory
+        #[arg(long, requires = "incremental")]
+        since: Option<String>,
+    },
+    /// Show the server's engine statistics
+    Stats {
+        /// Print as JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
 }
@@ -0,0 +1,430 @@
+//! Clients of `kvs-server`, speaking the protobuf messages of the `common` crate.
+//!
+//! [`KvsClient`] blocks the calling thread, [`AsyncKvsClient`] runs on tokio. Both keep their connection
+//! open and send each request as a length-prefixed frame on it, see [`common::frame`].
+//!
+//! Requests can be pipelined: [`KvsClient::send`] does not wait for the response, which
+//! [`KvsClient::receive`] reads later on, and [`KvsClient::pipeline`] sends a whole batch at once.
+//! Every message carries a `request_id` picked by the client, counting up from 1 on each connection,
+//! and the server answers requests in order, echoing their id, which the clients check.
+//!
+//! Connecting starts with a HELLO agreeing on the protocol version, see [`common::protocol`]. Requests
+//! for features the server doesn't list among its capabilities are turned down without being sent.
+//! Servers requiring a token refuse the handshake with `UNAUTHENTICATED` unless it carries theirs, see
+//! [`KvsClient::connect_with_token`].
+
+use anyhow::{bail, Context};
+use common::frame::{read_message, read_message_async, write_message, write_message_async};
+use common::message::Payload;
+use common::protocol::{self, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use common::{Get, Hello, Message, MessageType, Response, Rm, Set, Stats, Status};
+use kvs::EngineStats;
+use log::trace;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufReader, BufWriter};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+/// Wrap `payload` into the message sent over the wire as request `request_id`
+pub fn message(request_id: u64, payload: Payload) -> Message {
+    let r#type = match payload {
+        Payload::Set { .. } => MessageType::Set,
+        Payload::Get { .. } => MessageType::Get,
+        Payload::Rm { .. } => MessageType::Rm,
+        Payload::Backup { .. } => MessageType::Backup,
+        Payload::Stats { .. } => MessageType::Stats,
+        Payload::Hello { .. } => MessageType::Hello,
+    };
+    Message {
+        r#type: r#type as i32,
+        payload: Some(payload),
+        request_id,
+    }
+}
+
+/// Client blocking on each request
+#[derive(Debug)]
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    pending: Pending,
+    server: Hello,
+}
+
+impl KvsClient {
+    /// Connect to the server listening on `addr`, and agree on the protocol with it
+    pub fn connect(addr: SocketAddr) -> anyhow::Result<Self> {
+        Self::connect_with_token(addr, None)
+    }
+
+    /// Connect to the server listening on `addr`, presenting `token` to servers requiring one
+    pub fn connect_with_token(addr: SocketAddr, token: Option<String>) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let mut client = KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            pending: Pending::default(),
+            server: Hello::default(),
+        };
+        client.server = agreed(client.request(hello(token))?)?;
+        Ok(client)
+    }
+
+    /// Protocol version agreed on, and the server's capabilities
+    pub fn server(&self) -> &Hello {
+        &self.server
+    }
+
+    /// Send `payload` over to the server and wait for its response
+    pub fn request(&mut self, payload: Payload) -> anyhow::Result<Response> {
+        self.send(payload)?;
+        self.receive()
+    }
+
+    /// Send `payload` over to the server without waiting for its response, returning its `request_id`
+    pub fn send(&mut self, payload: Payload) -> anyhow::Result<u64> {
+        supported(&self.server, &payload)?;
+        let message = self.pending.push(payload);
+        write_message(&mut self.writer, &message)?;
+        Ok(message.request_id)
+    }
+
+    /// Wait for the response to the oldest request sent and not yet received
+    pub fn receive(&mut self) -> anyhow::Result<Response> {
+        let response = read_message(&mut self.reader)?;
+        self.pending.pop(response)
+    }
+
+    /// Send all of `payloads` without waiting in between, and return their responses in the same order.
+    ///
+    /// Responses are read while requests are still being written, so that neither side stalls on a full
+    /// socket buffer however long the batch.
+    pub fn pipeline(&mut self, payloads: Vec<Payload>) -> anyhow::Result<Vec<Response>> {
+        if !offers(&self.server, protocol::PIPELINING) {
+            return payloads
+                .into_iter()
+                .map(|payload| self.request(payload))
+                .collect();
+        }
+        for payload in &payloads {
+            supported(&self.server, payload)?;
+        }
+        let messages: Vec<_> = payloads
+            .into_iter()
+            .map(|payload| self.pending.push(payload))
+            .collect();
+        let count = messages.len();
+        let writer = &mut self.writer;
+        thread::scope(|scope| {
+            let sender = scope.spawn(move || {
+                messages
+                    .iter()
+                    .try_for_each(|message| write_message(writer, message))
+            });
+            let responses: anyhow::Result<Vec<_>> = (0..count)
+                .map(|_| self.pending.pop(read_message(&mut self.reader)?))
+                .collect();
+            if responses.is_err() {
+                // Unblock the sender, the connection is of no use anymore
+                let _ = self.reader.get_ref().shutdown(Shutdown::Both);
+            }
+            sender.join().expect("pipeline sender panicked")?;
+            responses
+        })
+    }
+
+    /// Set `key` to `value`
+    pub fn set(&mut self, key: String, value: String) -> anyhow::Result<()> {
+        done(self.request(Payload::Set(Set { key, value }))?)
+    }
+
+    /// Value of `key`, `None` when it isn't set
+    pub fn get(&mut self, key: String) -> anyhow::Result<Option<String>> {
+        lookup(self.request(Payload::Get(Get { key }))?)
+    }
+
+    /// Remove `key`, failing with a `NOT_FOUND` [`ServerError`] when it isn't set
+    pub fn remove(&mut self, key: String) -> anyhow::Result<()> {
+        done(self.request(Payload::Rm(Rm { key }))?)
+    }
+
+    /// Statistics of the server's engine
+    pub fn stats(&mut self) -> anyhow::Result<EngineStats> {
+        stats(self.request(Payload::Stats(Stats {}))?)
+    }
+}
+
+/// Client running on tokio
+#[derive(Debug)]
+pub struct AsyncKvsClient {
+    reader: tokio::io::BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    pending: Pending,
+    server: Hello,
+}
+
+impl AsyncKvsClient {
+    /// Connect to the server listening on `addr`, and agree on the protocol with it
+    pub async fn connect(addr: SocketAddr) -> anyhow::Result<Self> {
+        Self::connect_with_token(addr, None).await
+    }
+
+    /// Connect to the server listening on `addr`, presenting `token` to servers requiring one
+    pub async fn connect_with_token(
+        addr: SocketAddr,
+        token: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let (reader, writer) = tokio::net::TcpStream::connect(addr).await?.into_split();
+        let mut client = AsyncKvsClient {
+            reader: tokio::io::BufReader::new(reader),
+            writer,
+            pending: Pending::default(),
+            server: Hello::default(),
+        };
+        client.server = agreed(client.request(hello(token)).await?)?;
+        Ok(client)
+    }
+
+    /// Protocol version agreed on, and the server's capabilities
+    pub fn server(&self) -> &Hello {
+        &self.server
+    }
+
+    /// Send `payload` over to the server and wait for its response
+    pub async fn request(&mut self, payload: Payload) -> anyhow::Result<Response> {
+        self.send(payload).await?;
+        self.receive().await
+    }
+
+    /// Send `payload` over to the server without waiting for its response, returning its `request_id`
+    pub async fn send(&mut self, payload: Payload) -> anyhow::Result<u64> {
+        supported(&self.server, &payload)?;
+        let message = self.pending.push(payload);
+        write_message_async(&mut self.writer, &message).await?;
+        Ok(message.request_id)
+    }
+
+    /// Wait for the response to the oldest request sent and not yet received
+    pub async fn receive(&mut self) -> anyhow::Result<Response> {
+        let response = read_message_async(&mut self.reader).await?;
+        self.pending.pop(response)
+    }
+
+    /// Send all of `payloads` without waiting in between, and return their responses in the same order
+    pub async fn pipeline(&mut self, payloads: Vec<Payload>) -> anyhow::Result<Vec<Response>> {
+        if !offers(&self.server, protocol::PIPELINING) {
+            let mut responses = Vec::with_capacity(payloads.len());
+            for payload in payloads {
+                responses.push(self.request(payload).await?);
+            }
+            return Ok(responses);
+        }
+        for payload in &payloads {
+            supported(&self.server, payload)?;
+        }
+        let messages: Vec<_> = payloads
+            .into_iter()
+            .map(|payload| self.pending.push(payload))
+            .collect();
+        let writer = &mut self.writer;
+        let send = async move {
+            for message in &messages {
+                write_message_async(writer, message).await?;
+            }
+            anyhow::Ok(())
+        };
+        let (reader, pending) = (&mut self.reader, &mut self.pending);
+        let receive = async move {
+            let mut responses = Vec::with_capacity(pending.ids.len());
+            while !pending.ids.is_empty() {
+                responses.push(pending.pop(read_message_async(reader).await?)?);
+            }
+            anyhow::Ok(responses)
+        };
+        let ((), responses) = tokio::try_join!(send, receive)?;
+        Ok(responses)
+    }
+
+    /// Set `key` to `value`
+    pub async fn set(&mut self, key: String, value: String) -> anyhow::Result<()> {
+        done(self.request(Payload::Set(Set { key, value })).await?)
+    }
+
+    /// Value of `key`, `None` when it isn't set
+    pub async fn get(&mut self, key: String) -> anyhow::Result<Option<String>> {
+        lookup(self.request(Payload::Get(Get { key })).await?)
+    }
+
+    /// Remove `key`, failing with a `NOT_FOUND` [`ServerError`] when it isn't set
+    pub async fn remove(&mut self, key: String) -> anyhow::Result<()> {
+        done(self.request(Payload::Rm(Rm { key })).await?)
+    }
+
+    /// Statistics of the server's engine
+    pub async fn stats(&mut self) -> anyhow::Result<EngineStats> {
+        stats(self.request(Payload::Stats(Stats {})).await?)
+    }
+}
+
+/// HELLO opening a connection
+fn hello(token: Option<String>) -> Payload {
+    Payload::Hello(Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: vec![protocol::PIPELINING.to_owned()],
+        token,
+    })
+}
+
+/// Protocol the server answered a HELLO with
+fn agreed(response: Response) -> anyhow::Result<Hello> {
+    if let Some(err) = ServerError::from_response(&response) {
+        return Err(anyhow::Error::new(err).context("handshake refused"));
+    }
+    let hello = response
+        .hello
+        .context("server answered HELLO without its protocol")?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.version) {
+        bail!(
+            "server agreed on protocol version {}, while this client speaks versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}",
+            hello.version
+        );
+    }
+    log::debug!(
+        "Protocol version {} with {:?}",
+        hello.version,
+        hello.capabilities
+    );
+    Ok(hello)
+}
+
+fn offers(server: &Hello, capability: &str) -> bool {
+    server.capabilities.iter().any(|c| c == capability)
+}
+
+/// Fail with `UNIMPLEMENTED` when `payload` needs a capability the server doesn't have
+fn supported(server: &Hello, payload: &Payload) -> anyhow::Result<()> {
+    let capability = match payload {
+        Payload::Backup(_) => protocol::BACKUP,
+        Payload::Stats(_) => protocol::STATS,
+        Payload::Set(_) | Payload::Get(_) | Payload::Rm(_) | Payload::Hello(_) => return Ok(()),
+    };
+    if offers(server, capability) {
+        return Ok(());
+    }
+    Err(ServerError {
+        status: Status::Unimplemented,
+        message: format!("server doesn't offer `{capability}`"),
+    }
+    .into())
+}
+
+/// Requests of a connection sent and not answered yet
+#[derive(Debug, Default)]
+struct Pending {
+    /// Last `request_id` handed out
+    last_id: u64,
+    /// `request_id` of the requests awaiting their response, oldest first
+    ids: VecDeque<u64>,
+}
+
+impl Pending {
+    /// Message for `payload`, under the next `request_id`
+    fn push(&mut self, payload: Payload) -> Message {
+        self.last_id += 1;
+        self.ids.push_back(self.last_id);
+        let message = message(self.last_id, payload);
+        trace!("Message request -> {:#?}", message);
+        message
+    }
+
+    /// Match `response`, `None` when the connection was closed, to the oldest pending request
+    fn pop(&mut self, response: Option<Response>) -> anyhow::Result<Response> {
+        // The server closes the connection instead of answering a request it cannot read
+        let response = response.context("server closed the connection without a response")?;
+        trace!("Message response -> {:#?}", response);
+        match self.ids.pop_front() {
+            Some(id) if id == response.request_id => Ok(response),
+            Some(id) => bail!(
+                "server answered request {} while request {id} was expected",
+                response.request_id
+            ),
+            None => bail!("server answered request {} never sent", response.request_id),
+        }
+    }
+}
+
+/// Request the server answered with another status than `OK`, or that the client didn't send
+/// as the server doesn't support it.
+///
+/// The helpers of the clients fail with it, inside their `anyhow::Error`, so that callers can tell
+/// failures apart with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl ServerError {
+    /// Error for `response`, `None` when its status is `OK`
+    pub fn from_response(response: &Response) -> Option<Self> {
+        // A status unknown to this client is a failure all the same
+        let status = Status::try_from(response.status).unwrap_or(Status::Internal);
+        match status {
+            Status::Ok => None,
+            _ => Some(ServerError {
+                status,
+                message: response
+                    .error
+                    .clone()
+                    .unwrap_or_else(|| status.as_str_name().to_owned()),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "server error ({}): {}",
+            self.status.as_str_name(),
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Turn a response into an error when the server failed
+fn done(response: Response) -> anyhow::Result<()> {
+    value(response).map(|_| ())
+}
+
+fn value(response: Response) -> anyhow::Result<Option<String>> {
+    match ServerError::from_response(&response) {
+        None => Ok(response.value),
+        Some(err) => Err(err.into()),
+    }
+}
+
+/// Value of a `GET` response, `None` when the key isn't set
+fn lookup(response: Response) -> anyhow::Result<Option<String>> {
+    match ServerError::from_response(&response) {
+        Some(ServerError {
+            status: Status::NotFound,
+            ..
+        }) => Ok(None),
+        _ => value(response),
+    }
+}
+
+fn stats(response: Response) -> anyhow::Result<EngineStats> {
+    match value(response)? {
+        Some(stats) => {
+            serde_json::from_str(&stats).context("failed to parse statistics sent by server")
+        }
+        None => bail!("server sent no statistics"),
+    }
+}
@@ -0,0 +1,412 @@
+use assert_cmd::prelude::*;
+use common::frame::{read_message, write_message};
+use common::message::Payload;
+use common::protocol::{self, PROTOCOL_VERSION};
+use common::{Backup, Get, Hello, Message, Response, Set, Status};
+use kvs::{KvStore, KvsEngine};
+use kvs_client::{AsyncKvsClient, KvsClient, ServerError};
+use std::fs;
+use std::net::TcpStream;
+use std::process::{Child, Command, ExitStatus};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Server killed when the test is over, pass or fail
+struct Server(Child);
+
+impl Server {
+    fn start(dir: &TempDir, args: &[&str]) -> Server {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Server(child)
+    }
+
+    /// Send SIGTERM and wait for the server to exit
+    fn terminate(&mut self) -> ExitStatus {
+        let status = Command::new("kill")
+            .args(["-TERM", &self.0.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        self.0.wait().unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn blocking_client() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4012", "--backup-dir", "backups"],
+    );
+    let mut client = KvsClient::connect("127.0.0.1:4012".parse()?)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    let err = client.remove("key2".to_owned()).unwrap_err();
+    let err = err.downcast_ref::<ServerError>().unwrap();
+    assert_eq!(err.status, Status::NotFound);
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.stats()?.engine, "kvs");
+
+    // A second backup into the same directory is refused as such, not as a server failure
+    let backup = |dir: &str| {
+        Payload::Backup(Backup {
+            dir: dir.to_owned(),
+            since: None,
+        })
+    };
+    assert_eq!(client.request(backup("nightly"))?.status(), Status::Ok);
+    assert!(temp_dir
+        .path()
+        .join("backups/nightly/backup.manifest")
+        .exists());
+    let response = client.request(backup("nightly"))?;
+    assert_eq!(response.status(), Status::InvalidArgument);
+    assert!(response.error.unwrap().contains("already holds a backup"));
+    assert_eq!(response.value, None);
+
+    // Backups stay inside the backup directory
+    let outside = temp_dir.path().join("outside").display().to_string();
+    for dir in [outside.as_str(), "../outside", "nightly/../../outside"] {
+        let response = client.request(backup(dir))?;
+        assert_eq!(response.status(), Status::InvalidArgument);
+    }
+    assert!(!temp_dir.path().join("outside").exists());
+    Ok(())
+}
+
+// Many clients at once against the async server
+#[tokio::test]
+async fn async_client_and_server() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4013", "--async", "--threads", "2"],
+    );
+    let addr = "127.0.0.1:4013".parse()?;
+    let tasks: Vec<_> = (0..100)
+        .map(|id| {
+            tokio::spawn(async move {
+                let mut client = AsyncKvsClient::connect(addr).await?;
+                client.set(format!("key{id}"), format!("value{id}")).await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await??;
+    }
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    assert_eq!(
+        client.get("key42".to_owned()).await?,
+        Some("value42".to_owned())
+    );
+    assert_eq!(client.get("missing".to_owned()).await?, None);
+    let gets = (0..100)
+        .map(|id| {
+            Payload::Get(Get {
+                key: format!("key{id}"),
+            })
+        })
+        .collect();
+    for (id, response) in client.pipeline(gets).await?.into_iter().enumerate() {
+        assert_eq!(response.value, Some(format!("value{id}")));
+    }
+    client.remove("key42".to_owned()).await?;
+    assert!(client.remove("key42".to_owned()).await.is_err());
+    assert_eq!(client.stats().await?.keys, 99);
+    Ok(())
+}
+
+// Values well past a single read, many requests over one connection, and the next connection
+// getting the only pool thread once the first is closed
+#[test]
+fn persistent_connection_with_large_values() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&temp_dir, &["--addr", "127.0.0.1:4014", "--threads", "1"]);
+    let addr = "127.0.0.1:4014".parse()?;
+    let value = "v".repeat(100 * 1024);
+    let mut client = KvsClient::connect(addr)?;
+    for id in 0..50 {
+        client.set(format!("key{id}"), format!("{value}{id}"))?;
+    }
+    for id in 0..50 {
+        assert_eq!(
+            client.get(format!("key{id}"))?,
+            Some(format!("{value}{id}"))
+        );
+    }
+    drop(client);
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.stats()?.keys, 50);
+    Ok(())
+}
+
+// A batch large enough to fill the socket buffers both ways, then requests sent ahead of their responses
+#[test]
+fn pipelined_requests() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&temp_dir, &["--addr", "127.0.0.1:4015"]);
+    let mut client = KvsClient::connect("127.0.0.1:4015".parse()?)?;
+    let value = "v".repeat(1024);
+    let sets = (0..2000)
+        .map(|id| {
+            Payload::Set(Set {
+                key: format!("key{id}"),
+                value: format!("{value}{id}"),
+            })
+        })
+        .collect();
+    let responses = client.pipeline(sets)?;
+    assert_eq!(responses.len(), 2000);
+    assert!(responses
+        .iter()
+        .all(|response| response.status() == Status::Ok));
+    // The HELLO of the connection went first
+    assert_eq!(responses[1999].request_id, 2001);
+
+    let first = client.send(Payload::Get(Get {
+        key: "key7".to_owned(),
+    }))?;
+    let second = client.send(Payload::Get(Get {
+        key: "key8".to_owned(),
+    }))?;
+    let response = client.receive()?;
+    assert_eq!(response.request_id, first);
+    assert_eq!(response.value, Some(format!("{value}7")));
+    let response = client.receive()?;
+    assert_eq!(response.request_id, second);
+    assert_eq!(response.value, Some(format!("{value}8")));
+    Ok(())
+}
+
+// The handshake, and what the server makes of versions and operations it doesn't know
+// An idle connection is closed, handing its pool thread over to the next one
+#[test]
+fn idle_connection_closed() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let _server = Server::start(
+        &temp_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4020",
+            "--threads",
+            "1",
+            "--idle-timeout",
+            "1",
+        ],
+    );
+    let mut idle = TcpStream::connect("127.0.0.1:4020")?;
+    thread::sleep(Duration::from_secs(2));
+    assert!(read_message::<Response>(&mut idle)?.is_none());
+
+    let mut client = KvsClient::connect("127.0.0.1:4020".parse()?)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn protocol_negotiation() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(&temp_dir, &["--addr", "127.0.0.1:4016"]);
+    let mut client = KvsClient::connect("127.0.0.1:4016".parse()?)?;
+    assert_eq!(client.server().version, PROTOCOL_VERSION);
+    assert!(client
+        .server()
+        .capabilities
+        .contains(&protocol::STATS.to_owned()));
+    // Without a backup directory, backups are turned down before reaching the server
+    assert!(!client
+        .server()
+        .capabilities
+        .contains(&protocol::BACKUP.to_owned()));
+    let err = client
+        .request(Payload::Backup(Backup {
+            dir: "nightly".to_owned(),
+            since: None,
+        }))
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ServerError>().unwrap().status,
+        Status::Unimplemented
+    );
+    // Free the pool thread it holds
+    drop(client);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4016")?;
+    let mut exchange = |message: Message| -> anyhow::Result<Response> {
+        write_message(&mut stream, &message)?;
+        Ok(read_message(&mut stream)?.unwrap())
+    };
+    // Without a HELLO, version 1 it is
+    let response = exchange(kvs_client::message(
+        1,
+        Payload::Get(Get {
+            key: "key".to_owned(),
+        }),
+    ))?;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = exchange(kvs_client::message(
+        2,
+        Payload::Hello(Hello {
+            version: protocol::MIN_PROTOCOL_VERSION - 1,
+            capabilities: vec![],
+            token: None,
+        }),
+    ))?;
+    assert_eq!(response.status(), Status::Unimplemented);
+    assert!(response.error.unwrap().contains("protocol version 0"));
+
+    let response = exchange(kvs_client::message(
+        3,
+        Payload::Hello(Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: vec!["watch".to_owned()],
+            token: None,
+        }),
+    ))?;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.hello.unwrap().version, PROTOCOL_VERSION);
+
+    // An operation from a newer protocol, its payload unknown here
+    let response = exchange(Message {
+        r#type: 42,
+        payload: None,
+        request_id: 4,
+    })?;
+    assert_eq!(response.status(), Status::Unimplemented);
+    assert_eq!(response.request_id, 4);
+    assert!(response.error.unwrap().contains("unsupported operation"));
+
+    // Clients ignoring the capabilities get the same answer from the server
+    let response = exchange(kvs_client::message(
+        5,
+        Payload::Backup(Backup {
+            dir: "nightly".to_owned(),
+            since: None,
+        }),
+    ))?;
+    assert_eq!(response.status(), Status::Unimplemented);
+    assert!(!temp_dir.path().join("nightly").exists());
+    Ok(())
+}
+
+// SIGTERM closes idle connections, syncs the engine and persists the index, then exits 0
+#[test]
+fn graceful_shutdown() -> anyhow::Result<()> {
+    for (port, mode) in [(4017, None), (4018, Some("--async"))] {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = format!("127.0.0.1:{port}");
+        let mut args = vec!["--addr", &addr];
+        args.extend(mode);
+        let mut server = Server::start(&temp_dir, &args);
+        let mut client = KvsClient::connect(addr.parse()?)?;
+        for id in 0..100 {
+            client.set(format!("key{id}"), format!("value{id}"))?;
+        }
+        drop(client);
+        // Left open and idle
+        let mut idle = KvsClient::connect(addr.parse()?)?;
+
+        assert!(server.terminate().success());
+        assert!(idle.get("key1".to_owned()).is_err());
+        assert!(temp_dir.path().join("kv_memory.index").exists());
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    }
+    Ok(())
+}
+
+// A server configured with a token, and its data directory, by a config file
+#[test]
+fn token_authentication() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let config = temp_dir.path().join("kvs-server.toml");
+    fs::write(
+        &config,
+        format!(
+            "[listener]\naddr = \"127.0.0.1:4019\"\n\n[data]\ndir = {:?}\n\n[auth]\ntoken = \"secret\"\n",
+            data_dir.display().to_string()
+        ),
+    )?;
+    let _server = Server::start(&temp_dir, &["--config", &config.display().to_string()]);
+    let addr = "127.0.0.1:4019".parse()?;
+
+    let err = KvsClient::connect(addr).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ServerError>().unwrap().status,
+        Status::Unauthenticated
+    );
+    let err = KvsClient::connect_with_token(addr, Some("guess".to_owned())).unwrap_err();
+    assert!(err
+        .downcast_ref::<ServerError>()
+        .unwrap()
+        .message
+        .contains("wrong token"));
+
+    // Nothing is served before the HELLO
+    let mut stream = TcpStream::connect(addr)?;
+    let get = kvs_client::message(
+        1,
+        Payload::Get(Get {
+            key: "key".to_owned(),
+        }),
+    );
+    write_message(&mut stream, &get)?;
+    let response: Response = read_message(&mut stream)?.unwrap();
+    assert_eq!(response.status(), Status::Unauthenticated);
+    assert_eq!(response.request_id, 1);
+    drop(stream);
+
+    let mut client = KvsClient::connect_with_token(addr, Some("secret".to_owned()))?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    drop(client);
+    // The store went into the configured directory, not the current one
+    let logs = |dir: &std::path::Path| -> anyhow::Result<usize> {
+        Ok(fs::read_dir(dir)?
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(".log")
+            })
+            .count())
+    };
+    assert!(logs(&data_dir)? > 0);
+    assert_eq!(logs(temp_dir.path())?, 0);
+
+    Command::cargo_bin("kvs-client")?
+        .args(["--addr", "127.0.0.1:4019", "get", "key"])
+        .env("KVS_AUTH_TOKEN", "guess")
+        .assert()
+        .failure()
+        .code(17);
+    Command::cargo_bin("kvs-client")?
+        .args([
+            "--addr",
+            "127.0.0.1:4019",
+            "--token",
+            "secret",
+            "get",
+            "key",
+        ])
+        .assert()
+        .success()
+        .stdout("value\n");
+    Ok(())
+}
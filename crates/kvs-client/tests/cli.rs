@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::contains;
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "get", "key"])
+        .args(["--addr", "invalid-addr", "get", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--unknown-flag", "get", "key"])
+        .args(["--unknown-flag", "get", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,42 +53,42 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "set", "key", "value"])
+        .args(["--addr", "invalid-addr", "set", "key", "value"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--unknown-flag", "get", "key"])
+        .args(["--unknown-flag", "get", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -98,35 +99,35 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "rm", "key"])
+        .args(["--addr", "invalid-addr", "rm", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--unknown-flag", "rm", "key"])
+        .args(["--unknown-flag", "rm", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -137,7 +138,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -148,7 +149,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -159,7 +160,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -171,13 +172,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -191,15 +193,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -210,39 +213,56 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// The engine of an existing directory is read from its manifest, whatever files it holds
+#[test]
+fn cli_engine_from_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    drop(kvs::LsmStore::open(temp_dir.path()).unwrap());
+    fs::remove_file(temp_dir.path().join("lsm.wal")).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .code(10);
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value1"])
+        .args(["--addr", addr, "set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success();
@@ -250,7 +270,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -258,7 +278,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value2"])
+        .args(["--addr", addr, "set", "key1", "value2"])
         .current_dir(&temp_dir)
         .assert()
         .success();
@@ -266,7 +286,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -274,7 +294,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -282,15 +302,16 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key2"])
+        .args(["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
+        .failure()
+        .code(11)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key2", "value3"])
+        .args(["--addr", addr, "set", "key2", "value3"])
         .current_dir(&temp_dir)
         .assert()
         .success();
@@ -298,7 +319,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key1"])
+        .args(["--addr", addr, "rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success();
@@ -311,26 +332,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -343,6 +365,54 @@ fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
 
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4007");
+}
+
+// Clients connecting all at once are served by each pool
+#[test]
+fn cli_access_server_thread_pools() {
+    for (pool, addr) in [
+        ("naive", "127.0.0.1:4009"),
+        ("shared-queue", "127.0.0.1:4010"),
+        ("rayon", "127.0.0.1:4011"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--pool", pool, "--threads", "2"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let clients: Vec<_> = (0..8)
+            .map(|id| {
+                thread::spawn(move || {
+                    Command::cargo_bin("kvs-client")
+                        .unwrap()
+                        .args(["--addr", addr, "set", &format!("key{id}"), "value"])
+                        .assert()
+                        .success();
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        for id in 0..8 {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(["--addr", addr, "get", &format!("key{id}")])
+                .assert()
+                .success()
+                .stdout("value\n");
+        }
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
 #[test]
 #[ignore = r#"Error: IO error: could not acquire lock on '/tmp/.tmp1srY2h/db': 
 Os { code: 11, kind: WouldBlock, message: "Resource temporarily unavailable" }
@@ -350,3 +420,158 @@ Test works manually"#]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `--eviction` only makes sense in cache mode
+#[test]
+fn server_cli_eviction_requires_cache_mode() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--eviction", "lfu"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only applies in cache mode"));
+    // The limit can come from anywhere
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--print-config", "--eviction", "lfu"])
+        .env("KVS_LIMITS_MAX_KEYS", "10")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("max_keys = 10").and(contains("eviction = \"lfu\"")));
+}
+
+#[test]
+fn cli_access_server_cache_mode() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--max-keys", "1", "--eviction", "lru"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n").and(contains("1 evictions")));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "stats", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#""engine":"kvs""#).and(contains(r#""keys":1"#)));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Flags win over `KVS_*` variables, which win over the config file, which wins over the defaults
+#[test]
+fn server_cli_print_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs-server.toml");
+    fs::write(
+        &config,
+        "[listener]\naddr = \"127.0.0.1:5000\"\n\n[pool]\nthreads = 2\nkind = \"rayon\"\n\n[auth]\ntoken = \"secret\"\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--print-config", "--threads", "4"])
+        .env("KVS_CONFIG", &config)
+        .env("KVS_POOL_THREADS", "3")
+        .env("KVS_LISTENER_ADDR", "127.0.0.1:6000")
+        .env("KVS_ENGINE_CACHE_SIZE", "1024")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("addr = \"127.0.0.1:6000\"")
+                .and(contains("threads = 4"))
+                .and(contains("kind = \"rayon\""))
+                .and(contains("cache_size = 1024"))
+                .and(contains("compress_min_size = 64"))
+                .and(contains("token = \"<redacted>\""))
+                .and(contains("secret").not()),
+        );
+}
+
+// Only numeric, boolean and list settings are read as TOML from the environment
+#[test]
+fn server_cli_env_strings() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--print-config"])
+        .env("KVS_AUTH_TOKEN", "123456")
+        .env("KVS_DATA_DIR", "2024")
+        .env("KVS_ENGINE_NAME", "true")
+        .env("KVS_LISTENER_ASYNC", "true")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("dir = \"2024\"")
+                .and(contains("name = \"true\""))
+                .and(contains("async = true")),
+        );
+}
+
+// Mistakes in the config file or the environment are refused rather than ignored
+#[test]
+fn server_cli_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs-server.toml");
+    fs::write(&config, "[pool]\nthread = 2\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--print-config", "--config"])
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `thread`"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--print-config"])
+        .env("KVS_POOL_THREADS", "many")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
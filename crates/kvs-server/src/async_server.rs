@@ -0,0 +1,99 @@
+//! `kvs-server --async`: connections are served as tokio tasks, so idle ones cost next to nothing,
+//! while engine calls, which block on disk, run on tokio's blocking pool. A connection stays open for as
+//! many requests as its client sends.
+
+use crate::request::{handle_request, Session, Settings};
+use crate::shutdown::Shutdown;
+use crate::Engine;
+use anyhow::Context;
+use common::frame::{read_message_async, write_message_async};
+use common::Message;
+use std::net::TcpListener;
+use std::sync::Arc;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tracing::Instrument;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace};
+
+/// Accept connections on a tokio runtime of `threads` worker threads until shutting down and the
+/// connections are drained
+pub(crate) fn run(
+    server: TcpListener,
+    engine: Engine,
+    threads: u32,
+    shutdown: Shutdown,
+    settings: Arc<Settings>,
+) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads as usize)
+        .enable_io()
+        .build()?;
+    runtime.block_on(async {
+        server.set_nonblocking(true)?;
+        let server = tokio::net::TcpListener::from_std(server)?;
+        loop {
+            let (stream, _) = server.accept().await?;
+            // The stream goes through std and back to be registered
+            let stream = stream.into_std()?;
+            let Some(registration) = shutdown.open(&stream) else {
+                break;
+            };
+            let stream = TcpStream::from_std(stream)?;
+            let engine = engine.clone();
+            let session = Session::new(settings.clone());
+            let connection = uuid::Uuid::new_v4();
+            let span = tracing::info_span!("Connection", %connection);
+            tokio::spawn(
+                async move {
+                    let _registration = registration;
+                    if let Err(err) = serve_request(engine, stream, session).await {
+                        error!(%err)
+                    }
+                }
+                .instrument(span),
+            );
+        }
+        anyhow::Ok(())
+    })?;
+    // Connections keep being served on the runtime's threads meanwhile
+    shutdown.drain();
+    // Don't wait on connections left behind
+    runtime.shutdown_background();
+    Ok(())
+}
+
+/// Answer the requests of a connection, one frame after the other, until the client closes it.
+/// Pipelined requests are handled in order, like on the thread pools
+async fn serve_request(
+    engine: Engine,
+    stream: TcpStream,
+    mut session: Session,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut served = 0_u64;
+    while let Some(request) = read_message_async::<Message>(&mut reader)
+        .await
+        .context("🚨 Server cannot decode request")?
+    {
+        let request_id = request.request_id;
+        let span = tracing::info_span!("Request", request_id);
+        if let Some(refusal) = span.in_scope(|| session.admit(&request)) {
+            write_message_async(&mut writer, &refusal).await?;
+            continue;
+        }
+        let mut engine = engine.clone();
+        let settings = session.settings.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let _span = span.entered();
+            handle_request(&mut engine, request, &settings)
+        })
+        .await??;
+        write_message_async(&mut writer, &response).await?;
+        served += 1;
+        trace!(request_id, "Request completed 🚀");
+    }
+    debug!("Connection closed after {served} requests");
+    Ok(())
+}
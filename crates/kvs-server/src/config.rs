@@ -0,0 +1,362 @@
+//! Server configuration, gathered from a TOML file, `KVS_*` environment variables and command-line flags.
+//!
+//! Each setting is taken from the first of these to have it:
+//! 1. its command-line flag,
+//! 2. the environment variable `KVS_<SECTION>_<KEY>`, e.g. `KVS_POOL_THREADS=8`. Values of numeric, boolean and
+//!    list settings are read as TOML (`8`, `true`, `["a.key", "b.key"]`), the others taken as they are,
+//! 3. the file given by `--config`, or the `KVS_CONFIG` variable,
+//! 4. its default.
+//!
+//! Unknown sections and keys in the file, and unknown keys of known sections in the environment, are errors
+//! rather than silently ignored. `KVS_ENCRYPTION_KEY` isn't a setting but a secret, read when opening the store.
+
+use crate::KvsServer;
+use anyhow::{bail, Context};
+use kvs::cache_mode::EvictionPolicy;
+use kvs::thread_pool::PoolKind;
+use kvs::Codec;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs};
+use toml::{Table, Value};
+
+/// Prefix of the environment variables overriding settings
+const ENV_PREFIX: &str = "KVS_";
+/// Variable naming the config file when `--config` isn't given
+const CONFIG_ENV: &str = "KVS_CONFIG";
+/// Shown by `--print-config` in place of secrets
+const REDACTED: &str = "<redacted>";
+/// Settings whose `KVS_*` variables are read as TOML, every other one is a string
+const TYPED_SETTINGS: [&str; 11] = [
+    "listener.async",
+    "listener.shutdown_timeout",
+    "listener.idle_timeout",
+    "engine.cache_size",
+    "engine.blob_threshold",
+    "engine.compress_min_size",
+    "engine.previous_key_files",
+    "pool.threads",
+    "limits.max_memory",
+    "limits.max_keys",
+    "limits.ttl",
+];
+
+/// Effective configuration of the server
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) listener: Listener,
+    pub(crate) data: Data,
+    pub(crate) engine: Engine,
+    pub(crate) pool: Pool,
+    pub(crate) limits: Limits,
+    pub(crate) logging: Logging,
+    pub(crate) auth: Auth,
+}
+
+/// Where and how connections are accepted
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Listener {
+    /// Socket v4 or v6 -> IP:PORT
+    pub(crate) addr: String,
+    /// Serve connections as tokio tasks rather than on a thread pool
+    #[serde(rename = "async")]
+    pub(crate) async_mode: bool,
+    /// Seconds open connections get to finish on SIGINT or SIGTERM
+    pub(crate) shutdown_timeout: u64,
+    /// Seconds a connection of the thread-pool server may stay silent before it's closed, 0 for never
+    pub(crate) idle_timeout: u64,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            addr: "127.0.0.1:4000".to_owned(),
+            async_mode: false,
+            shutdown_timeout: 10,
+            idle_timeout: 300,
+        }
+    }
+}
+
+/// Where the store, and its backups, live
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Data {
+    pub(crate) dir: PathBuf,
+    /// Root of the paths BACKUP requests name, backups are turned down when unset
+    pub(crate) backup_dir: Option<PathBuf>,
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        Data {
+            dir: PathBuf::from("."),
+            backup_dir: None,
+        }
+    }
+}
+
+/// Engine picked, and its tuning
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Engine {
+    /// kvs, sled or lsm
+    pub(crate) name: String,
+    pub(crate) cache_size: u64,
+    pub(crate) blob_threshold: Option<u64>,
+    #[serde(with = "value_enum")]
+    pub(crate) compression: Codec,
+    pub(crate) compress_min_size: u64,
+    pub(crate) key_file: Option<PathBuf>,
+    pub(crate) previous_key_files: Vec<PathBuf>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine {
+            name: "kvs".to_owned(),
+            cache_size: 0,
+            blob_threshold: None,
+            compression: Codec::default(),
+            compress_min_size: 64,
+            key_file: None,
+            previous_key_files: vec![],
+        }
+    }
+}
+
+/// Threads serving connections
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Pool {
+    #[serde(with = "value_enum")]
+    pub(crate) kind: PoolKind,
+    /// One per CPU when unset
+    pub(crate) threads: Option<u32>,
+}
+
+/// Bounds turning the store into a cache, see `kvs::cache_mode`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Limits {
+    pub(crate) max_memory: Option<u64>,
+    pub(crate) max_keys: Option<u64>,
+    /// Seconds keys live after they were last set
+    pub(crate) ttl: Option<u64>,
+    /// Only allowed in cache mode, lru when unset
+    #[serde(with = "value_enum::option", skip_serializing_if = "Option::is_none")]
+    pub(crate) eviction: Option<EvictionPolicy>,
+}
+
+impl Limits {
+    /// Whether any limit is set, making the server a cache
+    pub(crate) fn cache_mode(&self) -> bool {
+        self.max_memory.is_some() || self.max_keys.is_some() || self.ttl.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Logging {
+    /// off, error, warn, info, debug or trace
+    pub(crate) level: String,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: "info".to_owned(),
+        }
+    }
+}
+
+/// Who may talk to the server
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Auth {
+    /// Token clients must present in their HELLO, anyone may connect when unset
+    pub(crate) token: Option<String>,
+}
+
+impl Config {
+    /// Configuration given by `cli`, the environment and the config file, over the defaults
+    pub(crate) fn load(cli: &KvsServer) -> anyhow::Result<Config> {
+        let path = cli
+            .config
+            .clone()
+            .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+        let mut table = match &path {
+            Some(path) => read(path)?,
+            None => Table::new(),
+        };
+        overlay_env(&mut table, env::vars())?;
+        let mut config: Config = table
+            .try_into()
+            .context("invalid settings in the environment")?;
+        config.overlay_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The configuration as TOML, secrets redacted
+    pub(crate) fn to_toml(&self) -> anyhow::Result<String> {
+        let mut shown = self.clone();
+        if shown.auth.token.is_some() {
+            shown.auth.token = Some(REDACTED.to_owned());
+        }
+        Ok(toml::to_string(&shown)?)
+    }
+
+    /// Level messages are logged from
+    pub(crate) fn log_level(&self) -> anyhow::Result<LevelFilter> {
+        LevelFilter::from_str(&self.logging.level)
+            .with_context(|| format!("invalid log level {:?}", self.logging.level))
+    }
+
+    fn overlay_cli(&mut self, cli: &KvsServer) {
+        fn set<T: Clone>(setting: &mut T, flag: &Option<T>) {
+            if let Some(value) = flag {
+                *setting = value.clone();
+            }
+        }
+        fn set_some<T: Clone>(setting: &mut Option<T>, flag: &Option<T>) {
+            if flag.is_some() {
+                setting.clone_from(flag);
+            }
+        }
+        set(&mut self.listener.addr, &cli.socket);
+        if cli.async_mode {
+            self.listener.async_mode = true;
+        }
+        set(&mut self.listener.shutdown_timeout, &cli.shutdown_timeout);
+        set(&mut self.listener.idle_timeout, &cli.idle_timeout);
+        set(&mut self.data.dir, &cli.dir);
+        set_some(&mut self.data.backup_dir, &cli.backup_dir);
+        set(&mut self.engine.name, &cli.engine);
+        set(&mut self.engine.cache_size, &cli.cache_size);
+        set_some(&mut self.engine.blob_threshold, &cli.blob_threshold);
+        set(&mut self.engine.compression, &cli.compression);
+        set(&mut self.engine.compress_min_size, &cli.compress_min_size);
+        set_some(&mut self.engine.key_file, &cli.key_file);
+        if !cli.previous_key_file.is_empty() {
+            self.engine
+                .previous_key_files
+                .clone_from(&cli.previous_key_file);
+        }
+        set(&mut self.pool.kind, &cli.pool);
+        set_some(&mut self.pool.threads, &cli.threads);
+        set_some(&mut self.limits.max_memory, &cli.max_memory);
+        set_some(&mut self.limits.max_keys, &cli.max_keys);
+        set_some(&mut self.limits.ttl, &cli.ttl);
+        set_some(&mut self.limits.eviction, &cli.eviction);
+        set(&mut self.logging.level, &cli.log_level);
+    }
+
+    /// Refuse what flags would have refused
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.pool.threads == Some(0) {
+            bail!("pool.threads must be at least 1");
+        }
+        if self.limits.eviction.is_some() && !self.limits.cache_mode() {
+            bail!("limits.eviction only applies in cache mode, set limits.max_memory, limits.max_keys or limits.ttl");
+        }
+        self.log_level()?;
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Table> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let table: Table = toml::from_str(&text)
+        .with_context(|| format!("failed to parse config file {}", path.display()))?;
+    // Catch mistakes in the file itself, before the environment has a say
+    Config::deserialize(table.clone())
+        .with_context(|| format!("invalid settings in config file {}", path.display()))?;
+    Ok(table)
+}
+
+/// Set the settings `vars` name in `table`. Variables of sections that don't exist aren't settings
+fn overlay_env(
+    table: &mut Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<()> {
+    const SECTIONS: [&str; 7] = [
+        "listener", "data", "engine", "pool", "limits", "logging", "auth",
+    ];
+    for (name, raw) in vars {
+        let Some(setting) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let setting = setting.to_lowercase();
+        let Some((section, key)) = SECTIONS.iter().find_map(|&section| {
+            let key = setting.strip_prefix(section)?.strip_prefix('_')?;
+            Some((section, key))
+        }) else {
+            continue;
+        };
+        let value = match TYPED_SETTINGS.contains(&format!("{section}.{key}").as_str()) {
+            true => format!("value = {raw}")
+                .parse::<Table>()
+                .with_context(|| format!("`{name}` doesn't hold a TOML value"))?
+                .remove("value")
+                .expect("just parsed"),
+            false => Value::String(raw),
+        };
+        let section = table
+            .entry(section)
+            .or_insert_with(|| Value::Table(Table::new()));
+        match section {
+            Value::Table(section) => section.insert(key.to_owned(), value),
+            _ => bail!("`{name}` overrides a key of [{section}], which isn't a section"),
+        };
+    }
+    Ok(())
+}
+
+/// Enums written the way their command-line flags take them, e.g. `shared-queue`
+mod value_enum {
+    use clap::ValueEnum;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer, E: ValueEnum>(
+        value: &E,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let value = value.to_possible_value().expect("no variant is skipped");
+        serializer.serialize_str(value.get_name())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>, E: ValueEnum>(
+        deserializer: D,
+    ) -> Result<E, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        E::from_str(&name, true).map_err(D::Error::custom)
+    }
+
+    /// Same for optional settings
+    pub(super) mod option {
+        use clap::ValueEnum;
+        use serde::{Deserializer, Serializer};
+
+        pub(crate) fn serialize<S: Serializer, E: ValueEnum>(
+            value: &Option<E>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match value {
+                Some(value) => super::serialize(value, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>, E: ValueEnum>(
+            deserializer: D,
+        ) -> Result<Option<E>, D::Error> {
+            super::deserialize(deserializer).map(Some)
+        }
+    }
+}
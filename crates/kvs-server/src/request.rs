@@ -1,114 +1,320 @@
-use crate::Backend;
-use anyhow::{anyhow, bail, Context};
-use common::{message::Payload, Get, Message, Response, Rm, Set};
-use kvs::DbError;
-use prost::Message as ProstMessage;
+use anyhow::Context;
+use common::frame::{read_message, write_message};
+use common::protocol;
+use common::{
+    message::Payload, Backup, Get, Hello, Message, MessageType, Response, Rm, Set, Stats, Status,
+};
+use kvs::{DbError, KvsEngine};
 use std::{
-    io::{Read, Write},
-    net::{Shutdown, TcpStream},
+    io::{self, BufReader, BufWriter},
+    net::TcpStream,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace};
 
-pub(crate) fn serve_request(backend: &mut Backend, mut stream: TcpStream) -> anyhow::Result<()> {
-    // Note: If you're using `read_to_end`, you can simply use a 0-length `vec![]` that will be coerced to the
-    // correct length during read. Say N bytes are read, so the vec will be N bytes long
-    // In this case however, we write our code using 1024 bytes and use the `bytes_read` a crucial variable
-    // to slice into the buffer for correct decoding of the protobuf.
-    let mut buffer: Vec<u8> = vec![0_u8; 1024];
-    let bytes_read = stream.read(&mut buffer)?;
-    trace!("{bytes_read} bytes read : {:?}", &buffer[..bytes_read]);
-    if buffer.iter().all(|x| *x == 0) {
-        bail!("Request is zeroes 0.. aborting");
-    }
-    {
-        // Response 
-        let response: Vec<u8> = handle_request(backend, &buffer[..bytes_read])?;
-        drop(buffer);
-        stream.write_all(response.as_slice())?;
-        stream.flush()?;
-        stream.shutdown(Shutdown::Write)?;
+/// Answer the requests of a connection, one frame after the other, until the client closes it.
+///
+/// Clients may pipeline requests, sending more before the first is answered: they are still handled
+/// in order, and each response carries the `request_id` of its request. The connection holds on to
+/// its pool thread for as long as it stays open, so the pool size caps how many connections are
+/// served at once: a connection silent for the idle timeout is closed to hand its thread over.
+pub(crate) fn serve_request<E: KvsEngine>(
+    backend: &mut E,
+    stream: TcpStream,
+    mut session: Session,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(session.settings.idle_timeout)?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut served = 0_u64;
+    loop {
+        let request = match read_message::<Message>(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                debug!(
+                    "Closing the connection, idle for {:?}",
+                    session.settings.idle_timeout
+                );
+                break;
+            }
+            Err(err) => return Err(err).context("🚨 Server cannot decode request"),
+        };
+        let request_id = request.request_id;
+        let _span = tracing::info_span!("Request", request_id).entered();
+        let response = match session.admit(&request) {
+            Some(refusal) => refusal,
+            None => handle_request(backend, request, &session.settings)?,
+        };
+        write_message(&mut writer, &response)?;
+        served += 1;
         trace!("Request completed 🚀");
     }
+    debug!("Connection closed after {served} requests");
     Ok(())
 }
+/// Settings of the server every connection is served with
+#[derive(Debug, Default)]
+pub(crate) struct Settings {
+    /// Token connections have to present, if any
+    pub(crate) token: Option<String>,
+    /// Directory BACKUP requests write under, backups are turned down when unset
+    pub(crate) backup_dir: Option<PathBuf>,
+    /// How long a connection of the thread-pool server may stay silent before it's closed
+    pub(crate) idle_timeout: Option<Duration>,
+}
+
+/// What the server knows of a connection across its requests
+pub(crate) struct Session {
+    pub(crate) settings: Arc<Settings>,
+    authenticated: bool,
+}
+
+impl Session {
+    /// Session of a new connection to a server with `settings`
+    pub(crate) fn new(settings: Arc<Settings>) -> Self {
+        Session {
+            authenticated: settings.token.is_none(),
+            settings,
+        }
+    }
+
+    /// Response turning `request` down, `None` when it may be handled
+    pub(crate) fn admit(&mut self, request: &Message) -> Option<Response> {
+        // Nothing to check without a token, or once it was presented
+        let expected = self
+            .settings
+            .token
+            .as_deref()
+            .filter(|_| !self.authenticated)?;
+        let error = match &request.payload {
+            Some(Payload::Hello(Hello {
+                token: Some(token), ..
+            })) if same(token.as_bytes(), expected.as_bytes()) => {
+                self.authenticated = true;
+                return None;
+            }
+            Some(Payload::Hello(Hello { token: Some(_), .. })) => "wrong token",
+            _ => "this server requires a token, to be sent in a HELLO",
+        };
+        info!("🔒 Refusing request: {error}");
+        Some(Response {
+            status: Status::Unauthenticated as i32,
+            error: Some(error.to_owned()),
+            request_id: request.request_id,
+            ..Default::default()
+        })
+    }
+}
+
+/// Compare tokens in time independent of where they differ
+fn same(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 // This functions returns a Result, whose Err variant is supposed to notify our server
 // That some processing has failed. Kvs Backend errors are handled differently in that
-// the failure is logged, and the client is notified with a Response { success: false }
-fn handle_request(backend: &mut Backend, buffer: &[u8]) -> anyhow::Result<Vec<u8>> {
+// the failure is logged, and the client is notified with a Response carrying its status
+pub(crate) fn handle_request<E: KvsEngine>(
+    backend: &mut E,
+    request: Message,
+    settings: &Settings,
+) -> anyhow::Result<Response> {
     trace!("🔄 Processing request");
-    // Note, the type of request is embedded both in the `type` and `payload` fields of `Message`
-    let request: Message = Message::decode(buffer).with_context(|| {
-        error!("🚨 Failed to parse request from client",);
-        "🚨 Server cannot decode request"
-    })?;
-    let payload = request
-        .payload
-        .ok_or(anyhow!("🚨 Missing payload in Request"))?;
+    let request_id = request.request_id;
+    // Note, the type of request is embedded both in the `type` and `payload` fields of `Message`.
+    // A payload unknown to this server decodes as none at all, its type telling the two cases apart
+    let Some(payload) = request.payload else {
+        let (status, error) = match MessageType::try_from(request.r#type) {
+            Ok(_) => (Status::InvalidArgument, "missing payload".to_owned()),
+            Err(_) => (
+                Status::Unimplemented,
+                format!("unsupported operation (message type {})", request.r#type),
+            ),
+        };
+        error!("🚨 Rejecting request: {error}");
+        return Ok(Response {
+            status: status as i32,
+            error: Some(error),
+            request_id,
+            ..Default::default()
+        });
+    };
     // No matter error or success, we create a response to send back to the client
     let response = match payload {
         Payload::Set(Set { key, value }) => {
             trace!("🔄 Processing Set {key}->{value} request");
+            // A backend Err indicates that our KVS failed but we must also notify
+            // this to the client. We follow this logic with all other arms
             match backend.set(key, value) {
-                Ok(()) => Response {
-                    success: true,
-                    value: None,
-                },
-                // A backend Err indicates that our KVS failed but we must also notify
-                // this to the client. We follow this logic with all other arms
-                Err(e) => {
-                    error!("🚨 Backend failed to SET key-value pair: {}", e);
-                    Response {
-                        success: false,
-                        value: None,
-                    }
-                }
+                Ok(()) => ok(None),
+                Err(e) => failure("SET key-value pair", e),
             }
         }
         Payload::Get(Get { key }) => {
             trace!("🔄 Processing Get {key} request");
             match backend.get(key) {
-                Ok(Some(value)) => Response {
-                    success: true,
-                    value: Some(value),
-                },
-                Ok(None) => Response {
-                    success: true,
-                    value: Some(format!("Key not found")),
-                },
-                Err(e) => {
-                    error!("🚨 Backend failed to GET key-value pair: {}", e);
-                    Response {
-                        success: false,
-                        value: None,
-                    }
-                }
+                Ok(Some(value)) => ok(Some(value)),
+                Ok(None) => failure("GET key-value pair", DbError::KeyNotFound),
+                Err(e) => failure("GET key-value pair", e),
             }
         }
         Payload::Rm(Rm { key }) => {
             trace!("🔄 Processing Remove {key} request");
             match backend.remove(key) {
-                Ok(()) => Response {
-                    success: true,
-                    value: None,
+                Ok(()) => ok(None),
+                Err(e) => failure("RM key-value pair", e),
+            }
+        }
+        Payload::Backup(Backup { dir, since }) => {
+            info!("🔄 Processing Backup request into {dir}");
+            match backup_paths(settings, &dir, since.as_deref()) {
+                Ok((path, since)) => match backend.backup_to(&path, since.as_deref()) {
+                    Ok(manifest) => ok(Some(format!(
+                        "Backed up {} store to {dir}",
+                        manifest.engine
+                    ))),
+                    Err(e) => failure("BACKUP", e),
                 },
-                Err(e) => {
-                    error!("🚨 Backend failed to RM key-value pair: {}", e);
-                    Response {
-                        success: false,
-                        // TODO: How can we match on e if DbError doesn't implement PartialEq?
-                        value: match e {
-                            DbError::KeyNotFound => Some(format!("Key not found")),
-                            _ => None,
-                        },
-                    }
-                }
+                Err(refusal) => refusal,
+            }
+        }
+        Payload::Hello(Hello {
+            version,
+            capabilities,
+            ..
+        }) => {
+            info!("🤝 Client speaks protocol version {version}, with {capabilities:?}");
+            hello(version, settings)
+        }
+        Payload::Stats(Stats {}) => {
+            trace!("🔄 Processing Stats request");
+            match backend.stats() {
+                Ok(stats) => ok(Some(serde_json::to_string(&stats)?)),
+                Err(e) => failure("gather STATS", e),
             }
         }
     };
-    let mut buffer: Vec<u8> = vec![];
-    response
-        .encode(&mut buffer)
-        .context("Server failed to encode response back to client")?;
-    Ok(buffer)
+    Ok(Response {
+        request_id,
+        ..response
+    })
+}
+
+/// Where the backup of a BACKUP request into `dir`, incremental to `since`, goes and comes from.
+/// Both are taken relative to the backup directory, and may not leave it
+fn backup_paths(
+    settings: &Settings,
+    dir: &str,
+    since: Option<&str>,
+) -> Result<(PathBuf, Option<PathBuf>), Response> {
+    let Some(root) = &settings.backup_dir else {
+        debug!("Turning down BACKUP, no backup directory is configured");
+        return Err(Response {
+            status: Status::Unimplemented as i32,
+            error: Some("backups are disabled on this server".to_owned()),
+            ..Default::default()
+        });
+    };
+    let resolve = |path: &str| {
+        let path = Path::new(path);
+        let inside = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if path.as_os_str().is_empty() || !inside {
+            return Err(Response {
+                status: Status::InvalidArgument as i32,
+                error: Some(format!(
+                    "backup path {path:?} must be relative to the backup directory, without `..`"
+                )),
+                ..Default::default()
+            });
+        }
+        Ok(root.join(path))
+    };
+    Ok((resolve(dir)?, since.map(resolve).transpose()?))
+}
+
+/// Features of the protocol this server offers on top of the basic operations
+const CAPABILITIES: &[&str] = &[protocol::PIPELINING, protocol::STATS];
+
+/// Answer to the HELLO of a client speaking up to `version`
+fn hello(version: u32, settings: &Settings) -> Response {
+    let backup = settings.backup_dir.as_ref().map(|_| protocol::BACKUP);
+    match protocol::negotiate(version) {
+        Some(version) => Response {
+            hello: Some(Hello {
+                version,
+                capabilities: CAPABILITIES
+                    .iter()
+                    .copied()
+                    .chain(backup)
+                    .map(str::to_owned)
+                    .collect(),
+                token: None,
+            }),
+            ..ok(None)
+        },
+        None => {
+            debug!("Turning down protocol version {version}");
+            Response {
+                status: Status::Unimplemented as i32,
+                error: Some(format!(
+                    "protocol version {version} is unsupported, this server speaks versions {} to {}",
+                    protocol::MIN_PROTOCOL_VERSION,
+                    protocol::PROTOCOL_VERSION
+                )),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+fn ok(value: Option<String>) -> Response {
+    Response {
+        status: Status::Ok as i32,
+        value,
+        ..Default::default()
+    }
+}
+
+/// Response to a request the backend failed to `action`
+fn failure(action: &str, err: DbError) -> Response {
+    let status = status(&err);
+    match status {
+        Status::Internal => error!("🚨 Backend failed to {action}: {err}"),
+        _ => debug!("Backend refused to {action}: {err}"),
+    }
+    Response {
+        status: status as i32,
+        error: Some(err.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Status telling the client what kind of failure `err` is
+fn status(err: &DbError) -> Status {
+    match err {
+        DbError::KeyNotFound => Status::NotFound,
+        DbError::KeyExists(_) => Status::Conflict,
+        DbError::OverBudget { .. }
+        | DbError::RecordTooLarge { .. }
+        | DbError::Backup(_)
+        | DbError::Incompatible(_) => Status::InvalidArgument,
+        DbError::ReadOnly | DbError::Locked { .. } => Status::Unavailable,
+        _ => Status::Internal,
+    }
 }
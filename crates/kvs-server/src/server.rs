@@ -1,23 +1,71 @@
 use anyhow::bail;
+use config::{Auth, Config, Data, Listener, Pool};
 use env_logger::{Builder, Target};
-use kvs::{exit_program, KvStore, SledKvsEngine};
-use request::serve_request;
-use std::env;
-use std::ffi::OsString;
+use kvs::backup::{KVS_ENGINE, LSM_ENGINE, SLED_ENGINE};
+use kvs::cache_mode::{CacheOptions, EvictionPolicy};
+use kvs::thread_pool::{NaiveThreadPool, PoolKind, RayonThreadPool, SharedQueueThreadPool};
+use kvs::{
+    exit_program, CacheEngine, Codec, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, LsmStore,
+    Manifest, SharedEngine, SledKvsEngine, ThreadPool,
+};
+use request::{serve_request, Session, Settings};
+use shutdown::Shutdown;
+use std::fs;
 use std::net::{SocketAddr, TcpListener};
 use std::path::PathBuf;
-use tracing::{error, info};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info};
+mod async_server;
+mod config;
 mod request;
+mod shutdown;
 #[tracing::instrument]
 fn main() -> anyhow::Result<()> {
+    let cli = <KvsServer as clap::Parser>::parse();
+    let config = Config::load(&cli)?;
+    if cli.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
     Builder::new()
         .target(Target::Stderr)
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(config.log_level()?)
         .init();
-    let KvsServer { socket, engine, .. } = <KvsServer as clap::Parser>::parse();
+    let Config {
+        listener:
+            Listener {
+                addr: socket,
+                async_mode,
+                shutdown_timeout,
+                idle_timeout,
+            },
+        data: Data { dir, backup_dir },
+        engine:
+            config::Engine {
+                name: engine_str,
+                cache_size,
+                blob_threshold,
+                compression,
+                compress_min_size,
+                key_file,
+                previous_key_files,
+            },
+        pool: Pool {
+            kind: pool,
+            threads,
+        },
+        limits,
+        logging: _,
+        auth: Auth { token },
+    } = config;
     let socket: SocketAddr = socket.parse().expect("Failed to parse socket address");
-    let engine_str = engine.expect("clap default used");
-    let existing_db = match check_db(env::current_dir()?) {
+    fs::create_dir_all(&dir)?;
+    if let Some(backup_dir) = &backup_dir {
+        fs::create_dir_all(backup_dir)?;
+    }
+    let existing_db = match check_db(dir.clone()) {
         Ok(db) => db,
         Err(err) => {
             error!("{}", err);
@@ -26,85 +74,222 @@ fn main() -> anyhow::Result<()> {
     };
     let mut backend: Backend = match engine_str.to_lowercase().as_str() {
         "kvs" => {
-            if existing_db == Db::Sled {
+            let (encryption_key, previous_keys) =
+                EncryptionKey::load(key_file.as_deref(), &previous_key_files)?;
+            if existing_db == Db::Sled || existing_db == Db::Lsm {
                 exit_program(10);
             };
-            Backend::Kvs(KvStore::open(env::current_dir()?)?)
+            Backend::Kvs(KvStore::open_with(
+                dir,
+                KvStoreOptions {
+                    cache_size,
+                    blob_threshold,
+                    compression,
+                    compress_min_size,
+                    encryption_key,
+                    previous_keys,
+                    ..Default::default()
+                },
+            )?)
         }
         "sled" => {
-            if existing_db == Db::Kvs {
+            if existing_db == Db::Kvs || existing_db == Db::Lsm {
                 exit_program(11);
             };
-            Backend::Sled(SledKvsEngine::open(env::current_dir()?)?)
+            Backend::Sled(SledKvsEngine::open(dir)?)
+        }
+        "lsm" => {
+            if existing_db == Db::Kvs || existing_db == Db::Sled {
+                exit_program(12);
+            };
+            Backend::Lsm(LsmStore::open(dir)?)
         }
         _ => {
             error!("Unsupported Engine");
             exit_program(2);
         }
     };
+    if limits.cache_mode() {
+        let options = CacheOptions {
+            max_bytes: limits.max_memory,
+            max_keys: limits.max_keys,
+            policy: limits.eviction.unwrap_or_default(),
+            ttl: limits.ttl.map(Duration::from_secs),
+        };
+        backend = Backend::Cached(CacheEngine::new(backend.into(), options)?);
+    }
+    let threads = match threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism().map_or(1, |threads| threads.get() as u32),
+    };
     info!("Starting KVS server version {}", env!("CARGO_PKG_VERSION"));
+    let runtime = match async_mode {
+        true => "tokio".to_owned(),
+        false => format!("{pool:?} pool"),
+    };
     info!(
-        "Server configuration - IP:PORT: {socket}, Storage Engine: {}",
+        "Server configuration - IP:PORT: {socket}, Storage Engine: {}, Runtime: {runtime} of {threads} threads",
         engine_str
     );
 
     let server = TcpListener::bind(socket).expect("Failed to bind to socket");
-    for stream in server.incoming() {
-        let request_id = uuid::Uuid::new_v4();
-        let span = tracing::info_span!("Request Processing", %request_id);
-        let _span_enter = span.enter();
-        if let Err(err) = serve_request(&mut backend, stream?) {
-            error!(%err)
+    let shutdown =
+        Shutdown::on_signals(server.local_addr()?, Duration::from_secs(shutdown_timeout))?;
+    let mut engine = SharedEngine::new(backend.into());
+    let connections = engine.clone();
+    let settings = Arc::new(Settings {
+        token,
+        backup_dir,
+        idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout)),
+    });
+    match (async_mode, pool) {
+        (true, _) => async_server::run(server, connections, threads, shutdown, settings),
+        (false, PoolKind::Naive) => {
+            run::<NaiveThreadPool>(server, connections, threads, shutdown, settings)
+        }
+        (false, PoolKind::SharedQueue) => {
+            run::<SharedQueueThreadPool>(server, connections, threads, shutdown, settings)
         }
+        (false, PoolKind::Rayon) => {
+            run::<RayonThreadPool>(server, connections, threads, shutdown, settings)
+        }
+    }?;
+    engine.sync()?;
+    info!("Engine synced, bye 👋");
+    Ok(())
+}
+
+/// The engine as connections get it
+pub(crate) type Engine = SharedEngine<Box<dyn KvsEngine + Send>>;
+
+/// Accept connections, serving each of them on `P`, until shutting down and the connections are drained
+fn run<P: ThreadPool>(
+    server: TcpListener,
+    engine: Engine,
+    threads: u32,
+    shutdown: Shutdown,
+    settings: Arc<Settings>,
+) -> anyhow::Result<()> {
+    let pool = P::new(threads)?;
+    for stream in server.incoming() {
+        let stream = stream?;
+        let Some(registration) = shutdown.open(&stream) else {
+            break;
+        };
+        let mut engine = engine.clone();
+        let session = Session::new(settings.clone());
+        pool.spawn(move || {
+            let _registration = registration;
+            let connection = uuid::Uuid::new_v4();
+            let span = tracing::info_span!("Connection", %connection);
+            let _span_enter = span.enter();
+            if let Err(err) = serve_request(&mut engine, stream, session) {
+                error!(%err)
+            }
+        });
     }
+    shutdown.drain();
     Ok(())
 }
 
+/// Flags of `kvs-server`. Those left out fall back on the environment, the config file, then the defaults,
+/// see [`config`]
 #[derive(clap::Parser)]
 #[command(version)]
 struct KvsServer {
-    #[arg(long = "addr", short = 'a', default_value = "127.0.0.1:4000")]
+    #[arg(long)]
+    /// TOML file to read settings from, otherwise read from KVS_CONFIG.
+    config: Option<PathBuf>,
+    #[arg(long)]
+    /// Print the configuration in effect as TOML, then exit.
+    print_config: bool,
+    #[arg(long = "addr", short = 'a')]
     // Socket v4 or v6 -> IP:PORT
-    socket: String,
-    #[arg(long, short, default_value = "kvs")]
-    /// KV backend to use.
+    /// Address to listen on, 127.0.0.1:4000 by default.
+    socket: Option<String>,
+    #[arg(long)]
+    /// Directory of the store, the current directory by default.
+    dir: Option<PathBuf>,
+    #[arg(long)]
+    /// Directory BACKUP requests write under. Backups over the network are disabled when unset.
+    backup_dir: Option<PathBuf>,
+    #[arg(long, short)]
+    /// KV backend to use: kvs (default), sled or lsm.
     engine: Option<String>,
+    #[arg(long)]
+    /// Bytes of hot values the kvs engine keeps in memory, 0 (default) to disable the read cache.
+    cache_size: Option<u64>,
+    #[arg(long)]
+    /// Values longer than this many bytes are kept out of the kvs log, in blob files.
+    blob_threshold: Option<u64>,
+    #[arg(long, value_enum)]
+    /// Codec the kvs engine compresses logged values with, none by default.
+    compression: Option<Codec>,
+    #[arg(long)]
+    /// Values shorter than this many bytes (64 by default) are logged uncompressed.
+    compress_min_size: Option<u64>,
+    #[arg(long)]
+    /// File holding the key the kvs engine encrypts its files with, otherwise read from KVS_ENCRYPTION_KEY.
+    key_file: Option<PathBuf>,
+    #[arg(long)]
+    /// File holding a key being rotated away from, compaction re-seals its data with the current key.
+    previous_key_file: Vec<PathBuf>,
+    #[arg(long)]
+    /// Run as a cache: evict keys once keys and values take up more than this many bytes.
+    max_memory: Option<u64>,
+    #[arg(long)]
+    /// Run as a cache: evict keys once there are more than this many.
+    max_keys: Option<u64>,
+    #[arg(long, value_enum)]
+    /// Which keys a cache evicts first, lru by default. Only allowed along with a limit.
+    eviction: Option<EvictionPolicy>,
+    #[arg(long)]
+    /// Run as a cache: keys expire this many seconds after they were last set.
+    ttl: Option<u64>,
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    /// Threads serving connections, one per CPU by default. Ignored by the naive pool, which starts one per connection.
+    threads: Option<u32>,
+    #[arg(long, value_enum)]
+    /// Thread pool connections are served on, shared-queue by default.
+    pool: Option<PoolKind>,
+    #[arg(long = "async", conflicts_with = "pool")]
+    /// Serve connections as tokio tasks, with `--threads` worker threads, and run engine calls on tokio's blocking pool.
+    async_mode: bool,
+    #[arg(long)]
+    /// Seconds open connections get to finish their requests on SIGINT or SIGTERM (10 by default), before the engine is synced anyway.
+    shutdown_timeout: Option<u64>,
+    #[arg(long)]
+    /// Seconds a connection may stay silent before it's closed (300 by default, 0 for never), so idle clients don't hold on to pool threads. Ignored with `--async`.
+    idle_timeout: Option<u64>,
+    #[arg(long)]
+    /// Level messages are logged from: off, error, warn, info (default), debug or trace.
+    log_level: Option<String>,
 }
+// Only ever one of these, held for the lifetime of the server
+#[allow(clippy::large_enum_variant)]
 enum Backend {
     Kvs(KvStore),
     Sled(SledKvsEngine),
+    Lsm(LsmStore),
+    Cached(CacheEngine<Box<dyn KvsEngine + Send>>),
 }
 
-impl std::fmt::Display for Backend {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Backend::Kvs(_) => write!(f, "Kvs"),
-            Backend::Sled(_) => write!(f, "Sled"),
+impl From<Backend> for Box<dyn KvsEngine + Send> {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Kvs(kvs) => Box::new(kvs),
+            Backend::Sled(sled) => Box::new(sled),
+            Backend::Lsm(lsm) => Box::new(lsm),
+            Backend::Cached(cache) => Box::new(cache),
         }
     }
 }
 
-impl std::ops::Deref for Backend {
-    type Target = dyn kvs::KvsEngine;
-    fn deref(&self) -> &Self::Target {
-        match self {
-            Backend::Kvs(kvs) => kvs,
-            Backend::Sled(sled) => sled,
-        }
-    }
-}
-impl std::ops::DerefMut for Backend {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        match self {
-            Backend::Kvs(kvs) => kvs,
-            Backend::Sled(sled) => sled,
-        }
-    }
-}
 #[derive(Debug, Default, PartialEq)]
 enum Db {
     Sled,
     Kvs,
+    Lsm,
     #[default]
     None,
 }
@@ -115,27 +300,43 @@ fn check_db(dir: PathBuf) -> anyhow::Result<Db> {
     if !dir.is_dir() {
         bail!("Path is not a directory");
     }
+    if let Some(manifest) = Manifest::load(&dir)? {
+        info!(
+            "Found a {} store, format version {}",
+            manifest.engine, manifest.format_version
+        );
+        return match manifest.engine.as_str() {
+            KVS_ENGINE => Ok(Db::Kvs),
+            LSM_ENGINE => Ok(Db::Lsm),
+            SLED_ENGINE => Ok(Db::Sled),
+            engine => bail!("Unknown engine {engine:?} in the manifest"),
+        };
+    }
+    // Stores created before manifests existed are told apart by their file names
     let mut sled_db = false;
     let mut kvs_db = false;
+    let mut lsm_db = false;
     if let Ok(entries) = std::fs::read_dir(dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if entry.file_name() == OsString::from("db") {
-                    println!("Sled found");
-                    sled_db = true;
-                } else if entry.file_name().to_str().unwrap().starts_with("kv_")
-                    && entry.file_name().to_str().unwrap().ends_with(".log")
-                {
-                    println!("Kvs found");
-                    kvs_db = true;
-                }
+        for entry in entries.flatten() {
+            if entry.file_name() == "db" {
+                debug!("Found a sled store without a manifest");
+                sled_db = true;
+            } else if entry.file_name().to_str().unwrap().starts_with("kv_")
+                && entry.file_name().to_str().unwrap().ends_with(".log")
+            {
+                debug!("Found a kvs store without a manifest");
+                kvs_db = true;
+            } else if entry.file_name() == "lsm.levels" || entry.file_name() == "lsm.wal" {
+                debug!("Found an lsm store without a manifest");
+                lsm_db = true;
             }
         }
     }
-    match (sled_db, kvs_db) {
-        (true, true) => bail!("Both databases found. Abort"),
-        (true, false) => Ok(Db::Sled),
-        (false, true) => Ok(Db::Kvs),
-        (false, false) => Ok(Db::None),
+    match (sled_db, kvs_db, lsm_db) {
+        (true, false, false) => Ok(Db::Sled),
+        (false, true, false) => Ok(Db::Kvs),
+        (false, false, true) => Ok(Db::Lsm),
+        (false, false, false) => Ok(Db::None),
+        _ => bail!("Several databases found. Abort"),
     }
 }
@@ -0,0 +1,152 @@
+//! Graceful shutdown on SIGINT and SIGTERM.
+//!
+//! The first signal stops the listener from taking new connections, and ends the reading side of the
+//! open ones: requests being handled are still answered, then each connection closes. Once they all
+//! have, or the timeout passed, the engine is synced and the server exits 0. A second signal exits at once.
+
+use kvs::exit_program;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown as Direction, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+/// Shutdown state shared by the listener, the connections and the signal thread
+#[derive(Clone, Default)]
+pub(crate) struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// How long open connections get to close
+    timeout: Duration,
+    state: Mutex<State>,
+    /// Signalled whenever a connection closes
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    stopping: bool,
+    next_id: u64,
+    /// Handles on the open connections, to end their reads
+    connections: HashMap<u64, TcpStream>,
+}
+
+/// Registration of an open connection, removed when dropped
+pub(crate) struct Connection {
+    shutdown: Shutdown,
+    id: u64,
+}
+
+impl Shutdown {
+    /// Shut down on the first SIGINT or SIGTERM, giving open connections `timeout` to close.
+    /// `addr` is where the listener is bound, which the signal thread connects to so that a listener
+    /// blocked in `accept` notices
+    pub(crate) fn on_signals(mut addr: SocketAddr, timeout: Duration) -> anyhow::Result<Self> {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let shutdown = Shutdown {
+            inner: Arc::new(Inner {
+                timeout,
+                ..Default::default()
+            }),
+        };
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = shutdown.clone();
+        thread::spawn(move || {
+            let mut signals = signals.forever();
+            if let Some(signal) = signals.next() {
+                info!("🛑 Received signal {signal}, shutting down");
+                handle.stop();
+                if let Err(err) = TcpStream::connect(addr) {
+                    debug!("Failed to wake up the listener: {err}");
+                }
+            }
+            if let Some(signal) = signals.next() {
+                warn!("Received signal {signal} again, exiting without waiting");
+                exit_program(1);
+            }
+        });
+        Ok(shutdown)
+    }
+
+    /// Register `stream` as open until the returned guard is dropped, `None` when shutting down
+    pub(crate) fn open(&self, stream: &TcpStream) -> Option<Connection> {
+        let handle = match stream.try_clone() {
+            Ok(handle) => handle,
+            Err(err) => {
+                error!("Failed to register connection: {err}");
+                return None;
+            }
+        };
+        let mut state = self.state();
+        if state.stopping {
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(id, handle);
+        Some(Connection {
+            shutdown: self.clone(),
+            id,
+        })
+    }
+
+    /// Stop taking connections and end the reads of the open ones
+    fn stop(&self) {
+        let mut state = self.state();
+        state.stopping = true;
+        for stream in state.connections.values() {
+            // Connections already gone don't mind
+            let _ = stream.shutdown(Direction::Read);
+        }
+    }
+
+    /// Wait for the open connections to close, up to the timeout
+    pub(crate) fn drain(&self) {
+        let timeout = self.inner.timeout;
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+        while !state.connections.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                warn!(
+                    "{} connections still open after {timeout:?}, leaving them behind",
+                    state.connections.len()
+                );
+                return;
+            }
+            state = self
+                .inner
+                .closed
+                .wait_timeout(state, left)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        info!("All connections closed");
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.shutdown.state().connections.remove(&self.id);
+        self.shutdown.inner.closed.notify_all();
+    }
+}
@@ -1,77 +1,96 @@
 use criterion::{black_box, criterion_group, criterion_main, Bencher, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsEngine, LsmStore, SledKvsEngine};
 use rand::Rng;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
+const ENGINES: [&str; 3] = ["kvs", "sled", "lsm"];
+
+fn open_engine(engine: &str, path: &Path) -> Box<dyn KvsEngine> {
+    match engine {
+        "kvs" => Box::new(KvStore::open(path).unwrap()),
+        "sled" => Box::new(SledKvsEngine::open(path).unwrap()),
+        "lsm" => Box::new(LsmStore::open(path).unwrap()),
+        _ => unreachable!("unknown engine {engine}"),
+    }
+}
+
 fn cold_start_get(c: &mut Criterion) {
     let mut group = c.benchmark_group("GET");
 
-    group.bench_function("kvs: get key", |b: &mut Bencher<_>| {
-        // Setup for KVS
-        let temp_dir = TempDir::new().unwrap();
-        let mut store = KvStore::open(&temp_dir.path()).unwrap();
-        store
-            .set("key".to_string(), "some_get_val".to_string())
-            .unwrap();
-        b.iter(|| {
-            let _ = store.get("key".to_string()).unwrap();
-        })
-    });
+    for engine in ENGINES {
+        group.bench_function(format!("{engine}: get key"), |b: &mut Bencher<_>| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut store = open_engine(engine, temp_dir.path());
+            store
+                .set("key".to_string(), "some_get_val".to_string())
+                .unwrap();
+            b.iter(|| {
+                let _ = store.get("key".to_string()).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
 
-    group.bench_function("sled: get key", |b: &mut Bencher<_>| {
-        // Setup for SLED
+// Reads spread over a dataset bigger than the LSM memtable, so they have to go to disk
+fn get_many_keys(c: &mut Criterion) {
+    let mut group = c.benchmark_group("GET many");
+    let keys: Vec<String> = (0..20_000).map(|id| format!("key{id:05}")).collect();
+    for engine in ENGINES {
         let temp_dir = TempDir::new().unwrap();
-        let mut store = SledKvsEngine::open(&temp_dir.path()).unwrap();
-        store
-            .set("key".to_string(), "some_get_val".to_string())
-            .unwrap();
-        b.iter(|| {
-            let _ = store.get("key".to_string()).unwrap();
-        })
-    });
+        let mut store = open_engine(engine, temp_dir.path());
+        for key in &keys {
+            store.set(key.clone(), generate_random_string(256)).unwrap();
+        }
+        group.bench_function(format!("{engine}: random GET"), |b: &mut Bencher<_>| {
+            let mut rng = rand::thread_rng();
+            b.iter(|| {
+                let key = &keys[rng.gen_range(0..keys.len())];
+                black_box(store.get(key.clone()).unwrap());
+            })
+        });
+    }
     group.finish();
 }
+
 fn set_many_keys(c: &mut Criterion) {
     let mut group = c.benchmark_group("SET & RM");
-    let temp_dir = TempDir::new().unwrap();
-    let mut store = KvStore::open(&temp_dir.path()).unwrap();
     let test_data: Vec<(String, String)> = generate_test_data();
-    group.bench_function("kvs: SET", |b: &mut Bencher<_>| {
-        b.iter(|| {
-            black_box(for (k, v) in test_data.clone().into_iter() {
-                store.set(k, v).unwrap();
-            })
-        })
-    });
-    group.bench_function("kvs: REMOVE", |b| {
-        b.iter(|| {
-            black_box(for (k, _) in test_data.clone().into_iter() {
-                store.remove(k).unwrap();
-            })
-        })
-    });
-    let temp_dir = TempDir::new().unwrap();
-    let mut store = SledKvsEngine::open(&temp_dir.path()).unwrap();
-    let test_data: Vec<(String, String)> = generate_test_data();
-    group.bench_function("sled: SET", |b: &mut Bencher<_>| {
-        b.iter(|| {
-            black_box(for (k, v) in test_data.clone().into_iter() {
-                store.set(k, v).unwrap();
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = open_engine(engine, temp_dir.path());
+        group.bench_function(format!("{engine}: SET"), |b: &mut Bencher<_>| {
+            b.iter(|| {
+                for (k, v) in test_data.clone() {
+                    black_box(store.set(k, v)).unwrap();
+                }
             })
-        })
-    });
-    group.bench_function("sled: REMOVE", |b| {
-        b.iter(|| {
-            black_box(for (k, _) in test_data.clone().into_iter() {
-                store.remove(k).unwrap();
+        });
+        group.bench_function(format!("{engine}: REMOVE"), |b| {
+            // Only the removes are timed, re-seeding the store between iterations is not
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    for (k, v) in test_data.clone() {
+                        store.set(k, v).unwrap();
+                    }
+                    let start = Instant::now();
+                    for (k, _) in test_data.clone() {
+                        black_box(store.remove(k)).unwrap();
+                    }
+                    elapsed += start.elapsed();
+                }
+                elapsed
             })
-        })
-    });
+        });
+    }
     group.finish();
 }
 
 // Define a criterion group `kv_benches` with the benchmarks under it
-criterion_group!(kv_benches, cold_start_get, set_many_keys);
+criterion_group!(kv_benches, cold_start_get, get_many_keys, set_many_keys);
 // Run all benchmarks in a given group
 criterion_main!(kv_benches);
 
@@ -85,19 +104,21 @@ fn generate_random_string(length: usize) -> String {
 }
 
 fn generate_test_data() -> Vec<(String, String)> {
-    let mut data: Vec<(String, String)> = vec![];
+    const COUNT: usize = 100;
     const MIN_LENGTH: usize = 1;
-    const MAX_LENGTH: usize = 100_000;
+    const MAX_LENGTH: usize = 1_000;
     let mut rng = rand::thread_rng();
-
-    for item in &mut data {
-        let (k_len, v_len) = (
-            rng.gen_range(MIN_LENGTH..=MAX_LENGTH),
-            rng.gen_range(MIN_LENGTH..=MAX_LENGTH),
-        );
-        item.0 = generate_random_string(k_len); // Adjust the length as needed
-        item.1 = generate_random_string(v_len); // Adjust the length as needed
-    }
-
+    let mut data: Vec<(String, String)> = (0..COUNT)
+        .map(|_| {
+            let (k_len, v_len) = (
+                rng.gen_range(MIN_LENGTH..=MAX_LENGTH),
+                rng.gen_range(MIN_LENGTH..=MAX_LENGTH),
+            );
+            (generate_random_string(k_len), generate_random_string(v_len))
+        })
+        .collect();
+    // Removing a key twice fails, keep them unique
+    data.sort();
+    data.dedup_by(|a, b| a.0 == b.0);
     data
 }
@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, SharedEngine};
+use std::sync::mpsc;
+use tempfile::TempDir;
+
+const THREADS: [u32; 4] = [1, 2, 4, 8];
+const REQUESTS: usize = 1_000;
+
+/// Serve `REQUESTS` reads of a shared store on `pool`, the way `kvs-server` serves connections
+fn serve_reads<P: ThreadPool>(pool: &P, engine: &SharedEngine<KvStore>) {
+    let (sender, receiver) = mpsc::channel();
+    for id in 0..REQUESTS {
+        let (engine, sender) = (engine.clone(), sender.clone());
+        pool.spawn(move || {
+            let value = engine.get(format!("key{}", id % 100)).unwrap();
+            sender.send(value.is_some()).unwrap();
+        });
+    }
+    for _ in 0..REQUESTS {
+        assert!(receiver.recv().unwrap());
+    }
+}
+
+fn bench_pool<P: ThreadPool>(c: &mut Criterion, name: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = SharedEngine::new(KvStore::open(temp_dir.path()).unwrap());
+    for id in 0..100 {
+        engine
+            .set(format!("key{id}"), format!("value{id}"))
+            .unwrap();
+    }
+    let mut group = c.benchmark_group(format!("{name} pool"));
+    for threads in THREADS {
+        let pool = P::new(threads).unwrap();
+        group.bench_with_input(BenchmarkId::new("get", threads), &threads, |b, _| {
+            b.iter(|| serve_reads(&pool, &engine))
+        });
+    }
+    group.finish();
+}
+
+fn thread_pools(c: &mut Criterion) {
+    bench_pool::<NaiveThreadPool>(c, "naive");
+    bench_pool::<SharedQueueThreadPool>(c, "shared queue");
+    bench_pool::<RayonThreadPool>(c, "rayon");
+}
+
+criterion_group!(benches, thread_pools);
+criterion_main!(benches);
@@ -0,0 +1,412 @@
+//! Online backups and restores.
+//!
+//! A backup is a directory holding a copy of a store's data next to a `backup.manifest`
+//! describing it. For `KvStore` the data is the set of frozen log segments and their hint files,
+//! for sled it's a fresh sled database populated through sled's own export/import.
+//!
+//! Since `KvStore` segments never change once frozen, a `KvStore` backup can be *incremental*:
+//! taken relative to an earlier backup, it only copies the files that backup doesn't already hold
+//! and records the earlier one as its `parent`. Restoring walks the chain from the base backup
+//! up to the requested one. Backups refer to their parent by a path relative to their own directory,
+//! so a chain can be moved or mounted elsewhere as a whole.
+//!
+//! Every backup also holds a copy of the store's `MANIFEST`, listing the files of the backup, so that the
+//! restored store keeps its format version and creation options.
+
+use crate::manifest::{self, Manifest};
+use crate::{DbError, KvStore, LsmStore, Result};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Name of the manifest file inside a backup directory
+pub const MANIFEST_FILE: &str = "backup.manifest";
+/// Engine name recorded for [`KvStore`] backups
+pub const KVS_ENGINE: &str = "kvs";
+/// Engine name recorded for [`LsmStore`] backups
+pub const LSM_ENGINE: &str = "lsm";
+/// Engine name recorded for [`SledKvsEngine`](crate::SledKvsEngine) backups
+pub const SLED_ENGINE: &str = "sled";
+/// Longest chain of incremental backups a restore will follow
+const MAX_CHAIN: usize = 1024;
+
+/// Description of a backup, written as `backup.manifest` in the backup directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    /// Engine that produced the backup
+    pub engine: String,
+    /// Version of kvs that wrote the backup
+    pub version: String,
+    /// Seconds since the UNIX epoch at which the backup was taken
+    pub created: u64,
+    /// Log segments making up the store, oldest first. For lsm, its SSTables and level layout. Empty for sled
+    pub segments: Vec<BackupFile>,
+    /// Hint files of those segments that have one. Empty for sled
+    #[serde(default)]
+    pub hints: Vec<BackupFile>,
+    /// Blob files holding the values separated from the log. Empty for sled
+    #[serde(default)]
+    pub blobs: Vec<BackupFile>,
+    /// CRC32 over all keys and values as computed by sled. Only set for sled backups
+    pub checksum: Option<u32>,
+    /// Backup this one is incremental to, if any, relative to this backup's directory
+    #[serde(default)]
+    pub parent: Option<PathBuf>,
+    /// Copy of the store's `MANIFEST`. Missing from backups of stores that didn't have one
+    #[serde(default)]
+    pub manifest: Option<BackupFile>,
+    /// `true` when the store was encrypted, in which case it can only be opened with its key
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// A file belonging to a backup
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    /// File name, relative to the backup directory
+    pub name: String,
+    /// Size in bytes
+    pub len: u64,
+    /// CRC32 of the file's contents
+    #[serde(default)]
+    pub checksum: Option<u32>,
+    /// `true` when the file wasn't copied because the parent backup already holds it
+    #[serde(default)]
+    pub inherited: bool,
+}
+
+impl BackupManifest {
+    pub(crate) fn new(engine: &str) -> Self {
+        BackupManifest {
+            engine: engine.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            segments: vec![],
+            hints: vec![],
+            blobs: vec![],
+            checksum: None,
+            parent: None,
+            manifest: None,
+            encrypted: false,
+        }
+    }
+
+    /// Read the manifest of the backup in `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.is_file() {
+            return Err(DbError::Backup(format!(
+                "No {MANIFEST_FILE} found in {dir:?}"
+            )));
+        }
+        Ok(ron::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let serialized = ron::ser::to_string_pretty(self, Default::default())?;
+        fs::write(dir.join(MANIFEST_FILE), serialized)?;
+        Ok(())
+    }
+
+    /// All files making up the store, segments first and `MANIFEST` last
+    pub fn files(&self) -> impl Iterator<Item = &BackupFile> {
+        self.segments
+            .iter()
+            .chain(self.hints.iter())
+            .chain(self.blobs.iter())
+            .chain(self.manifest.iter())
+    }
+
+    fn find(&self, name: &str) -> Option<&BackupFile> {
+        self.files().find(|file| file.name == name)
+    }
+
+    /// Check the files this backup holds itself, as opposed to inheriting them, against the manifest
+    pub fn validate(&self, dir: &Path) -> Result<()> {
+        match self.engine.as_str() {
+            KVS_ENGINE | LSM_ENGINE => {
+                for file in self.files().filter(|file| !file.inherited) {
+                    verify_file(&dir.join(&file.name), file)?;
+                }
+            }
+            SLED_ENGINE => {
+                if self.checksum.is_none() {
+                    return Err(DbError::Backup("Sled backup has no checksum".to_owned()));
+                }
+                if let Some(file) = &self.manifest {
+                    verify_file(&dir.join(&file.name), file)?;
+                }
+            }
+            engine => return Err(DbError::Backup(format!("Unknown engine {engine:?}"))),
+        }
+        Ok(())
+    }
+}
+
+/// Resolve the backup an incremental backup is taken relative to.
+/// `since` is either a backup directory or the manifest inside it.
+pub(crate) fn load_base(since: &Path) -> Result<(PathBuf, BackupManifest)> {
+    let dir = if since.is_file() {
+        since.parent().unwrap_or(Path::new("."))
+    } else {
+        since
+    };
+    let dir = dir.canonicalize()?;
+    let manifest = BackupManifest::load(&dir)?;
+    if manifest.engine != KVS_ENGINE {
+        return Err(DbError::Backup(
+            "Incremental backups are only supported by the kvs engine".to_owned(),
+        ));
+    }
+    Ok((dir, manifest))
+}
+
+/// Path of the backup in `base` as seen from the backup in `dir`. `base` must be canonical
+pub(crate) fn relative_path(dir: &Path, base: &Path) -> Result<PathBuf> {
+    let dir = dir.canonicalize()?;
+    let common = dir
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative: PathBuf = dir
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .collect();
+    relative.extend(base.components().skip(common));
+    Ok(relative)
+}
+
+/// Create `dir` if needed, refusing to reuse a directory that already holds a backup
+pub(crate) fn prepare_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if dir.join(MANIFEST_FILE).exists() {
+        return Err(DbError::Backup(format!("{dir:?} already holds a backup")));
+    }
+    Ok(())
+}
+
+/// Copy `name` from `source` into the backup in `dir`, unless `base` already holds an identical copy
+pub(crate) fn backup_file(
+    source: &Path,
+    dir: &Path,
+    name: &str,
+    base: Option<&BackupManifest>,
+) -> Result<BackupFile> {
+    let len = fs::metadata(source.join(name))?.len();
+    // Frozen files never change, but a name can come back with other contents, like a collected blob file's id
+    if let Some(file) = base.and_then(|base| base.find(name)) {
+        if file.len == len && file.checksum == Some(checksum(&source.join(name))?) {
+            debug!("{name} is already in the base backup, skipping it");
+            return Ok(BackupFile {
+                inherited: true,
+                ..file.clone()
+            });
+        }
+    }
+    let (len, checksum) = copy_file(&source.join(name), &dir.join(name))?;
+    Ok(BackupFile {
+        name: name.to_owned(),
+        len,
+        checksum: Some(checksum),
+        inherited: false,
+    })
+}
+
+/// Write the store's `manifest` into the backup in `dir`, listing `segments` as the store's files
+pub(crate) fn backup_manifest(
+    manifest: &Manifest,
+    dir: &Path,
+    segments: Vec<String>,
+) -> Result<BackupFile> {
+    Manifest {
+        segments,
+        ..manifest.clone()
+    }
+    .save(dir)?;
+    let contents = fs::read(dir.join(manifest::MANIFEST_FILE))?;
+    Ok(BackupFile {
+        name: manifest::MANIFEST_FILE.to_owned(),
+        len: contents.len() as u64,
+        checksum: Some(crc32fast::hash(&contents)),
+        inherited: false,
+    })
+}
+
+/// Copy `from` to `to`, returning the length and CRC32 of what was copied
+fn copy_file(from: &Path, to: &Path) -> Result<(u64, u32)> {
+    let mut reader = BufReader::new(File::open(from)?);
+    let mut writer = BufWriter::new(File::create(to)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0_u8; 8192];
+    let mut len = 0;
+    loop {
+        let bytes_read = reader.read(&mut buf)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+        writer.write_all(&buf[..bytes_read])?;
+        len += bytes_read as u64;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    Ok((len, hasher.finalize()))
+}
+
+/// CRC32 of the file at `path`, read a buffer at a time
+fn checksum(path: &Path) -> Result<u32> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.update(buf);
+        let consumed = buf.len();
+        reader.consume(consumed);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Check the file at `path` has the length and checksum recorded for it
+fn verify_file(path: &Path, file: &BackupFile) -> Result<()> {
+    let contents =
+        fs::read(path).map_err(|_| DbError::Backup(format!("Missing file {}", file.name)))?;
+    if contents.len() as u64 != file.len {
+        return Err(DbError::Backup(format!(
+            "{} is {} bytes, manifest says {}",
+            file.name,
+            contents.len(),
+            file.len
+        )));
+    }
+    if let Some(expected) = file.checksum {
+        let actual = crc32fast::hash(&contents);
+        if actual != expected {
+            return Err(DbError::Backup(format!(
+                "{} has checksum {actual:08x}, manifest says {expected:08x}",
+                file.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Copy the files of the sled backup in `from` into `to`, all but `backup.manifest`
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        if entry.file_name() == MANIFEST_FILE {
+            continue;
+        } else if entry.file_type()?.is_dir() {
+            copy_dir(&source, &target)?;
+        } else {
+            fs::copy(source, target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Load the backup in `backup` along with every backup it's incremental to, base first
+fn load_chain(backup: &Path) -> Result<Vec<(PathBuf, BackupManifest)>> {
+    let mut chain = vec![];
+    let mut next = Some(backup.to_path_buf());
+    while let Some(dir) = next {
+        if chain.len() == MAX_CHAIN {
+            return Err(DbError::Backup(format!(
+                "Backup chain is longer than {MAX_CHAIN}, is it circular?"
+            )));
+        }
+        let manifest = BackupManifest::load(&dir)?;
+        manifest.validate(&dir)?;
+        // Older backups recorded an absolute path, which `join` keeps as is
+        next = manifest.parent.as_ref().map(|parent| dir.join(parent));
+        chain.push((dir, manifest));
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Validate the backup in `backup` and rebuild the store it describes in `dir`,
+/// which must be empty or not exist yet. Incremental backups are applied on top of
+/// the backups they were taken relative to, oldest first.
+pub fn restore(backup: &Path, dir: &Path) -> Result<BackupManifest> {
+    let mut chain = load_chain(backup)?;
+    let (_, manifest) = chain
+        .pop()
+        .expect("chain holds at least the requested backup");
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(DbError::Backup(format!(
+            "Restore target {dir:?} is not empty"
+        )));
+    }
+    match manifest.engine.as_str() {
+        KVS_ENGINE | LSM_ENGINE => {
+            chain.push((backup.to_path_buf(), manifest.clone()));
+            for (source, link) in &chain {
+                for file in link.files().filter(|file| !file.inherited) {
+                    // Files the requested backup no longer lists were compacted away since
+                    if manifest.find(&file.name).is_some() {
+                        fs::copy(source.join(&file.name), dir.join(&file.name))?;
+                    }
+                }
+            }
+            for file in manifest.files() {
+                verify_file(&dir.join(&file.name), file)?;
+            }
+            // Opening the restored store proves its files parse, encrypted ones are left to their checksums
+            if manifest.encrypted {
+                debug!("Restored encrypted store into {dir:?}");
+            } else if manifest.engine == KVS_ENGINE {
+                let store = KvStore::open(dir)?;
+                debug!("Restored {} keys into {dir:?}", store.map.len());
+            } else {
+                let store = LsmStore::open(dir)?;
+                debug!(
+                    "Restored tables {:?} into {dir:?}",
+                    store.tables_per_level()
+                );
+            }
+        }
+        _ => {
+            if !chain.is_empty() {
+                return Err(DbError::Backup(
+                    "Sled backups can't be incremental".to_owned(),
+                ));
+            }
+            // The backup is a sled database itself: copy it over and check the copy, leaving the backup untouched
+            copy_dir(backup, dir)?;
+            let target = sled::open(dir)?;
+            if Some(target.checksum()?) != manifest.checksum {
+                return Err(DbError::Backup(
+                    "Restored sled database doesn't match the backup checksum".to_owned(),
+                ));
+            }
+            if let Some(file) = &manifest.manifest {
+                verify_file(&dir.join(&file.name), file)?;
+            }
+        }
+    }
+    info!(
+        "Restored {} backup from {backup:?} into {dir:?}",
+        manifest.engine
+    );
+    Ok(manifest)
+}
@@ -1,7 +1,12 @@
 //! This builds the `kvs` executable
-use kvs::{cli, exit_program, KvsEngine};
+use kvs::{
+    cli, exit_program, inspect, recover, repair, transfer, verify, EncryptionKey, KvStore,
+    KvStoreOptions, KvsEngine,
+};
 use log::{error, info};
 use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
 fn main() -> kvs::Result<()> {
     ::dotenv::dotenv().ok();
     // Read kv_00001.log file into BufReader
@@ -20,14 +25,148 @@ fn main() -> kvs::Result<()> {
     }
     env_logger::init();
     let cli = <KvsCLI as clap::Parser>::parse();
+    // Restoring builds a brand new store, so it must not open one in the current directory first
+    if let Some(Command::Restore(RestoreCmd { backup, dir })) = &cli.command {
+        let manifest = kvs::backup::restore(backup, dir)?;
+        info!(
+            "Restored {} backup taken at {}",
+            manifest.engine, manifest.created
+        );
+        return Ok(());
+    }
+    let (encryption_key, previous_keys) =
+        EncryptionKey::load(cli.key_file.as_deref(), &cli.previous_key_file)?;
+    // Verifying reads the files as they are, without opening the store
+    if let Some(Command::Verify(VerifyCmd { dir })) = &cli.command {
+        let dir = dir.clone().unwrap_or(env::current_dir()?);
+        match verify::verify(&dir, encryption_key.as_ref(), &previous_keys) {
+            Ok(report) => {
+                print!("{report}");
+                exit_program(if report.is_ok() { 0 } else { 1 });
+            }
+            Err(err) => {
+                error!("Cannot verify {dir:?}: {err}");
+                exit_program(2);
+            }
+        }
+    }
+    if let Some(Command::Repair(RepairCmd { dir })) = &cli.command {
+        let dir = dir.clone().unwrap_or(env::current_dir()?);
+        let report = repair::repair(&dir, encryption_key.as_ref(), &previous_keys)?;
+        print!("{report}");
+        return Ok(());
+    }
+    if let Some(Command::Recover(RecoverCmd { until, src, dst })) = &cli.command {
+        let report = recover::recover(src, dst, *until, encryption_key.as_ref(), &previous_keys)?;
+        print!("{report}");
+        return Ok(());
+    }
+    if let Some(Command::Log(LogCmd {
+        command: LogCommand::Inspect(inspect_cmd),
+    })) = &cli.command
+    {
+        let dir = inspect_cmd.dir.clone().unwrap_or(env::current_dir()?);
+        let filter = inspect::LogFilter {
+            key: inspect_cmd.key.clone(),
+            pattern: inspect_cmd.pattern.clone(),
+            ops: inspect_cmd.op.clone(),
+        };
+        for entry in inspect::inspect(&dir, encryption_key.as_ref(), &previous_keys, &filter)? {
+            match inspect_cmd.json {
+                true => println!("{}", serde_json::to_string(&entry)?),
+                false => println!("{entry}"),
+            }
+        }
+        return Ok(());
+    }
     // create a local kvs instance
-    let mut kvs = kvs::KvStore::open(env::current_dir()?)?;
+    // Commands that only read don't need the writer lock, so they work next to a running kvs-server
+    let read_only = !cli.compact
+        && !cli.blob_gc
+        && matches!(
+            cli.command,
+            Some(Command::Action(Action::Get(_)) | Command::Export(_) | Command::Stats(_))
+        );
+    let mut kvs = KvStore::open_with(
+        env::current_dir()?,
+        KvStoreOptions {
+            encryption_key,
+            previous_keys,
+            read_only,
+            ..Default::default()
+        },
+    )?;
 
     if cli.compact {
         kvs.compaction()?;
     }
 
-    if let Some(action) = cli.action {
+    if cli.blob_gc {
+        let reclaimed = kvs.blob_gc()?;
+        info!("Reclaimed {reclaimed} bytes from blob files");
+    }
+
+    if let Some(command) = cli.command {
+        let action = match command {
+            Command::Action(action) => action,
+            Command::Export(ExportCmd {
+                format,
+                prefix,
+                output,
+            }) => {
+                let exported = match output {
+                    Some(path) => transfer::export(&kvs, format, &prefix, File::create(path)?)?,
+                    None => transfer::export(&kvs, format, &prefix, io::stdout().lock())?,
+                };
+                info!("Exported {exported} records");
+                return Ok(());
+            }
+            Command::Import(ImportCmd {
+                format,
+                prefix,
+                on_conflict,
+                input,
+            }) => {
+                let summary = match input {
+                    Some(path) => transfer::import(
+                        &mut kvs,
+                        format,
+                        &prefix,
+                        on_conflict,
+                        BufReader::new(File::open(path)?),
+                    )?,
+                    None => transfer::import(
+                        &mut kvs,
+                        format,
+                        &prefix,
+                        on_conflict,
+                        io::stdin().lock(),
+                    )?,
+                };
+                info!("{summary:?}");
+                return Ok(());
+            }
+            Command::Backup(BackupCmd { dir, since, .. }) => {
+                let manifest = kvs.backup_to(&dir, since.as_deref())?;
+                info!("Backed up {} segments", manifest.segments.len());
+                return Ok(());
+            }
+            Command::Stats(StatsCmd { json }) => {
+                let stats = kvs.stats()?;
+                match json {
+                    true => println!("{}", serde_json::to_string_pretty(&stats)?),
+                    false => print!("{stats}"),
+                }
+                return Ok(());
+            }
+            Command::Restore(_)
+            | Command::Verify(_)
+            | Command::Repair(_)
+            | Command::Log(_)
+            | Command::Recover(_) => {
+                unreachable!("handled before opening the store")
+            }
+        };
         match action {
             Action::Set(SetCmd { key, value }) => {
                 info!("Setting {key} to {value}");
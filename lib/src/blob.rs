@@ -0,0 +1,314 @@
+//! Value separation, WiscKey style.
+//!
+//! Values bigger than [`KvStoreOptions::blob_threshold`](crate::KvStoreOptions) are appended to blob files
+//! `kv_<id>.blob` and the log only records where they landed. Key compaction then copies a small pointer
+//! instead of the whole value.
+//!
+//! Overwriting or removing a separated value leaves garbage behind in its blob file. Blob garbage collection
+//! runs on its own schedule: the live values of a blob file that is mostly garbage are appended to the active
+//! blob file, the log is pointed at their new location and the old file is deleted.
+//!
+//! In an encrypted store each value is sealed on its own, so it can still be read with a single seek.
+
+use crate::crypto::Keyring;
+use crate::record::{BlobRecord, Record};
+use crate::{KvStore, Result};
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Once the active blob file grows past this many bytes a new one is started
+const BLOB_FILE_SIZE: u64 = 1 << 26;
+/// Garbage a blob file must hold before it's collected automatically, on top of being mostly garbage
+const BLOB_GC_THRESHOLD: u64 = 1 << 20;
+
+/// Location of a value inside a blob file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BlobPointer {
+    /// Blob file id, as in `kv_<file>.blob`
+    pub(crate) file: u64,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    /// Key the value was sealed with, `None` when it's stored in the clear
+    #[serde(default)]
+    pub(crate) key_id: Option<u32>,
+}
+
+/// Authenticated along with every sealed blob value
+const BLOB_AAD: &[u8] = b"blob";
+
+/// File name of blob file `id`
+pub(crate) fn blob_name(id: u64) -> String {
+    format!("kv_{id:05}.blob")
+}
+
+/// Path of blob file `id` inside `dir`
+pub(crate) fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(blob_name(id))
+}
+
+/// Ids of all blob files found in `dir`, oldest first
+pub(crate) fn blob_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("kv_")?
+                .strip_suffix(".blob")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Separated values of a store and the files holding them
+#[derive(Debug, Default)]
+pub(crate) struct Blobs {
+    dir: PathBuf,
+    /// Keys whose current value lives in a blob file
+    pub(crate) index: HashMap<String, BlobPointer>,
+    /// Bytes of live values per blob file
+    live: HashMap<u64, u64>,
+    /// Size of every blob file, so that writes don't need to look at the directory
+    sizes: BTreeMap<u64, u64>,
+    /// Blob files whose garbage grew since the last garbage collection check
+    changed: BTreeSet<u64>,
+    readers: RefCell<HashMap<u64, File>>,
+    /// Append handle on the active blob file, opened on the first write
+    writer: Option<File>,
+    active: u64,
+    offset: u64,
+    keyring: Keyring,
+}
+
+impl Blobs {
+    pub(crate) fn open(dir: &Path, keyring: Keyring) -> Result<Self> {
+        let mut sizes = BTreeMap::new();
+        for id in blob_ids(dir)? {
+            sizes.insert(id, fs::metadata(blob_path(dir, id))?.len());
+        }
+        Ok(Blobs {
+            dir: dir.to_path_buf(),
+            active: sizes.keys().last().copied().unwrap_or(1),
+            sizes,
+            keyring,
+            ..Default::default()
+        })
+    }
+
+    /// Record that `key` now points at `blob`, or at no blob at all
+    pub(crate) fn track(&mut self, key: &str, blob: Option<BlobPointer>) {
+        let old = match blob {
+            Some(blob) => self.index.insert(key.to_owned(), blob),
+            None => self.index.remove(key),
+        };
+        if old == blob {
+            return;
+        }
+        if let Some(old) = old {
+            if let Some(live) = self.live.get_mut(&old.file) {
+                *live -= old.len;
+                self.changed.insert(old.file);
+            }
+        }
+        // Replaying the log comes across values of files collected since, which must not come back.
+        // Files from the active one on may be new, written by another process a read-only store follows
+        if let Some(blob) =
+            blob.filter(|blob| blob.file >= self.active || self.sizes.contains_key(&blob.file))
+        {
+            *self.live.entry(blob.file).or_default() += blob.len;
+            let size = self.sizes.entry(blob.file).or_default();
+            *size = (*size).max(blob.offset + blob.len);
+        }
+    }
+
+    /// Append `value` to the active blob file, sealed if the store is encrypted
+    pub(crate) fn write(&mut self, value: &[u8]) -> Result<BlobPointer> {
+        let sealed;
+        let value = if self.keyring.enabled() {
+            sealed = self.keyring.seal(value, BLOB_AAD)?;
+            &sealed
+        } else {
+            value
+        };
+        if self.offset >= BLOB_FILE_SIZE {
+            self.roll();
+        }
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(blob_path(&self.dir, self.active))?;
+                self.offset = file.metadata()?.len();
+                self.writer.insert(file)
+            }
+        };
+        writer.write_all(value)?;
+        let blob = BlobPointer {
+            file: self.active,
+            offset: self.offset,
+            len: value.len() as u64,
+            key_id: self.keyring.current(),
+        };
+        self.offset += blob.len;
+        self.sizes.insert(self.active, self.offset);
+        Ok(blob)
+    }
+
+    /// Read the value at `blob`
+    pub(crate) fn read(&self, blob: BlobPointer) -> Result<String> {
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(blob.file) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(File::open(blob_path(&self.dir, blob.file))?),
+        };
+        reader.seek(SeekFrom::Start(blob.offset))?;
+        let mut buf = vec![0; blob.len as usize];
+        reader.read_exact(&mut buf)?;
+        if blob.key_id.is_some() {
+            buf = self.keyring.open(&buf, BLOB_AAD)?;
+        }
+        Ok(String::from_utf8(buf)?)
+    }
+
+    /// Freeze the active blob file, the next write starts a new one
+    pub(crate) fn roll(&mut self) {
+        self.writer = None;
+        self.offset = 0;
+        if self.sizes.contains_key(&self.active) {
+            self.active += 1;
+        }
+    }
+
+    /// Make the values written so far durable
+    pub(crate) fn sync(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Every blob file, as `(id, size, garbage)`
+    pub(crate) fn files(&self) -> Vec<(u64, u64, u64)> {
+        self.sizes.keys().map(|&id| self.file(id)).collect()
+    }
+
+    /// Blob file `id` as `(id, size, garbage)`
+    fn file(&self, id: u64) -> (u64, u64, u64) {
+        let size = self.sizes.get(&id).copied().unwrap_or_default();
+        let live = self.live.get(&id).copied().unwrap_or_default();
+        (id, size, size.saturating_sub(live))
+    }
+
+    fn delete(&mut self, id: u64) -> Result<()> {
+        self.readers.borrow_mut().remove(&id);
+        self.live.remove(&id);
+        self.sizes.remove(&id);
+        self.changed.remove(&id);
+        match fs::remove_file(blob_path(&self.dir, id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl KvStore {
+    /// Collect every blob file holding garbage, whatever its share of garbage.
+    /// Returns the number of bytes reclaimed.
+    pub fn blob_gc(&mut self) -> Result<u64> {
+        self.check_writable()?;
+        self.blobs.roll();
+        let mut reclaimed = 0;
+        for (id, _, garbage) in self.blobs.files() {
+            if garbage > 0 {
+                reclaimed += self.collect_blob_file(id)?;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Rewrite the blob files holding values sealed with anything but the current key, or none when
+    /// the current key is set, so that after a key rotation nothing depends on the old key anymore
+    pub(crate) fn reseal_blobs(&mut self) -> Result<()> {
+        let current = self.keyring.current();
+        let files: BTreeSet<u64> = self
+            .blobs
+            .index
+            .values()
+            .filter(|blob| blob.key_id != current)
+            .map(|blob| blob.file)
+            .collect();
+        for id in files {
+            self.collect_blob_file(id)?;
+        }
+        Ok(())
+    }
+
+    /// Collect the blob files that are mostly garbage, as writes go. Only the files whose garbage grew
+    /// since the last call are looked at, so writes to a store without blobs skip this altogether
+    pub(crate) fn maintain_blobs(&mut self) -> Result<()> {
+        if self.blobs.changed.is_empty() {
+            return Ok(());
+        }
+        for id in std::mem::take(&mut self.blobs.changed) {
+            let (_, size, garbage) = self.blobs.file(id);
+            if garbage >= BLOB_GC_THRESHOLD && garbage * 2 >= size {
+                self.collect_blob_file(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the live values of blob file `id` to the active blob file and delete it
+    fn collect_blob_file(&mut self, id: u64) -> Result<u64> {
+        if id == self.blobs.active {
+            self.blobs.roll();
+        }
+        let (_, size, _) = self.blobs.file(id);
+        let moved: Vec<(String, BlobPointer)> = self
+            .blobs
+            .index
+            .iter()
+            .filter(|(_, blob)| blob.file == id)
+            .map(|(key, &blob)| (key.clone(), blob))
+            .collect();
+        let mut kept = 0;
+        for (key, old) in moved {
+            let value = self.blobs.read(old)?;
+            let blob = self.blobs.write(value.as_bytes())?;
+            // The value moves, the write it came from stays the same
+            let stamp = self.read_record(self.map[&key])?.stamp();
+            let pointer = self.append(&Record::Blob(BlobRecord {
+                key: key.clone(),
+                blob,
+                stamp,
+            }))?;
+            if let Some(old) = self.map.insert(key.clone(), pointer) {
+                self.stale += old.len + 1;
+            }
+            self.blobs.track(&key, Some(blob));
+            kept += blob.len;
+        }
+        // The old file may only go once the log durably points elsewhere
+        self.blobs.sync()?;
+        if let Some(writer) = &self.writer {
+            writer.sync_data()?;
+        }
+        self.blobs.delete(id)?;
+        debug!("Collected blob file {id}, moved {kept} live bytes out of {size}");
+        Ok(size.saturating_sub(kept))
+    }
+}
@@ -0,0 +1,99 @@
+//! Bounded cache of hot values in front of [`KvStore`](crate::KvStore) reads.
+//! Without it every `get` seeks into the log and parses the command it finds there,
+//! even for keys read thousands of times a second.
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+/// Counters describing the read cache
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups that had to go to the log
+    pub misses: u64,
+    /// Entries dropped to stay within capacity
+    pub evictions: u64,
+    /// Entries dropped because their time to live ran out
+    pub expired: u64,
+    /// Entries currently cached
+    pub entries: u64,
+    /// Bytes currently cached, counting keys and values
+    pub bytes: u64,
+    /// Upper bound on `bytes`
+    pub capacity: u64,
+}
+
+/// Least recently used cache of values, bounded by the bytes taken up by keys and values
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    entries: LruCache<String, String>,
+    stats: CacheStats,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        ValueCache {
+            entries: LruCache::unbounded(),
+            stats: CacheStats {
+                capacity,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<String> {
+        match self.entries.get(key) {
+            Some(value) => {
+                self.stats.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache `value`, evicting the least recently used entries to make room.
+    /// Values too big to ever fit are not cached.
+    pub(crate) fn insert(&mut self, key: String, value: String) {
+        let size = footprint(&key, &value);
+        if size > self.stats.capacity {
+            return;
+        }
+        self.invalidate(&key);
+        while self.stats.bytes + size > self.stats.capacity {
+            let Some((old_key, old_value)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.stats.bytes -= footprint(&old_key, &old_value);
+            self.stats.evictions += 1;
+        }
+        self.entries.put(key, value);
+        self.stats.bytes += size;
+        self.stats.entries = self.entries.len() as u64;
+    }
+
+    pub(crate) fn invalidate(&mut self, key: &str) {
+        if let Some(value) = self.entries.pop(key) {
+            self.stats.bytes -= footprint(key, &value);
+            self.stats.entries = self.entries.len() as u64;
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.stats.bytes = 0;
+        self.stats.entries = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+/// Bytes a cached pair is accounted for
+pub(crate) fn footprint(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
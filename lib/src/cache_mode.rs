@@ -0,0 +1,313 @@
+//! Cache mode: run any [`KvsEngine`] as a bounded cache.
+//!
+//! [`CacheEngine`] wraps an engine and keeps it within a budget of bytes and/or keys.
+//! When a write would go over budget, keys are evicted from the wrapped engine according
+//! to an [`EvictionPolicy`]. Keys may also be given a time to live, after which they read
+//! as missing and are purged on the next write.
+
+use crate::cache::footprint;
+use crate::{BackupManifest, CacheStats, DbError, EngineStats, KvPairs, KvsEngine, Result};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Which keys make room when the cache is full
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Least recently used first
+    #[default]
+    Lru,
+    /// Least frequently used first, least recently used among equals
+    Lfu,
+    /// Keys closest to expiring first, then least recently used
+    TtlFirst,
+}
+
+/// Budget and eviction settings of a [`CacheEngine`]
+#[derive(Debug, Clone, Default)]
+pub struct CacheOptions {
+    /// Bytes of keys and values the cache may hold
+    pub max_bytes: Option<u64>,
+    /// Number of keys the cache may hold
+    pub max_keys: Option<u64>,
+    /// How to pick keys to evict
+    pub policy: EvictionPolicy,
+    /// Time to live of every key written, counted from its last `set`
+    pub ttl: Option<Duration>,
+}
+
+/// Eviction order, smallest evicted first
+type Rank = (u64, u64);
+
+/// What the cache knows about a key
+#[derive(Debug)]
+struct Tracked {
+    size: u64,
+    last_used: u64,
+    uses: u64,
+    /// Milliseconds since the cache started at which the key expires
+    expires: Option<u64>,
+}
+
+impl Tracked {
+    fn rank(&self, policy: EvictionPolicy) -> Rank {
+        match policy {
+            EvictionPolicy::Lru => (self.last_used, 0),
+            EvictionPolicy::Lfu => (self.uses, self.last_used),
+            EvictionPolicy::TtlFirst => (self.expires.unwrap_or(u64::MAX), self.last_used),
+        }
+    }
+}
+
+/// Bookkeeping of every key in the wrapped engine
+#[derive(Debug)]
+struct Tracker {
+    policy: EvictionPolicy,
+    started: Instant,
+    keys: HashMap<String, Tracked>,
+    /// Keys in eviction order
+    order: BTreeSet<(Rank, String)>,
+    /// Keys with a time to live, soonest to expire first
+    deadlines: BTreeSet<(u64, String)>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Tracker {
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn is_expired(&self, key: &str) -> bool {
+        let now = self.now();
+        self.keys
+            .get(key)
+            .and_then(|tracked| tracked.expires)
+            .is_some_and(|expires| expires <= now)
+    }
+
+    /// Start tracking `key`, replacing what was known about it. An overwrite counts as one more use,
+    /// so that updating a hot key doesn't make it the first one evicted
+    fn insert(&mut self, key: String, size: u64, ttl: Option<Duration>) {
+        let uses = self.keys.get(&key).map_or(1, |old| old.uses + 1);
+        self.untrack(&key);
+        self.clock += 1;
+        let tracked = Tracked {
+            size,
+            last_used: self.clock,
+            uses,
+            expires: ttl.map(|ttl| self.now() + ttl.as_millis() as u64),
+        };
+        self.order.insert((tracked.rank(self.policy), key.clone()));
+        if let Some(expires) = tracked.expires {
+            self.deadlines.insert((expires, key.clone()));
+        }
+        self.stats.bytes += size;
+        self.keys.insert(key, tracked);
+        self.stats.entries = self.keys.len() as u64;
+    }
+
+    /// Record a read of `key`
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(tracked) = self.keys.get_mut(key) {
+            self.order
+                .remove(&(tracked.rank(self.policy), key.to_owned()));
+            tracked.last_used = self.clock;
+            tracked.uses += 1;
+            self.order
+                .insert((tracked.rank(self.policy), key.to_owned()));
+        }
+    }
+
+    fn untrack(&mut self, key: &str) {
+        if let Some(tracked) = self.keys.remove(key) {
+            self.order
+                .remove(&(tracked.rank(self.policy), key.to_owned()));
+            if let Some(expires) = tracked.expires {
+                self.deadlines.remove(&(expires, key.to_owned()));
+            }
+            self.stats.bytes -= tracked.size;
+            self.stats.entries = self.keys.len() as u64;
+        }
+    }
+
+    /// Next key to evict, other than `keep`
+    fn victim(&self, keep: &str) -> Option<String> {
+        self.order
+            .iter()
+            .map(|(_, key)| key)
+            .find(|key| *key != keep)
+            .cloned()
+    }
+
+    /// Keys whose time to live ran out
+    fn expired(&self) -> Vec<String> {
+        let now = self.now();
+        self.deadlines
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+/// A [`KvsEngine`] kept within a memory or key budget, see the [module docs](self)
+pub struct CacheEngine<E: KvsEngine> {
+    inner: E,
+    options: CacheOptions,
+    tracker: RefCell<Tracker>,
+}
+
+impl<E: KvsEngine> CacheEngine<E> {
+    /// Wrap `inner`, evicting right away if what it already holds is over budget.
+    /// Time to live isn't persisted, keys already in `inner` start theirs now.
+    pub fn new(inner: E, options: CacheOptions) -> Result<Self> {
+        let mut tracker = Tracker {
+            policy: options.policy,
+            started: Instant::now(),
+            keys: HashMap::new(),
+            order: BTreeSet::new(),
+            deadlines: BTreeSet::new(),
+            clock: 0,
+            stats: CacheStats {
+                capacity: options.max_bytes.unwrap_or(0),
+                ..Default::default()
+            },
+        };
+        for pair in inner.scan("")? {
+            let (key, value) = pair?;
+            let size = footprint(&key, &value);
+            tracker.insert(key, size, options.ttl);
+        }
+        let mut engine = CacheEngine {
+            inner,
+            options,
+            tracker: RefCell::new(tracker),
+        };
+        engine.enforce_budget("")?;
+        info!(
+            "Cache mode with {} keys, {} bytes, evicting by {:?}",
+            engine.cache_stats().entries,
+            engine.cache_stats().bytes,
+            engine.options.policy
+        );
+        Ok(engine)
+    }
+
+    /// Counters of the cache, `capacity` being the byte budget or 0 when there is none
+    pub fn cache_stats(&self) -> CacheStats {
+        self.tracker.borrow().stats
+    }
+
+    /// The wrapped engine
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    fn over_budget(&self) -> bool {
+        let stats = self.tracker.borrow().stats;
+        self.options.max_bytes.is_some_and(|max| stats.bytes > max)
+            || self.options.max_keys.is_some_and(|max| stats.entries > max)
+    }
+
+    /// Evict keys other than `keep` until the cache fits its budget
+    fn enforce_budget(&mut self, keep: &str) -> Result<()> {
+        while self.over_budget() {
+            let Some(key) = self.tracker.borrow().victim(keep) else {
+                break;
+            };
+            debug!("Evicting {key:?}");
+            self.inner.remove(key.clone())?;
+            let mut tracker = self.tracker.borrow_mut();
+            tracker.untrack(&key);
+            tracker.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Remove every key whose time to live ran out from the wrapped engine
+    fn purge_expired(&mut self) -> Result<()> {
+        let expired = self.tracker.borrow().expired();
+        for key in expired {
+            debug!("Expiring {key:?}");
+            self.inner.remove(key.clone())?;
+            let mut tracker = self.tracker.borrow_mut();
+            tracker.untrack(&key);
+            tracker.stats.expired += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CacheEngine<E> {
+    /// Set : Values that could never fit the byte budget are refused,
+    /// otherwise other keys are evicted until the new one fits.
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let size = footprint(&key, &value);
+        if let Some(limit) = self.options.max_bytes.filter(|&max| size > max) {
+            return Err(DbError::OverBudget { size, limit });
+        }
+        self.purge_expired()?;
+        self.inner.set(key.clone(), value)?;
+        self.tracker
+            .borrow_mut()
+            .insert(key.clone(), size, self.options.ttl);
+        self.enforce_budget(&key)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if self.tracker.borrow().is_expired(&key) {
+            self.tracker.borrow_mut().stats.misses += 1;
+            return Ok(None);
+        }
+        let value = self.inner.get(key.clone())?;
+        let mut tracker = self.tracker.borrow_mut();
+        match value {
+            Some(_) => {
+                tracker.stats.hits += 1;
+                tracker.touch(&key);
+            }
+            None => tracker.stats.misses += 1,
+        }
+        Ok(value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.purge_expired()?;
+        self.inner.remove(key.clone())?;
+        self.tracker.borrow_mut().untrack(&key);
+        Ok(())
+    }
+
+    /// Scan : Expired keys are left out
+    fn scan<'a>(&'a self, prefix: &'a str) -> Result<KvPairs<'a>> {
+        let live = self.inner.scan(prefix)?.filter(move |pair| match pair {
+            Ok((key, _)) => !self.tracker.borrow().is_expired(key),
+            Err(_) => true,
+        });
+        Ok(Box::new(live))
+    }
+
+    fn backup_to(&mut self, dir: &Path, since: Option<&Path>) -> Result<BackupManifest> {
+        self.inner.backup_to(dir, since)
+    }
+
+    /// Stats : Those of the inner engine, along with the cache's counters
+    fn stats(&self) -> Result<EngineStats> {
+        // The wrapped engine's own figures, read cache and compression included, stay as they are
+        Ok(EngineStats {
+            cache_mode: Some(self.cache_stats()),
+            ..self.inner.stats()?
+        })
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+}
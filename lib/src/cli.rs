@@ -1,6 +1,11 @@
 //! CLI machinery for KvStore client
 
+use crate::inspect::Op;
+use crate::recover::Until;
+use crate::transfer::{Format, OnConflict};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(clap::Parser)]
 #[command(author, version, about)]
@@ -8,8 +13,8 @@ use serde::{Deserialize, Serialize};
 /// The main CLI entry point
 pub struct KvsCLI {
     #[command(subcommand)]
-    /// Action for the KvStore
-    pub action: Option<Action>,
+    /// Command for the KvStore
+    pub command: Option<Command>,
 
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
@@ -18,6 +23,18 @@ pub struct KvsCLI {
     /// Run compaction
     #[arg(short, long)]
     pub compact: bool,
+
+    /// Reclaim the space taken by overwritten values in blob files
+    #[arg(long)]
+    pub blob_gc: bool,
+
+    /// File holding the key the store is encrypted with, otherwise read from `KVS_ENCRYPTION_KEY`
+    #[arg(long)]
+    pub key_file: Option<PathBuf>,
+
+    /// File holding a key being rotated away from, the next compaction re-seals its data with the current key
+    #[arg(long)]
+    pub previous_key_file: Vec<PathBuf>,
 }
 
 #[derive(clap::Args, Serialize, Deserialize, Debug)]
@@ -63,4 +80,160 @@ pub enum Action {
     #[serde(rename = "RM")]
     #[clap(name = "rm")]
     Remove(RmCmd),
-}
\ No newline at end of file
+}
+
+#[derive(clap::Subcommand, Debug)]
+/// Subcommands accepted by the `kvs` executable
+pub enum Command {
+    #[command(flatten)]
+    /// Key-value action, shared with `kvs-client`
+    Action(Action),
+    /// Stream the store's contents out as JSON Lines or CSV
+    Export(ExportCmd),
+    /// Load key-value pairs from a JSON Lines or CSV dump
+    Import(ImportCmd),
+    /// Copy a consistent snapshot of the store into a backup directory
+    Backup(BackupCmd),
+    /// Rebuild a store from a backup directory
+    Restore(RestoreCmd),
+    /// Show the store's size, fragmentation and compaction figures
+    Stats(StatsCmd),
+    /// Check the log, hint files and index of a store for corruption, without opening it.
+    /// Exits with 0 when the store is sound, 1 when problems were found and 2 when it couldn't be checked
+    Verify(VerifyCmd),
+    /// Rewrite damaged log segments from the records that can still be read, keeping the originals as `.corrupt`
+    Repair(RepairCmd),
+    /// Look into the log
+    Log(LogCmd),
+    /// Rebuild, in a new store, the state a store or backup was in at an earlier write or time
+    Recover(RecoverCmd),
+}
+
+#[derive(clap::Args, Debug)]
+/// Export the store
+pub struct ExportCmd {
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t)]
+    pub format: Format,
+    /// Only export keys starting with this prefix
+    #[arg(short, long, default_value = "")]
+    pub prefix: String,
+    /// Write to this file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+/// Import into the store
+pub struct ImportCmd {
+    /// Input format
+    #[arg(short, long, value_enum, default_value_t)]
+    pub format: Format,
+    /// Only import keys starting with this prefix
+    #[arg(short, long, default_value = "")]
+    pub prefix: String,
+    /// How to treat keys that already exist in the store
+    #[arg(long, value_enum, default_value_t)]
+    pub on_conflict: OnConflict,
+    /// Read from this file instead of stdin
+    pub input: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+/// Back up the store
+pub struct BackupCmd {
+    /// Directory to write the backup into, created if missing
+    #[arg(name = "DIR")]
+    pub dir: PathBuf,
+    /// Only copy the segments and hints created since the backup given by `--since`
+    #[arg(long, requires = "since")]
+    pub incremental: bool,
+    /// Manifest, or directory, of the backup an incremental backup builds on
+    #[arg(long, requires = "incremental")]
+    pub since: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+/// Restore a store from a backup
+pub struct RestoreCmd {
+    /// Backup directory, as written by `kvs backup` or a BACKUP request
+    #[arg(name = "BACKUP")]
+    pub backup: PathBuf,
+    /// Directory to restore into, must be empty
+    #[arg(name = "DIR")]
+    pub dir: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+/// Show store statistics
+pub struct StatsCmd {
+    /// Print as JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+/// Verify a store
+pub struct VerifyCmd {
+    /// Store directory, the current directory by default
+    #[arg(name = "DIR")]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+/// Repair a store
+pub struct RepairCmd {
+    /// Store directory, the current directory by default
+    #[arg(name = "DIR")]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+/// Recover a store to a point in time
+pub struct RecoverCmd {
+    /// Last write to recover: a sequence number as shown by `kvs log inspect`,
+    /// a timestamp such as `2024-05-01T12:00:00Z`, or a duration such as `15min`, meaning that long ago
+    #[arg(long)]
+    pub until: Until,
+    /// Store or backup directory to recover from, left untouched
+    #[arg(name = "SRC")]
+    pub src: PathBuf,
+    /// Directory to write the recovered store into, must be empty
+    #[arg(name = "DST")]
+    pub dst: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+/// Log tools
+pub struct LogCmd {
+    #[command(subcommand)]
+    /// Log tool to run
+    pub command: LogCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+/// Subcommands of `kvs log`
+pub enum LogCommand {
+    /// Print every record with its segment, byte position, sequence number, operation, key and value size
+    Inspect(InspectCmd),
+}
+
+#[derive(clap::Args, Debug)]
+/// Inspect the log
+pub struct InspectCmd {
+    /// Only show the records of this key
+    #[arg(short, long)]
+    pub key: Option<String>,
+    /// Only show the records of keys matching this regular expression
+    #[arg(short, long, value_parser = Regex::new)]
+    pub pattern: Option<Regex>,
+    /// Only show records of this kind, may be repeated
+    #[arg(long, value_enum)]
+    pub op: Vec<Op>,
+    /// Print JSON Lines, one object per record, instead of text
+    #[arg(long)]
+    pub json: bool,
+    /// Store directory, the current directory by default
+    #[arg(name = "DIR")]
+    pub dir: Option<PathBuf>,
+}
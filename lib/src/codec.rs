@@ -0,0 +1,87 @@
+//! Compression of log records.
+//!
+//! Values are often verbose JSON that RON then escapes a second time, so sets whose value is at least
+//! [`KvStoreOptions::compress_min_size`](crate::KvStoreOptions) bytes long are compressed before landing in the log.
+//! A compressed record is framed: a header holding the codec it was compressed with, the payload length and a
+//! checksum, followed by the compressed RON record. Records left uncompressed stay plain RON lines.
+
+use crate::{DbError, Result};
+use serde::{Deserialize, Serialize};
+
+/// Compression applied to records written to the log
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Codec {
+    /// Keep records as they are
+    #[default]
+    None,
+    /// Fast compression, modest ratio
+    Lz4,
+    /// Slower compression, better ratio
+    Zstd,
+}
+
+/// Compression level used for zstd, its default
+const ZSTD_LEVEL: i32 = 3;
+
+impl Codec {
+    /// Id stored in the header of framed records
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Codec> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Lz4),
+            2 => Ok(Codec::Zstd),
+            _ => Err(DbError::Corrupted(format!("unknown codec id {id}"))),
+        }
+    }
+
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Codec::None => data.to_vec(),
+            Codec::Lz4 => lz4_flex::compress_prepend_size(data),
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)?,
+        })
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|err| DbError::Corrupted(format!("lz4: {err}"))),
+            Codec::Zstd => {
+                zstd::decode_all(data).map_err(|err| DbError::Corrupted(format!("zstd: {err}")))
+            }
+        }
+    }
+}
+
+/// Bytes written to the log since the store was opened, before and after compression
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionStats {
+    /// Records written
+    pub records: u64,
+    /// Records that were stored compressed
+    pub compressed: u64,
+    /// Size of the records as serialized
+    pub raw_bytes: u64,
+    /// Size of the records as written, headers included
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// How many times smaller records got, `1.0` when nothing was written
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            1.0
+        } else {
+            self.raw_bytes as f64 / self.stored_bytes as f64
+        }
+    }
+}
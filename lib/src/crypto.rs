@@ -0,0 +1,221 @@
+//! Encryption at rest.
+//!
+//! When a [`KvStore`](crate::KvStore) is opened with an [`EncryptionKey`], log records, blob values,
+//! hint files and `kv_memory.index` are sealed with ChaCha20-Poly1305 before they reach the disk.
+//! Every sealed item starts with the id of the key it was sealed with, followed by a random nonce:
+//!
+//! | key id: u32 | nonce: 12 bytes | ciphertext and tag |
+//!
+//! Keys are never stored, their id is derived from the key itself. To rotate keys, open the store with
+//! the new key and the old one among the previous keys: everything still reads, and compaction rewrites it
+//! under the new key, after which the old one can be dropped.
+
+use crate::{DbError, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Environment variable holding the hex encoded key, when no key file is given
+pub const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+/// Environment variable holding the hex encoded key being rotated away from
+pub const PREVIOUS_KEY_ENV: &str = "KVS_PREVIOUS_ENCRYPTION_KEY";
+
+/// Length of a key id and nonce in front of every ciphertext
+const SEALED_HEADER_LEN: usize = 4 + 12;
+/// Bytes sealing adds to a plaintext: the key id and nonce, and the authentication tag
+pub(crate) const SEAL_OVERHEAD: u64 = SEALED_HEADER_LEN as u64 + 16;
+/// First bytes of a sealed hint or index file, plain ones are RON text
+const FILE_MAGIC: &[u8] = b"KVSENC1\n";
+
+/// 256 bit key the store is encrypted with
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Key made of the given bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Parse a key written as 64 hexadecimal digits
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(DbError::Encryption(
+                "key must be 64 hexadecimal digits".to_owned(),
+            ));
+        }
+        let mut bytes = [0; 32];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).expect("checked to be ascii");
+            *byte = u8::from_str_radix(digits, 16)
+                .map_err(|_| DbError::Encryption(format!("invalid hexadecimal {digits:?}")))?;
+        }
+        Ok(EncryptionKey(bytes))
+    }
+
+    /// Read a key file, holding either the 32 raw bytes of the key or their hex encoding
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path)?;
+        match <[u8; 32]>::try_from(contents.as_slice()) {
+            Ok(bytes) => Ok(EncryptionKey(bytes)),
+            Err(_) => EncryptionKey::from_hex(&String::from_utf8_lossy(&contents)),
+        }
+    }
+
+    /// Key from `file` if one is given, otherwise from the environment variable `env`, if set
+    pub fn resolve(file: Option<&Path>, env: &str) -> Result<Option<Self>> {
+        match (file, std::env::var(env)) {
+            (Some(path), _) => EncryptionKey::from_file(path).map(Some),
+            (None, Ok(hex)) => EncryptionKey::from_hex(&hex).map(Some),
+            (None, Err(_)) => Ok(None),
+        }
+    }
+
+    /// Current and previous keys as given on the command line, falling back to [`KEY_ENV`] and [`PREVIOUS_KEY_ENV`]
+    pub fn load(
+        key_file: Option<&Path>,
+        previous_key_files: &[PathBuf],
+    ) -> Result<(Option<Self>, Vec<Self>)> {
+        let current = EncryptionKey::resolve(key_file, KEY_ENV)?;
+        let mut previous = previous_key_files
+            .iter()
+            .map(|path| EncryptionKey::from_file(path))
+            .collect::<Result<Vec<_>>>()?;
+        previous.extend(EncryptionKey::resolve(None, PREVIOUS_KEY_ENV)?);
+        Ok((current, previous))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+// Keys must not end up in logs
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey({:08x})", key_id(&self.cipher()))
+    }
+}
+
+/// Id a key is known by on disk: the first bytes of a tag computed with it, which reveals nothing of the key
+fn key_id(cipher: &ChaCha20Poly1305) -> u32 {
+    let tag = cipher
+        .encrypt(
+            Nonce::from_slice(&[0; 12]),
+            Payload {
+                msg: &[],
+                aad: b"kvs key id",
+            },
+        )
+        .expect("sealing an empty message can't fail");
+    u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]])
+}
+
+/// Keys a store seals new data with and opens existing data with
+#[derive(Clone, Default)]
+pub(crate) struct Keyring {
+    /// Id of the key new data is sealed with, `None` when encryption is off
+    current: Option<u32>,
+    ciphers: HashMap<u32, ChaCha20Poly1305>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: Option<&EncryptionKey>, previous: &[EncryptionKey]) -> Keyring {
+        let mut ciphers = HashMap::new();
+        for key in previous.iter().chain(current) {
+            let cipher = key.cipher();
+            ciphers.insert(key_id(&cipher), cipher);
+        }
+        Keyring {
+            current: current.map(|key| key_id(&key.cipher())),
+            ciphers,
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Id of the key new data is sealed with
+    pub(crate) fn current(&self) -> Option<u32> {
+        self.current
+    }
+
+    /// Seal `plaintext` with the current key. `aad` is authenticated along with it, and must be given to open it
+    pub(crate) fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let id = self.current.ok_or(DbError::Encryption(
+            "store was opened without a key".to_owned(),
+        ))?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.ciphers[&id]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| DbError::Encryption("sealing failed".to_owned()))?;
+        let mut sealed = Vec::with_capacity(SEALED_HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(&id.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Check and decrypt what [`Keyring::seal`] produced, with whichever key it was sealed with
+    pub(crate) fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEALED_HEADER_LEN {
+            return Err(DbError::Corrupted("truncated ciphertext".to_owned()));
+        }
+        let id = u32::from_le_bytes([sealed[0], sealed[1], sealed[2], sealed[3]]);
+        let cipher = self.ciphers.get(&id).ok_or_else(|| {
+            DbError::Encryption(format!(
+                "data is sealed with key {id:08x}, which wasn't given"
+            ))
+        })?;
+        cipher
+            .decrypt(
+                Nonce::from_slice(&sealed[4..SEALED_HEADER_LEN]),
+                Payload {
+                    msg: &sealed[SEALED_HEADER_LEN..],
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                DbError::Encryption(format!(
+                    "data sealed with key {id:08x} failed authentication"
+                ))
+            })
+    }
+
+    /// Contents of a hint or index file as they should be written, sealed if encryption is on
+    pub(crate) fn seal_file(&self, contents: Vec<u8>) -> Result<Vec<u8>> {
+        if !self.enabled() {
+            return Ok(contents);
+        }
+        let mut sealed = FILE_MAGIC.to_vec();
+        sealed.extend(self.seal(&contents, FILE_MAGIC)?);
+        Ok(sealed)
+    }
+
+    /// Contents of a hint or index file as written by [`Keyring::seal_file`], opened if it was sealed
+    pub(crate) fn open_file(&self, contents: Vec<u8>) -> Result<Vec<u8>> {
+        match contents.strip_prefix(FILE_MAGIC) {
+            Some(sealed) => self.open(sealed, FILE_MAGIC),
+            None => Ok(contents),
+        }
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("keys", &self.ciphers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
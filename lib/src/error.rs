@@ -1,11 +1,10 @@
 //! Error and Result types
 
-use std;
 use std::io;
 
 use crate::cli::Action;
 
-#[derive(thiserror::Error, Debug,)]
+#[derive(thiserror::Error, Debug)]
 /// Database Error
 pub enum DbError {
     /// KvStore accessed before initialization on disk
@@ -14,12 +13,60 @@ pub enum DbError {
     /// Key not found
     #[error("Key doesn't exist")]
     KeyNotFound,
+    /// Key already present where a fresh one was required
+    #[error("Key already exists: {:?}", _0)]
+    KeyExists(String),
     /// Offset error
     #[error("Expected action `Set` but found {:?}", _0)]
     OffsetError(Action),
     /// Datbase not found at path
     #[error("Datbase not found at path: {:?}", _0)]
     DatabaseNotFound(std::path::PathBuf),
+    /// Pair too big to ever fit a cache's byte budget
+    #[error("Pair of {size} bytes exceeds the cache budget of {limit} bytes")]
+    OverBudget {
+        /// Bytes taken up by the key and value
+        size: u64,
+        /// Byte budget of the cache
+        limit: u64,
+    },
+    /// Record too big to be logged
+    #[error("Record of {size} bytes exceeds the limit of {limit} bytes")]
+    RecordTooLarge {
+        /// Bytes taken up by the framed payload
+        size: u64,
+        /// Largest payload a record can hold
+        limit: u64,
+    },
+    /// On-disk data failed validation
+    #[error("Corrupted data: {}", _0)]
+    Corrupted(String),
+    /// Store already opened for writing by another process
+    #[error(
+        "Store {dir:?} is locked by {}",
+        .pid.map_or("another process".to_owned(), |pid| format!("process {pid}"))
+    )]
+    Locked {
+        /// Directory of the store
+        dir: std::path::PathBuf,
+        /// Process holding the lock, as it recorded in the lock file
+        pid: Option<u32>,
+    },
+    /// Write attempted on a store opened read-only
+    #[error("Store was opened read-only")]
+    ReadOnly,
+    /// Missing key, or data that doesn't authenticate with the key it claims
+    #[error("Encryption error: {}", _0)]
+    Encryption(String),
+    /// Backup or restore failure
+    #[error("Backup error: {}", _0)]
+    Backup(String),
+    /// Directory written by another engine, or in an on-disk format this build can't read
+    #[error("Incompatible store: {}", _0)]
+    Incompatible(String),
+    /// Point-in-time recovery failure
+    #[error("Recovery error: {}", _0)]
+    Recover(String),
     /// Io Error
     #[error("{}", _0)]
     Io(#[from] io::Error),
@@ -32,9 +79,15 @@ pub enum DbError {
     /// Ron SpannedResult error
     #[error("{}", _0)]
     RonSpanned(#[from] ron::error::SpannedError),
+    /// Csv Error
+    #[error("{}", _0)]
+    Csv(#[from] csv::Error),
     /// Sled Error
     #[error("{}", _0)]
     SledError(#[from] sled::Error),
+    /// Rayon thread pool that couldn't be started
+    #[error("{}", _0)]
+    Rayon(#[from] rayon::ThreadPoolBuildError),
     /// Sled byte UTF-8 cast failure
     #[error("{}", _0)]
     SledUtf8Error(#[from] std::string::FromUtf8Error),
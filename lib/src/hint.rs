@@ -0,0 +1,86 @@
+//! Hint files, Bitcask style.
+//!
+//! When a segment is frozen, the index entries it contributes are written next to it as
+//! `kv_<id>.hint`. Opening a store loads the hints of frozen segments instead of
+//! parsing every command they hold, and only replays segments without a usable hint.
+
+use crate::blob::BlobPointer;
+use crate::crypto::Keyring;
+use crate::record::Stamp;
+use crate::{LogPointer, Result};
+#[allow(unused_imports)]
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Index entries of a single frozen segment
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Hint {
+    /// Length of the segment the hint was taken from
+    pub(crate) len: u64,
+    /// One entry per command in the segment, in log order
+    pub(crate) entries: Vec<HintEntry>,
+    /// For a segment written by compaction, the last write and the time of the compaction.
+    /// History older than that was compacted away
+    #[serde(default)]
+    pub(crate) compacted: Option<Stamp>,
+}
+
+/// Effect of one command on the in-memory index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HintEntry {
+    pub(crate) key: String,
+    pub(crate) pointer: LogPointer,
+    /// `true` for a `Remove`, in which case `pointer` locates the remove command itself
+    pub(crate) removed: bool,
+    /// Where the value lives when it was separated into a blob file
+    #[serde(default)]
+    pub(crate) blob: Option<BlobPointer>,
+    /// Sequence number of the command
+    #[serde(default)]
+    pub(crate) seq: u64,
+}
+
+/// File name of the hint for segment `id`
+pub(crate) fn hint_name(id: u64) -> String {
+    format!("kv_{id:05}.hint")
+}
+
+/// Path of the hint for segment `id` inside `dir`
+pub(crate) fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(hint_name(id))
+}
+
+impl Hint {
+    /// Load the hint of segment `id`, provided it matches a segment of `len` bytes
+    pub(crate) fn load(dir: &Path, id: u64, len: u64, keyring: &Keyring) -> Option<Hint> {
+        let contents = fs::read(hint_path(dir, id)).ok()?;
+        let parsed = keyring
+            .open_file(contents)
+            .and_then(|contents| Ok(ron::de::from_bytes::<Hint>(&contents)?));
+        match parsed {
+            Ok(hint) if hint.len == len => Some(hint),
+            Ok(_) => {
+                warn!("Hint for segment {id} doesn't match the segment, ignoring it");
+                None
+            }
+            Err(err) => {
+                warn!("Cannot load hint for segment {id}: {:?}", err);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, dir: &Path, id: u64, keyring: &Keyring) -> Result<()> {
+        let contents = keyring.seal_file(ron::to_string(self)?.into_bytes())?;
+        fs::write(hint_path(dir, id), contents)?;
+        debug!(
+            "Wrote hint for segment {id} with {} entries",
+            self.entries.len()
+        );
+        Ok(())
+    }
+}
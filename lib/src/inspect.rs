@@ -0,0 +1,144 @@
+//! Record by record listing of a `KvStore` log, as printed by `kvs log inspect`.
+//!
+//! Positions are those of [`LogPointer`](crate::LogPointer)s, so what the listing shows can be matched against
+//! `kv_memory.index` and the hint files.
+
+use crate::crypto::{Keyring, SEAL_OVERHEAD};
+use crate::record::{self, BlobRecord, Record, RmRecord, SetRecord};
+use crate::{segment_ids, segment_name, segment_path, DbError, EncryptionKey, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs::File, io::BufReader, path::Path};
+
+/// Kind of a logged record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    /// Set with the value held in the log
+    Set,
+    /// Set with the value held in a blob file
+    Blob,
+    /// Remove
+    Rm,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Op::Set => "SET",
+            Op::Blob => "BLOB",
+            Op::Rm => "RM",
+        })
+    }
+}
+
+/// One record of the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Segment id, as in `kv_<segment>.log`
+    pub segment: u64,
+    /// Byte offset of the record within its segment
+    pub offset: u64,
+    /// Length of the record on disk, not counting the trailing newline
+    pub len: u64,
+    /// Sequence number of the write, from 1. `0` for records logged before writes were numbered
+    pub seq: u64,
+    /// When the write happened, in milliseconds since the UNIX epoch. `0` when unknown
+    pub time: u64,
+    /// What the record does
+    pub op: Op,
+    /// Key the record applies to
+    pub key: String,
+    /// Length of the value set, `None` for removes
+    pub value_size: Option<u64>,
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} @{:<8} #{:<6} {:<4} {:?}",
+            segment_name(self.segment),
+            self.offset,
+            self.seq,
+            self.op,
+            self.key
+        )?;
+        match self.value_size {
+            Some(size) => write!(f, " ({size} bytes)"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Which records [`inspect`] lists, all of them by default
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    /// Only the records of this key
+    pub key: Option<String>,
+    /// Only the records of keys matching this pattern
+    pub pattern: Option<Regex>,
+    /// Only records of these kinds
+    pub ops: Vec<Op>,
+}
+
+impl LogFilter {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        self.key.as_ref().is_none_or(|key| *key == entry.key)
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(&entry.key))
+            && (self.ops.is_empty() || self.ops.contains(&entry.op))
+    }
+}
+
+/// List the records of the store in `dir` in log order, oldest segment first, without opening the store.
+/// Encrypted stores need their keys. Fails on the first record that can't be read, see [`crate::verify`].
+pub fn inspect(
+    dir: &Path,
+    key: Option<&EncryptionKey>,
+    previous_keys: &[EncryptionKey],
+    filter: &LogFilter,
+) -> Result<Vec<LogEntry>> {
+    if !dir.is_dir() {
+        return Err(DbError::DatabaseNotFound(dir.to_path_buf()));
+    }
+    let keyring = Keyring::new(key, previous_keys);
+    let mut entries = vec![];
+    for id in segment_ids(dir)? {
+        let mut reader = BufReader::new(File::open(segment_path(dir, id))?);
+        let mut offset = 0;
+        while let Some((raw, consumed)) = record::read_next(&mut reader)? {
+            let record = record::decode(&raw, &keyring).map_err(|err| {
+                DbError::Corrupted(format!("{} at byte {offset}: {err}", segment_name(id)))
+            })?;
+            let stamp = record.stamp();
+            let (op, key, value_size) = match record {
+                Record::Set(SetRecord { key, value, .. }) => {
+                    (Op::Set, key, Some(value.len() as u64))
+                }
+                Record::Blob(BlobRecord { key, blob, .. }) => {
+                    let overhead = blob.key_id.map_or(0, |_| SEAL_OVERHEAD);
+                    (Op::Blob, key, Some(blob.len - overhead))
+                }
+                Record::Remove(RmRecord { key, .. }) => (Op::Rm, key, None),
+            };
+            let entry = LogEntry {
+                segment: id,
+                offset,
+                len: raw.len() as u64,
+                seq: stamp.seq,
+                time: stamp.time,
+                op,
+                key,
+                value_size,
+            };
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+            offset += consumed;
+        }
+    }
+    Ok(entries)
+}
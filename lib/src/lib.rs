@@ -4,47 +4,110 @@
 //! [Find out more here](https://github.com/pingcap/talent-plan/blob/master/courses/rust/projects/project-1/README.md)
 //!
 //! - *command* - A request or the representation of a request made to the database.
-//! These are issued on the command line or over the network.
-//! They have an in-memory representation, a textual representation, and a machine-readable serialized representation.
+//!   These are issued on the command line or over the network.
+//!   They have an in-memory representation, a textual representation, and a machine-readable serialized representation.
 //!
 //! - *log* - An on-disk sequence of commands, in the order originally received and executed.
-//! Our database's on-disk format is almost entirely made up of logs.
-//! It will be simple, but also surprisingly efficient.
+//!   Our database's on-disk format is almost entirely made up of logs.
+//!   It will be simple, but also surprisingly efficient.
 //!
 //! - *log pointer* - A file offset into the log. Sometimes we'll just call this a "file offset".
 //!
+//! - *segment* - The log is split across numbered files `kv_00001.log`, `kv_00002.log`, ..
+//!   Only the newest, *active*, segment is appended to. Older segments are frozen and never change,
+//!   which is what lets backups copy them while the store stays online.
+//!
 //! - *log compaction* - As writes are issued to the database they sometimes invalidate old log entries.
-//! For example, writing key/value a = 0 then writing a = 1, makes the first log entry for "a" useless.
-//! Compaction — in our database at least — is the process of reducing the size of the database by remove stale commands from the log.
+//!   For example, writing key/value a = 0 then writing a = 1, makes the first log entry for "a" useless.
+//!   Compaction — in our database at least — is the process of reducing the size of the database by remove stale commands from the log.
 //!
 //! - *in-memory index (or index)* - A map of keys to log pointers.
-//! When a read request is issued, the in-memory index is searched for the appropriate log pointer,
-//! and when it is found the value is retrieved from the on-disk log. In our key/value store, like in bitcask,
-//! the index for the entire database is stored in memory.
+//!   When a read request is issued, the in-memory index is searched for the appropriate log pointer,
+//!   and when it is found the value is retrieved from the on-disk log. In our key/value store, like in bitcask,
+//!   the index for the entire database is stored in memory.
 //!
 //! - *index file* - The on-disk representation of the in-memory index.
-//! Without this the log would need to be completely replayed to restore the state of the in-memory index each time the database is started.
+//!   Without this the log would need to be completely replayed to restore the state of the in-memory index each time the database is started.
+//!
+//! - *blob file* - Values above a configurable size are kept out of the log in `kv_<id>.blob` files,
+//!   the log only holding a pointer to them. Compaction then doesn't need to rewrite them,
+//!   and blob files are garbage collected on their own.
+//!
+//! - *compression* - Sets with large enough values can be compressed with lz4 or zstd before they're logged.
+//!   The codec is recorded in a header in front of each compressed record, so reads never need to be told.
+//!
+//! - *encryption at rest* - Given a key, records, blob values, hints and the index file are sealed with
+//!   ChaCha20-Poly1305 before they're written. Compaction re-seals everything with the current key.
+//!
+//! - *lock file* - A store open for writing holds an exclusive lock on its `LOCK` file, so a second writer,
+//!   say the `kvs` CLI next to a running `kvs-server`, is turned away instead of interleaving records.
+//!   Stores opened read-only don't take it and can run alongside the writer.
+//!
+//! - *read cache* - An optional, size bounded, cache of recently read values sitting in front of the log,
+//!   so hot keys don't need a seek and a parse on every read.
+//!
+//! - *manifest* - `MANIFEST` names the engine a directory belongs to, the version of its on-disk format,
+//!   its segments and the options it was created with. Opening a store checks it first.
+//!
+//! - *stamp* - Each record carries the sequence number of the write it logs and the time it was logged.
+//!   Compaction keeps the stamps of the records it rewrites, which is what point-in-time recovery relies on.
 
 use lazy_static::lazy_static;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use ron::ser::PrettyConfig;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
-    collections::{BTreeMap, HashMap},
-    fs::{File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Read, Seek, Write},
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 
+pub mod backup;
+mod blob;
+mod cache;
+pub mod cache_mode;
 pub mod cli;
+mod codec;
+pub mod crypto;
 mod error;
+mod hint;
+pub mod inspect;
+mod lock;
+pub mod lsm;
+pub mod manifest;
+mod record;
+pub mod recover;
+pub mod repair;
+mod shared;
+mod stats;
+pub mod thread_pool;
+pub mod transfer;
 mod utils;
+pub mod verify;
+pub use backup::BackupManifest;
+pub use cache::CacheStats;
+pub use cache_mode::CacheEngine;
+pub use codec::{Codec, CompressionStats};
+pub use crypto::EncryptionKey;
 pub use error::{DbError, Result};
+pub use lsm::LsmStore;
+pub use manifest::Manifest;
+pub use shared::SharedEngine;
+pub use stats::EngineStats;
+pub use thread_pool::ThreadPool;
 pub use utils::*;
 
-use crate::cli::{Action, RmCmd, SetCmd};
+use crate::backup::{KVS_ENGINE, SLED_ENGINE};
+use crate::blob::{blob_name, BlobPointer, Blobs};
+use crate::cache::ValueCache;
+use crate::cli::{Action, RmCmd};
+use crate::crypto::Keyring;
+use crate::hint::{hint_name, hint_path, Hint, HintEntry};
+use crate::record::{BlobRecord, Record, RmRecord, SetRecord, Stamp};
+use crate::stats::Compactions;
 
 lazy_static! {
     static ref RON_CONFIG: PrettyConfig = PrettyConfig::default()
@@ -61,277 +124,740 @@ pub trait KvsEngine {
     fn get(&self, key: String) -> Result<Option<String>>;
     /// Remove key
     fn remove(&mut self, key: String) -> Result<()>;
+    /// Lazily iterate over all live key-value pairs whose key starts with `prefix`
+    fn scan<'a>(&'a self, prefix: &'a str) -> Result<KvPairs<'a>>;
+    /// Copy a consistent snapshot of the store into `dir` without closing it,
+    /// and describe what was copied in a [`BackupManifest`] written alongside it.
+    /// With `since` pointing at an earlier backup, only what changed since then is copied.
+    fn backup_to(&mut self, dir: &Path, since: Option<&Path>) -> Result<BackupManifest>;
+    /// Figures on the size of the store and the work it has done since it was opened
+    fn stats(&self) -> Result<EngineStats>;
+    /// Make every write so far durable, and save whatever spares the next open some work,
+    /// as before a shutdown
+    fn sync(&mut self) -> Result<()>;
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+    fn scan<'a>(&'a self, prefix: &'a str) -> Result<KvPairs<'a>> {
+        (**self).scan(prefix)
+    }
+    fn backup_to(&mut self, dir: &Path, since: Option<&Path>) -> Result<BackupManifest> {
+        (**self).backup_to(dir, since)
+    }
+    fn stats(&self) -> Result<EngineStats> {
+        (**self).stats()
+    }
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
 }
 
+/// Streaming iterator over key-value pairs produced by [`KvsEngine::scan`]
+pub type KvPairs<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
 /// File offset
 pub type Offset = u64;
+
+/// Once the active segment grows past this many bytes it is frozen and a new one is started
+const SEGMENT_SIZE: u64 = 1 << 20;
+/// Bytes of stale commands tolerated across all segments before compaction kicks in
+const COMPACTION_THRESHOLD: u64 = 1 << 20;
+/// On-disk representation of the in-memory index
+const INDEX_FILE: &str = "kv_memory.index";
+/// Values shorter than this rarely shrink enough to make up for the frame header
+const COMPRESS_MIN_SIZE: u64 = 64;
+
+/// Location of a serialized command inside the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogPointer {
+    /// Segment id, as in `kv_<segment>.log`
+    pub segment: u64,
+    /// Byte offset of the command within its segment
+    pub offset: Offset,
+    /// Length of the command in bytes, not counting the trailing newline
+    pub len: u64,
+}
+
+/// Settings a [`KvStore`] is opened with
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// Bytes of keys and values the read cache may hold. `0`, the default, disables the cache
+    pub cache_size: u64,
+    /// Values longer than this many bytes are stored in blob files rather than in the log.
+    /// `None`, the default, keeps every value in the log
+    pub blob_threshold: Option<u64>,
+    /// Codec sets are compressed with before being logged, none by default
+    pub compression: Codec,
+    /// Values shorter than this many bytes are logged uncompressed whatever the codec
+    pub compress_min_size: u64,
+    /// Key everything written is sealed with. `None`, the default, writes in the clear
+    pub encryption_key: Option<EncryptionKey>,
+    /// Keys rotated away from, only used to read what they sealed until compaction re-seals it
+    pub previous_keys: Vec<EncryptionKey>,
+    /// Open without taking the writer lock, every write is then refused
+    pub read_only: bool,
+}
+
+impl KvStoreOptions {
+    /// Options a store was created with, as its [`Manifest`] records them
+    fn manifest_options(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            (
+                "blob_threshold".to_owned(),
+                self.blob_threshold
+                    .map_or("none".to_owned(), |threshold| threshold.to_string()),
+            ),
+            (
+                "compression".to_owned(),
+                format!("{:?}", self.compression).to_lowercase(),
+            ),
+            (
+                "compress_min_size".to_owned(),
+                self.compress_min_size.to_string(),
+            ),
+            (
+                "encrypted".to_owned(),
+                self.encryption_key.is_some().to_string(),
+            ),
+        ])
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            cache_size: 0,
+            blob_threshold: None,
+            compression: Codec::None,
+            compress_min_size: COMPRESS_MIN_SIZE,
+            encryption_key: None,
+            previous_keys: vec![],
+            read_only: false,
+        }
+    }
+}
+
 /// KvStore implementation
 #[derive(Debug, Default)]
 pub struct KvStore {
-    /// In memory index from key -> offset in log
-    pub(crate) map: HashMap<String, Offset>,
-    pub(crate) disk: Option<RefCell<File>>,
+    /// Directory holding the log segments
+    pub(crate) dir: PathBuf,
+    /// In memory index from key -> pointer into the log
+    pub(crate) map: HashMap<String, LogPointer>,
+    /// Lazily opened read handles, one per segment
+    pub(crate) readers: RefCell<HashMap<u64, File>>,
+    /// Append handle on the active segment
+    pub(crate) writer: Option<File>,
+    /// Id of the active segment, the only one that is ever written to
+    pub(crate) active: u64,
+    /// Length of the active segment, i.e. where the next command lands
     pub(crate) offset: Offset,
+    /// Bytes taken up by commands that no longer contribute to the store's state
+    pub(crate) stale: u64,
+    /// Recently read values, if the store was opened with a cache
+    pub(crate) cache: Option<RefCell<ValueCache>>,
+    /// Size above which values are separated into blob files
+    pub(crate) blob_threshold: Option<u64>,
+    /// Values separated from the log
+    pub(crate) blobs: Blobs,
+    /// Codec applied to sets with a value of at least `compress_min_size` bytes
+    pub(crate) codec: Codec,
+    pub(crate) compress_min_size: u64,
+    /// Bytes written to the log, before and after compression
+    pub(crate) compression: CompressionStats,
+    /// Keys data is sealed with, empty when the store isn't encrypted
+    pub(crate) keyring: Keyring,
+    /// Writer lock on the directory, held until the store is dropped. `None` when read-only
+    _lock: Option<File>,
+    pub(crate) read_only: bool,
+    /// Compactions run since the store was opened
+    pub(crate) compactions: Compactions,
+    /// Sequence number of the last command logged
+    pub(crate) seq: u64,
+    manifest: Manifest,
+}
+
+/// Persisted copy of the in-memory index, valid as long as the segments it was taken from are unchanged
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot {
+    segments: Vec<u64>,
+    offset: Offset,
+    stale: u64,
+    map: HashMap<String, LogPointer>,
+    #[serde(default)]
+    blobs: HashMap<String, BlobPointer>,
+    #[serde(default)]
+    seq: u64,
 }
 
 impl KvStore {
     /// Open on disk KvStore.
-    /// On startup, the commands in the log are traversed from oldest to newest, and the in-memory index rebuilt.
-    /// When the size of the uncompacted log entries reach a given threshold,
-    /// kvs compacts it into a new log, removing redundent entries to reclaim disk space.
+    /// The log is split into numbered segments `kv_00001.log`, `kv_00002.log`, ..
+    /// of which only the newest one is ever appended to; the rest are frozen and immutable.
+    /// On startup, the commands in the log are traversed from oldest to newest, and the in-memory index rebuilt,
+    /// unless a valid `kv_memory.index` lets us skip straight to the tail of the active segment.
+    /// When the size of the stale log entries reaches a given threshold,
+    /// kvs compacts them into a new segment, removing redundent entries to reclaim disk space.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open on disk KvStore, as [`KvStore::open`] does, with the given options
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let mut dir: PathBuf = path.into();
+        // If path is a log file, open the store it belongs to
+        if dir.is_file() {
+            dir = dir
+                .parent()
+                .map(Path::to_path_buf)
+                .ok_or_else(|| DbError::DatabaseNotFound(dir.clone()))?;
+        }
+        if !dir.is_dir() {
+            return Err(DbError::DatabaseNotFound(dir));
+        }
+        let manifest = Manifest::check(&dir, KVS_ENGINE)?;
+        let lock = match options.read_only {
+            true => None,
+            false => Some(lock::acquire(&dir)?),
+        };
+        let keyring = Keyring::new(options.encryption_key.as_ref(), &options.previous_keys);
         let mut store = KvStore {
-            map: HashMap::new(),
-            disk: None,
-            offset: Default::default(),
+            blobs: Blobs::open(&dir, keyring.clone())?,
+            keyring,
+            dir,
+            cache: (options.cache_size > 0)
+                .then(|| RefCell::new(ValueCache::new(options.cache_size))),
+            blob_threshold: options.blob_threshold,
+            codec: options.compression,
+            compress_min_size: options.compress_min_size,
+            _lock: lock,
+            read_only: options.read_only,
+            manifest: manifest
+                .unwrap_or_else(|| Manifest::new(KVS_ENGINE, options.manifest_options())),
+            ..Default::default()
         };
-        // -- Load log file into KvStore --
-        let wal_path: PathBuf = path.into();
-        let opener = |path: &PathBuf| -> Result<RefCell<File>> {
-            Ok(RefCell::new(
-                OpenOptions::new().read(true).append(true).open(&path)?,
-            ))
+        let segments = store.load()?;
+        if !store.read_only {
+            store.open_active(store.active)?;
+        }
+        debug!(
+            "KvStore initialized with {} segments, active segment {} at offset {}",
+            segments, store.active, store.offset
+        );
+        Ok(store)
+    }
+
+    /// Open on disk KvStore for reading only, next to a process that may be writing to it.
+    /// Nothing is ever written, not even the active segment, and no lock is taken.
+    /// See [`KvStore::refresh`] to pick up what the writer appends afterwards.
+    /// Encrypted stores need their key, and are opened with [`KvStoreOptions::read_only`] instead.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(
+            path,
+            KvStoreOptions {
+                read_only: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Catch up with a writer: pick up the commands appended to the active segment since the store was opened
+    /// or last refreshed, and those of the segments started since, without replaying anything already seen.
+    /// If the writer compacted the log in the meantime, the index is rebuilt from the compacted segments.
+    /// Returns whether anything changed.
+    pub fn refresh(&mut self) -> Result<bool> {
+        let active_len = fs::metadata(segment_path(&self.dir, self.active))
+            .map(|metadata| metadata.len())
+            .ok();
+        let compacted = match active_len {
+            Some(len) => len < self.offset,
+            None => self.offset > 0,
         };
-        // If path is a file, load that file
-        if wal_path.is_file() {
-            let file = opener(&wal_path)?;
-            store.disk = Some(file);
-        } else if wal_path.is_dir() {
-            let wal_path = wal_path.join(Path::new("kv_00001.log"));
-            // Try to open default kv_00001.log, or create a file
-            match std::fs::metadata(&wal_path) {
-                Ok(_) => store.disk = Some(opener(&wal_path)?),
-                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
-                    info!("No kv log found, creating a new one");
-                    store.disk = Some(RefCell::new(
-                        OpenOptions::new()
-                            .read(true)
-                            .append(true)
-                            .create(true)
-                            .open(&wal_path)?,
-                    ));
-                    info!("File created and opened successfully: {:?}", wal_path);
-                }
-                Err(unhandled_err) => return Err(unhandled_err.into()),
+        if compacted {
+            debug!("Log was compacted since the last refresh, reloading the index");
+            self.map.clear();
+            self.stale = 0;
+            self.readers.borrow_mut().clear();
+            self.blobs = Blobs::open(&self.dir, self.keyring.clone())?;
+            if let Some(cache) = &self.cache {
+                cache.borrow_mut().clear();
             }
+            self.load()?;
+            return Ok(true);
         }
-        assert!(store.disk.is_some());
-        let mut disk = store
-            .disk
-            .as_ref()
-            .expect("Checked above | cannot fail")
-            .borrow_mut();
+        let seen = (self.active, self.offset);
+        let segments: Vec<u64> = segment_ids(&self.dir)?
+            .into_iter()
+            .filter(|&id| id >= seen.0)
+            .collect();
+        for id in segments {
+            let from = if id == self.active { self.offset } else { 0 };
+            let (entries, end) = read_segment(&self.dir, id, from, &self.keyring, true)?;
+            for entry in entries {
+                self.invalidate(&entry.key);
+                self.apply(entry);
+            }
+            (self.active, self.offset) = (id, end);
+        }
+        Ok((self.active, self.offset) != seen)
+    }
+
+    /// Build the in-memory index from the files on disk, leaving `active` and `offset` at the end of the log.
+    /// Returns the number of segments found.
+    fn load(&mut self) -> Result<usize> {
+        let segments = segment_ids(&self.dir)?;
+        if segments.is_empty() {
+            info!("No kv log found, creating a new one");
+        }
+        let active = segments.last().copied().unwrap_or(1);
+        let mut end = 0;
         // -- Initialize the memory map with disk commands --
-        // Check if a in memory index is already built, if yes, use that :
-        let mem_idx = std::env::current_dir().unwrap().join("kv_memory.index");
-        if mem_idx.exists() {
-            debug!("Loading in memory index from file {mem_idx:?}");
-            match ron::from_str(std::fs::read_to_string(&mem_idx).unwrap().as_str()) {
-                Ok(map) => {
-                    store.map = map;
-                    store.offset =
-                        (BufReader::new(disk.try_clone()?).lines().count() + 1) as Offset;
-                    debug!("Loaded in memory index with offset {}", store.offset);
-                }
-                Err(err) => error!("Cannot load in memory index: {:?}", err),
+        // Check if a in memory index is already built, if yes, use that and only replay what came after it :
+        match self.load_index(&segments) {
+            Some(offset) => {
+                end = self.replay_segment(active, offset)?;
             }
-        } else {
-            let log = read_action_from_log(&mut disk)?;
-            // -- Replay the commands in the log --
-            let mut offset: Offset = 0;
-            for (idx, action) in log.iter().enumerate() {
-                // Offset is just tracking the current deserialized index of Action in the list of Actions
-                // Offset numbering begins at 1. Offset of 1 = first line of the log
-                offset = (idx + 1) as Offset;
-                match action {
-                    Action::Set(SetCmd { key, .. }) => {
-                        store.map.insert(key.clone(), offset);
-                    }
-                    Action::Get(_) => {
-                        /* Idempotent action.
-                        We should not increase offset on a Get since we ensure we never write a Get to a log */
-                    }
-                    Action::Remove(RmCmd { key }) => {
-                        if let Some(_rm_offset) = store.map.remove(key) {}
+            None => {
+                for &id in &segments {
+                    // Frozen segments come with a hint that saves us parsing them
+                    let len = fs::metadata(segment_path(&self.dir, id))?.len();
+                    match Hint::load(&self.dir, id, len, &self.keyring).filter(|_| id != active) {
+                        Some(hint) => hint.entries.into_iter().for_each(|e| self.apply(e)),
+                        None => end = self.replay_segment(id, 0)?,
                     }
-                };
+                }
             }
-            // Store latest (empty) offset in KvStore for future insertions
-            store.offset = offset + 1;
-            debug!(
-                "KvStore initialized without in-mem index and offset {}",
-                store.offset
-            );
-        }
-        drop(disk);
-        Ok(store)
+        }
+        (self.active, self.offset) = (active, end);
+        Ok(segments.len())
     }
 
-    /// Run compaction on the disk log
+    /// Log a set of `key` to `value` stamped with `stamp`, which is kept as is when it comes from another store
+    pub(crate) fn write(&mut self, key: String, value: String, stamp: Stamp) -> Result<()> {
+        let blob = match self.blob_threshold {
+            Some(threshold) if value.len() as u64 > threshold => {
+                Some(self.blobs.write(value.as_bytes())?)
+            }
+            _ => None,
+        };
+        let record = match blob {
+            Some(blob) => Record::Blob(BlobRecord {
+                key: key.clone(),
+                blob,
+                stamp,
+            }),
+            None => Record::Set(SetRecord {
+                key: key.clone(),
+                value,
+                stamp,
+            }),
+        };
+        let pointer = self.append(&record)?;
+        self.seq = self.seq.max(stamp.seq);
+        self.blobs.track(&key, blob);
+        self.invalidate(&key);
+        if let Some(old) = self.map.insert(key, pointer) {
+            self.stale += old.len + 1;
+        }
+        self.maintain()
+    }
+
+    /// Run compaction on the disk log.
+    /// Every live command is rewritten into a fresh segment, after which all older segments are deleted.
+    /// Commands are re-encoded on the way, so they end up compressed with the codec the store was opened with,
+    /// and sealed with its current key. Blob values sealed with another key are moved over first.
     pub fn compaction(&mut self) -> Result<()> {
-        let mut file = self
-            .disk
-            .as_ref()
-            .ok_or(DbError::Uninitialized)?
-            .borrow_mut()
-            .try_clone()?;
-        file.rewind()?;
-
-        let log: Vec<Action> = read_action_from_log(&mut file)?;
-        // Hold unique keys last set value, None in case it was removed
-        let mut unique_keys: BTreeMap<String, Option<String>> = BTreeMap::new();
-        let mut compacted_log: Vec<Action> = Vec::with_capacity(log.capacity());
-        // debug!("Log to compact : {log:?}");
-        log.iter().rev().for_each(|action| {
-            // debug!("compact OFFSET: {offset}");
-            match action {
-                Action::Set(SetCmd { key, value }) => {
-                    if !unique_keys.contains_key(key) {
-                        unique_keys.insert(key.to_string(), Some(value.to_string()));
-                    }
-                }
-                Action::Get(_) => (),
-                Action::Remove(RmCmd { key }) => {
-                    if !unique_keys.contains_key(key) {
-                        unique_keys.insert(key.to_string(), None);
-                    }
-                }
+        self.check_writable()?;
+        self.reseal_blobs()?;
+        let compacted = self.active + 1;
+        let tmp_path = segment_path(&self.dir, compacted).with_extension("log.compacting");
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        let mut hint = Hint::default();
+        let live: Vec<LogPointer> = self.map.values().copied().collect();
+        for pointer in live {
+            // Records keep their stamps, only the history before them goes away
+            let record = self.read_record(pointer)?;
+            let command = self.encode(&record)?;
+            out.write_all(&command)?;
+            out.write_all(b"\n")?;
+            let len = command.len() as u64;
+            hint.entries.push(record.into_entry(LogPointer {
+                segment: compacted,
+                offset: hint.len,
+                len,
+            }));
+            hint.len += len + 1;
+        }
+        hint.compacted = Some(Stamp::now(self.seq));
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        // The compacted segment only becomes visible once it's complete
+        fs::rename(&tmp_path, segment_path(&self.dir, compacted))?;
+        hint.save(&self.dir, compacted, &self.keyring)?;
+        self.readers.borrow_mut().clear();
+        if let Some(cache) = &self.cache {
+            cache.borrow_mut().clear();
+        }
+        for id in segment_ids(&self.dir)?
+            .into_iter()
+            .filter(|&id| id < compacted)
+        {
+            fs::remove_file(segment_path(&self.dir, id))?;
+            // Segments frozen before hints existed have none
+            let _ = fs::remove_file(hint_path(&self.dir, id));
+        }
+        self.map = hint
+            .entries
+            .into_iter()
+            .map(|entry| (entry.key, entry.pointer))
+            .collect();
+        self.stale = 0;
+        self.open_active(compacted + 1)?;
+        self.compactions.record();
+        debug!(
+            "Post compaction, {} live keys in segment {compacted}",
+            self.map.len()
+        );
+        Ok(())
+    }
+
+    /// Write the in-memory index to `kv_memory.index` so the next [`KvStore::open`] doesn't need a full replay
+    pub fn persist_index(&self) -> Result<()> {
+        self.check_writable()?;
+        let snapshot = IndexSnapshot {
+            segments: segment_ids(&self.dir)?,
+            offset: self.offset,
+            stale: self.stale,
+            map: self.map.clone(),
+            blobs: self.blobs.index.clone(),
+            seq: self.seq,
+        };
+        let tmp_path = self.dir.join(INDEX_FILE).with_extension("index.tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(
+            &self
+                .keyring
+                .seal_file(ron::to_string(&snapshot)?.into_bytes())?,
+        )?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(tmp_path, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    /// Counters of the read cache, `None` when the store was opened without one
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.borrow().stats())
+    }
+
+    /// Bytes written to the log since the store was opened, before and after compression
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression
+    }
+
+    /// Refuse writes on a store opened read-only
+    fn check_writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(DbError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Drop `key` from the read cache, if any
+    fn invalidate(&self, key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.borrow_mut().invalidate(key);
+        }
+    }
+
+    /// Load `kv_memory.index` if it was taken from the segments currently on disk.
+    /// Returns the offset in the active segment from which replay has to resume.
+    fn load_index(&mut self, segments: &[u64]) -> Option<Offset> {
+        let path = self.dir.join(INDEX_FILE);
+        let contents = fs::read(&path).ok()?;
+        let parsed = self
+            .keyring
+            .open_file(contents)
+            .and_then(|contents| Ok(ron::de::from_bytes(&contents)?));
+        let snapshot: IndexSnapshot = match parsed {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                error!("Cannot load in memory index: {:?}", err);
+                return None;
             }
-        });
-        debug!("Unique Keys : {:?}", unique_keys);
-        // Rebuild log
-        compacted_log.extend(unique_keys.into_iter().rev().map(|(key, value)| {
-            if let Some(value) = value {
-                Action::Set(SetCmd { key, value })
-            } else {
-                Action::Remove(RmCmd { key })
+        };
+        let active_len = segments
+            .last()
+            .and_then(|&id| fs::metadata(segment_path(&self.dir, id)).ok())
+            .map(|metadata| metadata.len())?;
+        if snapshot.segments != segments || active_len < snapshot.offset {
+            warn!("In memory index is out of date with the log, replaying it instead");
+            return None;
+        }
+        debug!("Loaded in memory index from file {path:?}");
+        self.map = snapshot.map;
+        self.stale = snapshot.stale;
+        self.seq = snapshot.seq;
+        for (key, blob) in snapshot.blobs {
+            self.blobs.track(&key, Some(blob));
+        }
+        Some(snapshot.offset)
+    }
+
+    /// Replay the commands of segment `id` starting at byte `from` into the in-memory index.
+    /// Returns the offset right after the last command replayed.
+    fn replay_segment(&mut self, id: u64, from: Offset) -> Result<Offset> {
+        // A writer may be halfway through its last command
+        let tail = self.read_only;
+        let (entries, end) = read_segment(&self.dir, id, from, &self.keyring, tail)?;
+        for entry in entries {
+            self.apply(entry);
+        }
+        Ok(end)
+    }
+
+    /// Apply the effect of one logged command to the in-memory index
+    fn apply(
+        &mut self,
+        HintEntry {
+            key,
+            pointer,
+            removed,
+            blob,
+            seq,
+        }: HintEntry,
+    ) {
+        self.seq = self.seq.max(seq);
+        self.blobs.track(&key, blob);
+        if removed {
+            if let Some(old) = self.map.remove(&key) {
+                self.stale += old.len + 1;
+            }
+            self.stale += pointer.len + 1;
+        } else if let Some(old) = self.map.insert(key, pointer) {
+            self.stale += old.len + 1;
+        }
+    }
+
+    /// Make segment `id` the active segment, creating it if needed
+    fn open_active(&mut self, id: u64) -> Result<()> {
+        let path = segment_path(&self.dir, id);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        self.offset = file.metadata()?.len();
+        self.writer = Some(file);
+        self.active = id;
+        trace!("Active segment {path:?} at offset {}", self.offset);
+        let segments = segment_ids(&self.dir)?
+            .into_iter()
+            .map(segment_name)
+            .collect();
+        self.manifest.update(&self.dir, segments)
+    }
+
+    /// Freeze the active segment, leaving a hint behind, and start appending to a new one
+    fn roll(&mut self) -> Result<()> {
+        let hint = Hint {
+            len: self.offset,
+            entries: read_segment(&self.dir, self.active, 0, &self.keyring, false)?.0,
+            compacted: None,
+        };
+        hint.save(&self.dir, self.active, &self.keyring)?;
+        self.open_active(self.active + 1)
+    }
+
+    /// Serialize `record` as it's stored in the log, compressing it if it's a large enough set.
+    /// Compressed records are only kept when they actually came out smaller.
+    /// In an encrypted store every record is framed and sealed.
+    fn encode(&mut self, record: &Record) -> Result<Vec<u8>> {
+        let serialized = ron::ser::to_string_pretty(record, RON_CONFIG.to_owned())?.into_bytes();
+        let compress = match record {
+            Record::Set(SetRecord { value, .. }) => {
+                self.codec != Codec::None && value.len() as u64 >= self.compress_min_size
+            }
+            Record::Remove(_) | Record::Blob(_) => false,
+        };
+        self.compression.records += 1;
+        self.compression.raw_bytes += serialized.len() as u64;
+        let (mut codec, mut payload) = (Codec::None, serialized);
+        if compress {
+            let compressed = self.codec.compress(&payload)?;
+            if compressed.len() + record::FRAME_HEADER_LEN < payload.len() {
+                self.compression.compressed += 1;
+                (codec, payload) = (self.codec, compressed);
+            }
+        }
+        let encoded = if codec == Codec::None && !self.keyring.enabled() {
+            payload
+        } else {
+            record::frame(codec, &payload, &self.keyring)?
+        };
+        self.compression.stored_bytes += encoded.len() as u64;
+        Ok(encoded)
+    }
+
+    /// Append `record` to the active segment
+    pub(crate) fn append(&mut self, record: &Record) -> Result<LogPointer> {
+        let mut encoded = self.encode(record)?;
+        let writer = self.writer.as_mut().ok_or(DbError::Uninitialized)?;
+        let pointer = LogPointer {
+            segment: self.active,
+            offset: self.offset,
+            len: encoded.len() as u64,
+        };
+        encoded.push(b'\n');
+        // TODO : Maybe think about optimizing this? file sys-call on every command?
+        writer.write_all(&encoded)?;
+        self.offset += pointer.len + 1;
+        Ok(pointer)
+    }
+
+    /// Compact when there's enough garbage, otherwise roll the active segment once it's full
+    fn maintain(&mut self) -> Result<()> {
+        self.maintain_blobs()?;
+        if self.stale > COMPACTION_THRESHOLD {
+            self.compaction()
+        } else if self.offset >= SEGMENT_SIZE {
+            self.roll()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read the serialized command at `pointer`
+    fn read_raw(&self, pointer: LogPointer) -> Result<Vec<u8>> {
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(pointer.segment) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(File::open(segment_path(&self.dir, pointer.segment))?)
+            }
+        };
+        reader.seek(SeekFrom::Start(pointer.offset))?;
+        let mut buf = vec![0; pointer.len as usize];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read and decode the command at `pointer`
+    fn read_record(&self, pointer: LogPointer) -> Result<Record> {
+        record::decode(&self.read_raw(pointer)?, &self.keyring)
+    }
+
+    /// Read and evaluate the `Set` command at `pointer`, following it to its blob file if needed
+    fn read_value(&self, pointer: LogPointer) -> Result<String> {
+        match self.read_record(pointer)? {
+            Record::Set(SetRecord { value, .. }) => Ok(value),
+            Record::Blob(BlobRecord { blob, .. }) => self.blobs.read(blob),
+            Record::Remove(RmRecord { key, .. }) => {
+                Err(DbError::OffsetError(Action::Remove(RmCmd { key })))
             }
-        }));
-        // Recompute offsets
-        self.map.clear();
-        let mut offset: Offset = 1;
-        compacted_log.iter().for_each(|action| {
-            match action {
-                Action::Set(SetCmd { key, .. }) => {
-                    self.map.insert(key.clone(), offset);
-                }
-                Action::Get(_) => {}
-                Action::Remove(RmCmd { key }) => {
-                    self.map.remove(key);
-                }
-            };
-            offset += 1;
-        });
-        self.offset = offset;
-        debug!("Post compaction, current offset {}", self.offset);
-        // Write compacted_log to self.disk
-        let mut file = self
-            .disk
-            .as_ref()
-            .ok_or(DbError::Uninitialized)?
-            .borrow_mut();
-        file.rewind()?;
-        // Clear file contents
-        file.set_len(0)?;
-        // Write serialized to file but one entry at a time instead of as a Vec
-        for action in compacted_log {
-            let serialized = ron::ser::to_string_pretty(&action, RON_CONFIG.to_owned())? + "\n";
-            file.write_all(serialized.as_bytes())?;
         }
-        Ok(())
     }
 }
-/// Deserialize on disk log
-fn read_action_from_log(disk: &mut File) -> Result<Vec<Action>> {
-    let mut buf = String::new();
-    let _bytes_read = (*disk).read_to_string(&mut buf).map_err(|e| {
-        error!("Cannot load log file into memory");
-        e
-    })?;
-    let mut de = ron::Deserializer::from_str(&buf).expect("RON: deserializer init error");
-    let log: Vec<Action> = std::iter::from_fn({
-        move || {
-            de.end()
-                .is_err()
-                .then_some(Action::deserialize::<_>(&mut de))
-        }
-    })
-    .collect::<std::result::Result<Vec<_>, _>>()?;
-    Ok(log)
+
+/// Read the commands of segment `id` from byte `from` onwards as index entries,
+/// along with the offset right after the last one.
+/// With `tail`, a last command cut short is left for later rather than failing the whole read.
+pub(crate) fn read_segment(
+    dir: &Path,
+    id: u64,
+    from: Offset,
+    keyring: &Keyring,
+    tail: bool,
+) -> Result<(Vec<HintEntry>, Offset)> {
+    let mut reader = BufReader::new(File::open(segment_path(dir, id)).inspect_err(|_| {
+        error!("Cannot open log segment {id}");
+    })?);
+    reader.seek(SeekFrom::Start(from))?;
+    let mut entries = vec![];
+    let mut offset = from;
+    loop {
+        let (command, bytes_read) = match record::read_next(&mut reader) {
+            Ok(Some(next)) => next,
+            Ok(None) => break,
+            Err(DbError::Io(err)) if tail && err.kind() == io::ErrorKind::UnexpectedEof => {
+                trace!("Segment {id} ends with a partial command at {offset}");
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        let pointer = LogPointer {
+            segment: id,
+            offset,
+            len: command.len() as u64,
+        };
+        offset += bytes_read;
+        entries.push(record::decode(&command, keyring)?.into_entry(pointer));
+    }
+    Ok((entries, offset))
+}
+
+/// File name of segment `id`
+pub(crate) fn segment_name(id: u64) -> String {
+    format!("kv_{id:05}.log")
+}
+
+/// Path of segment `id` inside `dir`
+pub(crate) fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(segment_name(id))
+}
+
+/// Ids of all log segments found in `dir`, oldest first
+pub(crate) fn segment_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("kv_")?
+                .strip_suffix(".log")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
 }
 
 impl KvsEngine for KvStore {
     /// Set : When setting a key to a value, kvs writes the set command to disk in a sequential log,
     /// then stores the log pointer (file offset) of that command in the in-memory index from key to pointer.
+    /// Values above the blob threshold go to a blob file first, and the log records where.
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        if self.map.len() > 500 {
-            // trigger compaction
-            (*self).compaction()?;
-        }
-        let mut file = self
-            .disk
-            .as_ref()
-            .ok_or(DbError::Uninitialized)?
-            .borrow_mut();
-        self.map.insert(key.clone(), self.offset);
-        self.offset += 1;
-        let set_cmd = Action::Set(cli::SetCmd {
-            key: key.into(),
-            value: value.into(),
-        });
-        // serialize the set_cmd
-        let serialized = ron::ser::to_string_pretty(&set_cmd, RON_CONFIG.to_owned())? + "\n";
-        // let serialized = ron::ser::to_string(&set_cmd)? + "\n";
-        // write serialized to self.disk
-        // TODO : Maybe think about optimizing this? file sys-call on every set cmd?
-        file.write_all(serialized.as_bytes())?;
-
-        Ok(())
+        self.check_writable()?;
+        self.write(key, value, Stamp::now(self.seq + 1))
     }
     /// Get : When retrieving a value for a key with the get command, it searches the index,
     /// and if found then loads from the log the command at the corresponding log pointer,
     /// evaluates the command and returns the result.
+    /// With a read cache, values read recently are served from memory instead.
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(&offset) = self.map.get(&key) {
-            debug!("GET offset: {:?}", offset);
-            // File reset seek on self.disk
-            let mut file = self
-                .disk
-                .as_ref()
-                .ok_or(DbError::Uninitialized)?
-                .borrow_mut()
-                .try_clone()?;
-            file.rewind()?;
-            // Read file line number offset
-            let buf = BufReader::new(file)
-                .lines()
-                .enumerate()
-                .map(|(num, line)| {
-                    log::debug!("LOG line: {:?}", line.as_ref());
-                    line.expect(&format!(
-                        "Log Error : Failed to read LOG line number {}",
-                        num + 1
-                    ))
-                })
-                .nth(offset.saturating_sub(1) as usize)
-                .expect("Offset contents cannot be empty");
-            let set_cmd: Action = ron::de::from_str(&buf)?;
-            // Retain in memory idx if not already present in current working directory
-            let mem_idx = std::env::current_dir().unwrap().join("kv_memory.index");
-            if mem_idx.exists() {
-                let file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(mem_idx)?;
-                let mut file = BufWriter::new(file);
-                // Write contents of in memory map to file
-                file.write(ron::to_string(&self.map)?.as_bytes())?;
-            }
-            match set_cmd {
-                Action::Set(set_cmd) => Ok(Some(set_cmd.value)),
-                action => Err(DbError::OffsetError(action)),
+        if let Some(value) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.borrow_mut().get(&key))
+        {
+            return Ok(Some(value));
+        }
+        if let Some(&pointer) = self.map.get(&key) {
+            debug!("GET pointer: {:?}", pointer);
+            let value = self.read_value(pointer)?;
+            if let Some(cache) = &self.cache {
+                cache.borrow_mut().insert(key, value.clone());
             }
+            Ok(Some(value))
         } else {
             Ok(None)
         }
@@ -340,38 +866,157 @@ impl KvsEngine for KvStore {
     /// Checking to see first that the key exists
     /// then removes the key from the in-memory index.
     fn remove(&mut self, key: String) -> Result<()> {
+        self.check_writable()?;
         // Check using in memory map
         if self.map.contains_key(&key) {
-            let mut file = self
-                .disk
-                .as_ref()
-                .ok_or(DbError::Uninitialized)?
-                .borrow_mut();
-            let rm_cmd = Action::Remove(cli::RmCmd { key: key.clone() });
-            // serialize the rm_cmd
-            let serialized = ron::ser::to_string_pretty(&rm_cmd, RON_CONFIG.to_owned())? + "\n";
-            // let serialized = ron::ser::to_string(&rm_cmd)? + "\n";
-            // write serialized to self.disk
-            // TODO : Maybe think about optimizing this? file sys-call on every set cmd?
-            file.write_all(serialized.as_bytes())?;
-            self.map.remove(&key);
-            Ok(())
+            self.seq += 1;
+            let pointer = self.append(&Record::Remove(RmRecord {
+                key: key.clone(),
+                stamp: Stamp::now(self.seq),
+            }))?;
+            self.blobs.track(&key, None);
+            self.invalidate(&key);
+            if let Some(old) = self.map.remove(&key) {
+                self.stale += old.len + 1;
+            }
+            self.stale += pointer.len + 1;
+            self.maintain()
         } else {
             warn!("No such key: {:?}", key);
             Err(DbError::KeyNotFound)
         }
     }
+    /// Scan : Walk the in-memory index, reading each matching key's value from the log on demand
+    fn scan<'a>(&'a self, prefix: &'a str) -> Result<KvPairs<'a>> {
+        let live = self
+            .map
+            .iter()
+            .filter(move |(key, _)| key.starts_with(prefix))
+            .map(|(key, &pointer)| Ok((key.clone(), self.read_value(pointer)?)));
+        Ok(Box::new(live))
+    }
+    /// Backup : Freeze the active segment so that every command written so far lives in an
+    /// immutable segment, then copy those segments and their hints over. Writes after the freeze
+    /// go to the new active segment and are not part of the backup.
+    /// Blob files are frozen and copied the same way.
+    fn backup_to(&mut self, dir: &Path, since: Option<&Path>) -> Result<BackupManifest> {
+        self.check_writable()?;
+        let base = since.map(backup::load_base).transpose()?;
+        backup::prepare_dir(dir)?;
+        if self.offset > 0 {
+            self.roll()?;
+        }
+        self.blobs.roll();
+        let mut manifest = BackupManifest::new(backup::KVS_ENGINE);
+        manifest.encrypted = self.keyring.enabled();
+        let base_manifest = base.as_ref().map(|(_, manifest)| manifest);
+        for id in segment_ids(&self.dir)?
+            .into_iter()
+            .filter(|&id| id < self.active)
+        {
+            manifest.segments.push(backup::backup_file(
+                &self.dir,
+                dir,
+                &segment_name(id),
+                base_manifest,
+            )?);
+            if hint_path(&self.dir, id).exists() {
+                manifest.hints.push(backup::backup_file(
+                    &self.dir,
+                    dir,
+                    &hint_name(id),
+                    base_manifest,
+                )?);
+            }
+        }
+        for id in blob::blob_ids(&self.dir)? {
+            manifest.blobs.push(backup::backup_file(
+                &self.dir,
+                dir,
+                &blob_name(id),
+                base_manifest,
+            )?);
+        }
+        let segments = manifest
+            .segments
+            .iter()
+            .map(|file| file.name.clone())
+            .collect();
+        manifest.manifest = Some(backup::backup_manifest(&self.manifest, dir, segments)?);
+        manifest.parent = base
+            .map(|(base_dir, _)| backup::relative_path(dir, &base_dir))
+            .transpose()?;
+        manifest.save(dir)?;
+        info!(
+            "Backed up {} segments into {dir:?}, {} of them copied",
+            manifest.segments.len(),
+            manifest
+                .segments
+                .iter()
+                .filter(|file| !file.inherited)
+                .count()
+        );
+        Ok(manifest)
+    }
+    /// Stats : Live and stale bytes come from the in-memory index, blob files included
+    fn stats(&self) -> Result<EngineStats> {
+        let (mut live_bytes, mut stale_bytes) = (0, self.stale);
+        live_bytes += self
+            .map
+            .values()
+            .map(|pointer| pointer.len + 1)
+            .sum::<u64>();
+        for (_, size, garbage) in self.blobs.files() {
+            live_bytes += size - garbage;
+            stale_bytes += garbage;
+        }
+        Ok(EngineStats {
+            engine: backup::KVS_ENGINE.to_owned(),
+            keys: self.map.len() as u64,
+            live_bytes,
+            stale_bytes,
+            segments: segment_ids(&self.dir)?.len() as u64,
+            compactions: self.compactions.count,
+            last_compaction: self.compactions.last,
+            cache: self.cache_stats(),
+            compression: Some(self.compression),
+            ..Default::default()
+        })
+    }
+
+    /// Sync : The active segment is synced and the index persisted, so the next open skips the replay.
+    /// Read-only stores have nothing to sync
+    fn sync(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        if let Some(writer) = &self.writer {
+            writer.sync_all()?;
+        }
+        self.persist_index()
+    }
 }
 /// Sled backend for KVS
 pub struct SledKvsEngine {
     db: sled::Db,
+    manifest: Manifest,
 }
 
 impl SledKvsEngine {
     /// Start a Sled Kvs Engine
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let db = sled::open(path.into())?;
-        Ok(SledKvsEngine { db })
+        let dir: PathBuf = path.into();
+        let manifest = Manifest::check(&dir, SLED_ENGINE)?;
+        let db = sled::open(&dir)?;
+        let manifest = match manifest {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest::new(SLED_ENGINE, BTreeMap::new());
+                manifest.save(&dir)?;
+                manifest
+            }
+        };
+        Ok(SledKvsEngine { db, manifest })
     }
 }
 impl KvsEngine for SledKvsEngine {
@@ -383,8 +1028,7 @@ impl KvsEngine for SledKvsEngine {
     fn get(&self, key: String) -> Result<Option<String>> {
         if let Some(result) = self.db.get(key.as_bytes())? {
             return Ok(Some(
-                String::from_utf8(result.to_vec())
-                    .map_err(|utf8_err| DbError::SledUtf8Error(utf8_err))?,
+                String::from_utf8(result.to_vec()).map_err(DbError::SledUtf8Error)?,
             ));
         }
         Ok(None)
@@ -400,4 +1044,49 @@ impl KvsEngine for SledKvsEngine {
             Err(sled_err) => Err(DbError::SledError(sled_err)),
         }
     }
+
+    fn scan<'a>(&'a self, prefix: &'a str) -> Result<KvPairs<'a>> {
+        let pairs = self.db.scan_prefix(prefix.as_bytes()).map(|entry| {
+            let (key, value) = entry?;
+            Ok((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ))
+        });
+        Ok(Box::new(pairs))
+    }
+
+    fn backup_to(&mut self, dir: &Path, since: Option<&Path>) -> Result<BackupManifest> {
+        if since.is_some() {
+            return Err(DbError::Backup(
+                "Incremental backups are only supported by the kvs engine".to_owned(),
+            ));
+        }
+        backup::prepare_dir(dir)?;
+        self.db.flush()?;
+        let backup = sled::open(dir)?;
+        backup.import(self.db.export());
+        backup.flush()?;
+        let mut manifest = BackupManifest::new(backup::SLED_ENGINE);
+        manifest.checksum = Some(backup.checksum()?);
+        manifest.manifest = Some(backup::backup_manifest(&self.manifest, dir, vec![])?);
+        manifest.save(dir)?;
+        info!("Backed up sled database into {dir:?}");
+        Ok(manifest)
+    }
+
+    /// Stats : sled doesn't tell stale bytes apart, everything on disk is counted as live
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: backup::SLED_ENGINE.to_owned(),
+            keys: self.db.len() as u64,
+            live_bytes: self.db.size_on_disk()?,
+            ..Default::default()
+        })
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
 }
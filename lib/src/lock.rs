@@ -0,0 +1,46 @@
+//! Advisory lock keeping two processes from writing to the same store.
+//!
+//! A store opened for writing holds an exclusive `flock` on the `LOCK` file in its directory for as long as
+//! it's open, and writes its PID there so whoever is turned away knows who to look for. The lock goes away
+//! with the process, so a crash never leaves a store locked. Read-only stores don't take the lock.
+
+use crate::{DbError, Result};
+#[allow(unused_imports)]
+use log::{debug, warn};
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Name of the lock file inside a store's directory
+pub(crate) const LOCK_FILE: &str = "LOCK";
+
+/// Take the writer lock on the store in `dir`, to be held as long as the returned file is
+pub(crate) fn acquire(dir: &Path) -> Result<File> {
+    let path = dir.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            return Err(DbError::Locked {
+                dir: dir.to_path_buf(),
+                pid: holder.trim().parse().ok(),
+            });
+        }
+        Err(TryLockError::Error(err)) => return Err(err.into()),
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", std::process::id())?;
+    file.sync_data()?;
+    debug!("Locked {path:?}");
+    Ok(file)
+}
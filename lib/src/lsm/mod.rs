@@ -0,0 +1,627 @@
+//! LSM-tree engine, for datasets that don't fit in memory.
+//!
+//! - *memtable* - Sorted in-memory map receiving every write. Removes are recorded as *tombstones*.
+//!
+//! - *WAL* - `lsm.wal`, the write-ahead log. Every write is appended to it before reaching the memtable,
+//!   so the memtable can be rebuilt after a restart. It is emptied whenever the memtable is flushed.
+//!
+//! - *SSTable* - `lsm_<id>.sst`, an immutable file of sorted entries with a block index.
+//!   A full memtable is *flushed* into a new table.
+//!
+//! - *levels* - Tables are organized in levels, listed in `lsm.levels`. Flushed tables land in level 0,
+//!   where they may overlap each other. Tables of deeper levels never overlap within their level,
+//!   and every level may hold `level_ratio` times more bytes than the one above it.
+//!
+//! - *leveled compaction* - Once level 0 has too many tables, they are merged with the overlapping tables
+//!   of level 1. Once a deeper level outgrows its budget, one of its tables is merged into the next level.
+//!   Merging keeps only the newest version of each key, and drops tombstones once nothing older can hide below them.
+//!
+//! Reads check the memtable, then level 0 from newest to oldest, then at most one table per deeper level.
+
+mod sstable;
+
+use self::sstable::{table_name, table_path, Entry, SsTable, TableWriter};
+use crate::backup::{self, BackupManifest, LSM_ENGINE};
+use crate::cli::{Action, RmCmd, SetCmd};
+use crate::stats::Compactions;
+use crate::{DbError, EngineStats, KvPairs, KvsEngine, Manifest, Result, RON_CONFIG};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    iter::Peekable,
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+
+/// Write-ahead log of the memtable
+pub(crate) const WAL_FILE: &str = "lsm.wal";
+/// Tables making up each level
+pub(crate) const LEVELS_FILE: &str = "lsm.levels";
+
+/// Settings an [`LsmStore`] is opened with
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Bytes of keys and values the memtable holds before it's flushed
+    pub memtable_size: u64,
+    /// Target size of an SSTable block, the unit reads are done in
+    pub block_size: u64,
+    /// Size at which compaction starts a new SSTable
+    pub table_size: u64,
+    /// Number of level 0 tables that triggers a compaction into level 1
+    pub level0_tables: usize,
+    /// Bytes level 1 may hold before its tables get pushed down
+    pub level1_size: u64,
+    /// Growth factor of the byte budget from one level to the next
+    pub level_ratio: u64,
+}
+
+impl LsmOptions {
+    /// Options a store was created with, as its [`Manifest`] records them
+    fn manifest_options(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("memtable_size".to_owned(), self.memtable_size.to_string()),
+            ("block_size".to_owned(), self.block_size.to_string()),
+            ("table_size".to_owned(), self.table_size.to_string()),
+            ("level0_tables".to_owned(), self.level0_tables.to_string()),
+            ("level1_size".to_owned(), self.level1_size.to_string()),
+            ("level_ratio".to_owned(), self.level_ratio.to_string()),
+        ])
+    }
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 << 20,
+            block_size: 4 << 10,
+            table_size: 2 << 20,
+            level0_tables: 4,
+            level1_size: 10 << 20,
+            level_ratio: 10,
+        }
+    }
+}
+
+/// Contents of `lsm.levels`
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Levels {
+    next_id: u64,
+    /// Table ids per level. Level 0 is newest first, deeper levels are in key order
+    levels: Vec<Vec<u64>>,
+}
+
+/// LSM-tree implementation of [`KvsEngine`], see the [module docs](self)
+#[derive(Debug)]
+pub struct LsmStore {
+    dir: PathBuf,
+    options: LsmOptions,
+    memtable: BTreeMap<String, Option<String>>,
+    /// Bytes of keys and values in the memtable
+    memtable_size: u64,
+    wal: File,
+    levels: Vec<Vec<SsTable>>,
+    next_id: u64,
+    compactions: Compactions,
+    manifest: Manifest,
+}
+
+impl LsmStore {
+    /// Open the LSM store in directory `path` with default options
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmStore> {
+        LsmStore::open_with(path, LsmOptions::default())
+    }
+
+    /// Open the LSM store in directory `path`, replaying the WAL into the memtable
+    pub fn open_with(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmStore> {
+        let dir: PathBuf = path.into();
+        if !dir.is_dir() {
+            return Err(DbError::DatabaseNotFound(dir));
+        }
+        let manifest = Manifest::check(&dir, LSM_ENGINE)?;
+        let layout = match fs::read_to_string(dir.join(LEVELS_FILE)) {
+            Ok(contents) => ron::from_str(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Levels::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let levels = layout
+            .levels
+            .iter()
+            .map(|ids| ids.iter().map(|&id| SsTable::open(&dir, id)).collect())
+            .collect::<Result<Vec<_>>>()?;
+        // Tables a flush or compaction was writing when the process died
+        let live: HashSet<u64> = layout.levels.iter().flatten().copied().collect();
+        for id in table_ids(&dir)?.into_iter().filter(|id| !live.contains(id)) {
+            warn!("Removing unreferenced table {}", table_name(id));
+            fs::remove_file(table_path(&dir, id))?;
+        }
+        let wal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(WAL_FILE))?;
+        let mut store = LsmStore {
+            dir,
+            options,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            wal,
+            levels,
+            next_id: layout.next_id.max(1),
+            compactions: Compactions::default(),
+            manifest: Manifest::default(),
+        };
+        store.manifest = match manifest {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest::new(LSM_ENGINE, store.options.manifest_options());
+                manifest.save(&store.dir)?;
+                manifest
+            }
+        };
+        store.track_tables()?;
+        store.replay_wal()?;
+        debug!(
+            "LsmStore initialized with tables per level {:?}, {} keys in the memtable",
+            store.tables_per_level(),
+            store.memtable.len()
+        );
+        Ok(store)
+    }
+
+    /// Number of SSTables in each level, starting at level 0
+    pub fn tables_per_level(&self) -> Vec<usize> {
+        self.levels.iter().map(Vec::len).collect()
+    }
+
+    /// Write the memtable out as a level 0 table and empty the WAL, compacting if needed
+    pub fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let entries = self
+            .memtable
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let tables = write_tables(&self.dir, &self.options, &mut self.next_id, entries, false)?;
+        if self.levels.is_empty() {
+            self.levels.push(vec![]);
+        }
+        self.levels[0].splice(0..0, tables);
+        self.save_levels()?;
+        // Everything the WAL holds now lives in a table
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        self.memtable.clear();
+        self.memtable_size = 0;
+        self.compact()
+    }
+
+    /// Rebuild the memtable from the WAL. A last record cut short by a crash was never acknowledged,
+    /// so it's dropped; anything unreadable before it is corruption
+    fn replay_wal(&mut self) -> Result<()> {
+        let mut reader = BufReader::new(File::open(self.dir.join(WAL_FILE))?);
+        let mut offset = 0;
+        let mut line = vec![];
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)? as u64;
+            if read == 0 {
+                break;
+            }
+            let parsed = match line.last() {
+                Some(b'\n') => ron::de::from_bytes(&line).map_err(|err| err.to_string()),
+                _ => Err("record isn't followed by a newline".to_owned()),
+            };
+            let action = match parsed {
+                Ok(action) => action,
+                Err(reason) if reader.fill_buf()?.is_empty() => {
+                    warn!("Dropping the torn record ending {WAL_FILE} at byte {offset}: {reason}");
+                    self.wal.set_len(offset)?;
+                    self.wal.sync_all()?;
+                    break;
+                }
+                Err(reason) => {
+                    return Err(DbError::Corrupted(format!(
+                        "{WAL_FILE} at byte {offset}: {reason}"
+                    )))
+                }
+            };
+            offset += read;
+            match action {
+                Action::Set(SetCmd { key, value }) => self.insert(key, Some(value)),
+                Action::Remove(RmCmd { key }) => self.insert(key, None),
+                Action::Get(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Append `action` to the WAL
+    fn log(&mut self, action: &Action) -> Result<()> {
+        let serialized = ron::ser::to_string_pretty(action, RON_CONFIG.to_owned())?;
+        self.wal.write_all((serialized + "\n").as_bytes())?;
+        Ok(())
+    }
+
+    fn insert(&mut self, key: String, value: Option<String>) {
+        self.memtable_size += footprint(&key, &value);
+        if let Some(old) = self.memtable.insert(key.clone(), value) {
+            self.memtable_size -= footprint(&key, &old);
+        }
+    }
+
+    fn maybe_flush(&mut self) -> Result<()> {
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record the tables of every level in the manifest
+    fn track_tables(&mut self) -> Result<()> {
+        let tables = self
+            .levels
+            .iter()
+            .flatten()
+            .map(|table| table_name(table.id));
+        self.manifest.update(&self.dir, tables.collect())
+    }
+
+    fn save_levels(&mut self) -> Result<()> {
+        let levels = Levels {
+            next_id: self.next_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        let tmp_path = self.dir.join(LEVELS_FILE).with_extension("levels.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(ron::to_string(&levels)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.dir.join(LEVELS_FILE))?;
+        self.track_tables()
+    }
+
+    /// Compact levels until each is within its budget
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            let level0_full = self
+                .levels
+                .first()
+                .is_some_and(|tables| tables.len() >= self.options.level0_tables);
+            let level = if level0_full {
+                0
+            } else if let Some(level) = (1..self.levels.len())
+                .find(|&level| self.level_bytes(level) > self.max_level_bytes(level))
+            {
+                level
+            } else {
+                return Ok(());
+            };
+            self.compact_level(level)?;
+        }
+    }
+
+    fn level_bytes(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|table| table.size).sum()
+    }
+
+    fn max_level_bytes(&self, level: usize) -> u64 {
+        let growth = self.options.level_ratio.saturating_pow(level as u32 - 1);
+        self.options.level1_size.saturating_mul(growth)
+    }
+
+    /// Merge tables of `level` with the overlapping tables of the level below
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        if self.levels.len() == level + 1 {
+            self.levels.push(vec![]);
+        }
+        // Level 0 tables overlap each other so they all go down at once, deeper levels hand down one table at a time
+        let upper: Vec<u64> = match level {
+            0 => self.levels[0].iter().map(|table| table.id).collect(),
+            _ => vec![self.levels[level][0].id],
+        };
+        let upper_tables = || {
+            self.levels[level]
+                .iter()
+                .filter(|table| upper.contains(&table.id))
+        };
+        let first = upper_tables().map(|table| &table.first_key).min().cloned();
+        let last = upper_tables().map(|table| &table.last_key).max().cloned();
+        let (first, last) = (first.unwrap_or_default(), last.unwrap_or_default());
+        let lower: Vec<u64> = self.levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(&first, &last))
+            .map(|table| table.id)
+            .collect();
+        // Tombstones only need to outlive older values in deeper levels
+        let bottom = self.levels[level + 2..].iter().all(Vec::is_empty);
+
+        let mut sources: Vec<Source> = upper_tables()
+            .map(|table| Box::new(table.iter_from("")) as Source)
+            .collect();
+        sources.push(Box::new(
+            self.levels[level + 1]
+                .iter()
+                .filter(|table| lower.contains(&table.id))
+                .flat_map(|table| table.iter_from("")),
+        ));
+        let outputs = write_tables(
+            &self.dir,
+            &self.options,
+            &mut self.next_id,
+            MergeIter::new(sources),
+            bottom,
+        )?;
+
+        let (obsolete, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.levels[level])
+            .into_iter()
+            .partition(|table| upper.contains(&table.id));
+        self.levels[level] = kept;
+        let (mut obsolete_lower, mut kept): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.levels[level + 1])
+                .into_iter()
+                .partition(|table| lower.contains(&table.id));
+        kept.extend(outputs);
+        kept.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        self.levels[level + 1] = kept;
+        self.save_levels()?;
+        obsolete_lower.extend(obsolete);
+        for table in obsolete_lower {
+            table.delete(&self.dir)?;
+        }
+        self.compactions.record();
+        debug!(
+            "Compacted {} tables of level {level} and {} of level {}, tables per level now {:?}",
+            upper.len(),
+            lower.len(),
+            level + 1,
+            self.tables_per_level()
+        );
+        Ok(())
+    }
+}
+
+impl KvsEngine for LsmStore {
+    /// Set : Log the write to the WAL, then apply it to the memtable, flushing it once full
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.log(&Action::Set(SetCmd {
+            key: key.clone(),
+            value: value.clone(),
+        }))?;
+        self.insert(key, Some(value));
+        self.maybe_flush()
+    }
+    /// Get : The newest version of the key wins, the memtable being newer than every table
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+        for table in self.levels.first().into_iter().flatten() {
+            if let Some(value) = table.get(&key)? {
+                return Ok(value);
+            }
+        }
+        for level in self.levels.iter().skip(1) {
+            let candidate = level.partition_point(|table| table.last_key < key);
+            if let Some(value) = level
+                .get(candidate)
+                .map(|table| table.get(&key))
+                .transpose()?
+                .flatten()
+            {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+    /// Remove : Write a tombstone, which hides older values until compaction drops them
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            warn!("No such key: {:?}", key);
+            return Err(DbError::KeyNotFound);
+        }
+        self.log(&Action::Remove(RmCmd { key: key.clone() }))?;
+        self.insert(key, None);
+        self.maybe_flush()
+    }
+    /// Scan : Merge the memtable and every level, in key order
+    fn scan<'a>(&'a self, prefix: &'a str) -> Result<KvPairs<'a>> {
+        let mut sources: Vec<Source> = vec![Box::new(
+            self.memtable
+                .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        )];
+        for table in self.levels.first().into_iter().flatten() {
+            sources.push(Box::new(table.iter_from(prefix)));
+        }
+        for level in self.levels.iter().skip(1) {
+            sources.push(Box::new(
+                level
+                    .iter()
+                    .filter(move |table| table.last_key.as_str() >= prefix)
+                    .flat_map(move |table| table.iter_from(prefix)),
+            ));
+        }
+        let live = MergeIter::new(sources)
+            .take_while(move |entry| match entry {
+                Ok((key, _)) => key.starts_with(prefix),
+                Err(_) => true,
+            })
+            .filter_map(|entry| match entry {
+                Ok((key, Some(value))) => Some(Ok((key, value))),
+                Ok((_, None)) => None,
+                Err(err) => Some(Err(err)),
+            });
+        Ok(Box::new(live))
+    }
+    /// Backup : Flush the memtable, then copy every table along with the level layout
+    fn backup_to(&mut self, dir: &Path, since: Option<&Path>) -> Result<BackupManifest> {
+        if since.is_some() {
+            return Err(DbError::Backup(
+                "Incremental backups are only supported by the kvs engine".to_owned(),
+            ));
+        }
+        backup::prepare_dir(dir)?;
+        self.flush()?;
+        self.save_levels()?;
+        let mut manifest = BackupManifest::new(backup::LSM_ENGINE);
+        for table in self.levels.iter().flatten() {
+            manifest.segments.push(backup::backup_file(
+                &self.dir,
+                dir,
+                &table_name(table.id),
+                None,
+            )?);
+        }
+        manifest
+            .segments
+            .push(backup::backup_file(&self.dir, dir, LEVELS_FILE, None)?);
+        manifest.manifest = Some(backup::backup_manifest(
+            &self.manifest,
+            dir,
+            self.manifest.segments.clone(),
+        )?);
+        manifest.save(dir)?;
+        info!(
+            "Backed up {} tables into {dir:?}",
+            manifest.segments.len() - 1
+        );
+        Ok(manifest)
+    }
+    /// Stats : Takes a full scan. Tables and the WAL don't keep track of what's shadowed,
+    /// so live bytes are the keys and values the scan returns, and the rest of the files is counted as stale
+    fn stats(&self) -> Result<EngineStats> {
+        let (mut keys, mut live_bytes) = (0, 0);
+        for pair in self.scan("")? {
+            let (key, value) = pair?;
+            keys += 1;
+            live_bytes += (key.len() + value.len()) as u64;
+        }
+        let on_disk = self
+            .levels
+            .iter()
+            .flatten()
+            .map(|table| table.size)
+            .sum::<u64>()
+            + self.wal.metadata()?.len();
+        Ok(EngineStats {
+            engine: backup::LSM_ENGINE.to_owned(),
+            keys,
+            live_bytes,
+            stale_bytes: on_disk.saturating_sub(live_bytes),
+            segments: self.levels.iter().map(Vec::len).sum::<usize>() as u64,
+            compactions: self.compactions.count,
+            last_compaction: self.compactions.last,
+            ..Default::default()
+        })
+    }
+
+    /// Sync : The memtable is flushed into a table, leaving the WAL empty
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.wal.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Stream of entries in key order, each key at most once
+type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// Merges sources ordered from newest to oldest, keeping the newest entry of every key
+struct MergeIter<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Self {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut smallest: Option<(usize, String)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                None => {}
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _)))
+                    if smallest.as_ref().is_none_or(|(_, smallest)| key < smallest) =>
+                {
+                    smallest = Some((i, key.clone()));
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+        let (newest, key) = smallest?;
+        let entry = self.sources[newest].next();
+        // Older sources holding the same key are shadowed
+        for source in &mut self.sources[newest + 1..] {
+            if matches!(source.peek(), Some(Ok((other, _))) if *other == key) {
+                source.next();
+            }
+        }
+        entry
+    }
+}
+
+/// Write `entries` out as tables of about `table_size` bytes each
+fn write_tables(
+    dir: &Path,
+    options: &LsmOptions,
+    next_id: &mut u64,
+    entries: impl Iterator<Item = Result<Entry>>,
+    drop_tombstones: bool,
+) -> Result<Vec<SsTable>> {
+    let mut tables = vec![];
+    let mut writer: Option<TableWriter> = None;
+    for entry in entries {
+        let (key, value) = entry?;
+        if value.is_none() && drop_tombstones {
+            continue;
+        }
+        if writer.is_none() {
+            writer = Some(TableWriter::create(dir, *next_id, options.block_size)?);
+            *next_id += 1;
+        }
+        let current = writer.as_mut().expect("writer was just created");
+        current.add(&key, value.as_deref())?;
+        if current.size() >= options.table_size {
+            tables.extend(writer.take().map(TableWriter::finish).transpose()?);
+        }
+    }
+    tables.extend(writer.map(TableWriter::finish).transpose()?);
+    Ok(tables)
+}
+
+fn footprint(key: &str, value: &Option<String>) -> u64 {
+    (key.len() + value.as_ref().map_or(0, String::len)) as u64
+}
+
+/// Ids of all tables found in `dir`
+fn table_ids(dir: &Path) -> Result<Vec<u64>> {
+    Ok(fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("lsm_")?
+                .strip_suffix(".sst")?
+                .parse()
+                .ok()
+        })
+        .collect())
+}
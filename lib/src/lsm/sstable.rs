@@ -0,0 +1,356 @@
+//! Sorted string tables.
+//!
+//! An SSTable is an immutable file of entries sorted by key, written once by a flush or a compaction:
+//!
+//! ```text
+//! [block 0] [block 1] .. [block n] [index] [footer]
+//! ```
+//!
+//! - Each *block* holds consecutive entries, `key_len: u32, key, tag: u8, (value_len: u32, value)?`
+//!   where a tag of 1 marks a tombstone without a value, followed by the CRC32 of those entries.
+//! - The *index* has one `key_len: u32, last_key, offset: u64, len: u64` record per block, so a lookup
+//!   only ever reads the one block that may hold its key.
+//! - The *footer* is `index_offset: u64, index_len: u64, magic: u64`.
+//!
+//! Integers are little endian.
+
+use crate::{DbError, Result};
+#[allow(unused_imports)]
+use log::{debug, trace, warn};
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// A key and its value, `None` being a tombstone left by a remove
+pub(crate) type Entry = (String, Option<String>);
+
+/// Marks the end of a complete table, "kvs_sst1"
+const MAGIC: u64 = 0x6b76_735f_7373_7431;
+const FOOTER_LEN: u64 = 24;
+const VALUE: u8 = 0;
+const TOMBSTONE: u8 = 1;
+
+/// File name of table `id`
+pub(crate) fn table_name(id: u64) -> String {
+    format!("lsm_{id:05}.sst")
+}
+
+/// Path of table `id` inside `dir`
+pub(crate) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(table_name(id))
+}
+
+/// Location of a block and the greatest key it holds
+#[derive(Debug)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// Writes the entries handed to it, in key order, as table `id`
+pub(crate) struct TableWriter {
+    id: u64,
+    path: PathBuf,
+    out: BufWriter<File>,
+    block_size: u64,
+    block: Vec<u8>,
+    last_key: Option<String>,
+    index: Vec<BlockHandle>,
+    offset: u64,
+}
+
+impl TableWriter {
+    pub(crate) fn create(dir: &Path, id: u64, block_size: u64) -> Result<Self> {
+        let path = table_path(dir, id);
+        Ok(TableWriter {
+            id,
+            out: BufWriter::new(File::create(&path)?),
+            path,
+            block_size,
+            block: vec![],
+            last_key: None,
+            index: vec![],
+            offset: 0,
+        })
+    }
+
+    /// Append an entry, keys must come in strictly increasing order
+    pub(crate) fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        debug_assert!(self.last_key.as_deref() < Some(key));
+        put_str(&mut self.block, key);
+        match value {
+            Some(value) => {
+                self.block.push(VALUE);
+                put_str(&mut self.block, value);
+            }
+            None => self.block.push(TOMBSTONE),
+        }
+        self.last_key = Some(key.to_owned());
+        if self.block.len() as u64 >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far
+    pub(crate) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let checksum = crc32fast::hash(&self.block);
+        self.block.extend_from_slice(&checksum.to_le_bytes());
+        self.out.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone().unwrap_or_default(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write the index and footer, make the table durable and open it for reading
+    pub(crate) fn finish(mut self) -> Result<SsTable> {
+        self.finish_block()?;
+        let mut index = vec![];
+        for handle in &self.index {
+            put_str(&mut index, &handle.last_key);
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.len.to_le_bytes());
+        }
+        self.out.write_all(&index)?;
+        self.out.write_all(&self.offset.to_le_bytes())?;
+        self.out.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.out.write_all(&MAGIC.to_le_bytes())?;
+        self.out
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        trace!(
+            "Wrote table {:?} with {} blocks",
+            self.path,
+            self.index.len()
+        );
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        SsTable::open(dir, self.id)
+    }
+}
+
+/// An open, immutable, table
+#[derive(Debug)]
+pub(crate) struct SsTable {
+    pub(crate) id: u64,
+    /// Size of the file in bytes
+    pub(crate) size: u64,
+    /// Smallest key in the table
+    pub(crate) first_key: String,
+    /// Greatest key in the table
+    pub(crate) last_key: String,
+    index: Vec<BlockHandle>,
+    file: RefCell<File>,
+}
+
+impl SsTable {
+    pub(crate) fn open(dir: &Path, id: u64) -> Result<SsTable> {
+        let path = table_path(dir, id);
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let corrupted = |what: &str| DbError::Corrupted(format!("{path:?}: {what}"));
+        if size < FOOTER_LEN {
+            return Err(corrupted("too short to be a table"));
+        }
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        let mut cursor = footer.as_slice();
+        let index_offset = get_u64(&mut cursor)?;
+        let index_len = get_u64(&mut cursor)?;
+        let end = index_offset
+            .checked_add(index_len)
+            .and_then(|end| end.checked_add(FOOTER_LEN));
+        if get_u64(&mut cursor)? != MAGIC || end != Some(size) {
+            return Err(corrupted("bad footer"));
+        }
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut buf = vec![0; index_len as usize];
+        file.read_exact(&mut buf)?;
+        let mut cursor = buf.as_slice();
+        let mut index = vec![];
+        while !cursor.is_empty() {
+            index.push(BlockHandle {
+                last_key: get_str(&mut cursor)?,
+                offset: get_u64(&mut cursor)?,
+                len: get_u64(&mut cursor)?,
+            });
+        }
+        let Some(last) = index.last() else {
+            return Err(corrupted("no blocks"));
+        };
+        let last_key = last.last_key.clone();
+        let mut table = SsTable {
+            id,
+            size,
+            first_key: String::new(),
+            last_key,
+            index,
+            file: RefCell::new(file),
+        };
+        table.first_key = table
+            .read_block(0)?
+            .into_iter()
+            .next()
+            .map(|(key, _)| key)
+            .ok_or_else(|| corrupted("empty first block"))?;
+        Ok(table)
+    }
+
+    /// Whether the table may hold keys in `first..=last`
+    pub(crate) fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key.as_str() <= last && first <= self.last_key.as_str()
+    }
+
+    /// Look `key` up. `Some(None)` means the table holds a tombstone for it
+    pub(crate) fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key.as_str() || key > self.last_key.as_str() {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(candidate, _)| candidate == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Entries with a key greater than or equal to `from`, in order
+    pub(crate) fn iter_from<'a>(&'a self, from: &str) -> TableIter<'a> {
+        let next_block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < from);
+        TableIter {
+            table: self,
+            next_block,
+            entries: vec![].into_iter(),
+            from: from.to_owned(),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.index[block];
+        let mut buf = vec![0; handle.len as usize];
+        {
+            let mut file = self.file.borrow_mut();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        let (entries, checksum) = buf.split_at(buf.len().saturating_sub(4));
+        if checksum.len() != 4 || crc32fast::hash(entries).to_le_bytes() != checksum {
+            return Err(DbError::Corrupted(format!(
+                "block {block} of table {} fails its checksum",
+                self.id
+            )));
+        }
+        let mut cursor = entries;
+        let mut decoded = vec![];
+        while !cursor.is_empty() {
+            let key = get_str(&mut cursor)?;
+            let value = match get_u8(&mut cursor)? {
+                VALUE => Some(get_str(&mut cursor)?),
+                TOMBSTONE => None,
+                tag => {
+                    return Err(DbError::Corrupted(format!(
+                        "unknown tag {tag} in table {}",
+                        self.id
+                    )))
+                }
+            };
+            decoded.push((key, value));
+        }
+        Ok(decoded)
+    }
+
+    /// Delete the table's file
+    pub(crate) fn delete(self, dir: &Path) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(table_path(dir, self.id))?;
+        Ok(())
+    }
+}
+
+/// In-order iterator over a table, reading one block at a time
+pub(crate) struct TableIter<'a> {
+    table: &'a SsTable,
+    next_block: usize,
+    entries: std::vec::IntoIter<Entry>,
+    from: String,
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                if entry.0 >= self.from {
+                    return Some(Ok(entry));
+                }
+                continue;
+            }
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(err) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(err));
+                }
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn take<'a>(cursor: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if cursor.len() < len {
+        return Err(DbError::Corrupted("truncated table record".to_owned()));
+    }
+    let (head, tail) = cursor.split_at(len);
+    *cursor = tail;
+    Ok(head)
+}
+
+fn get_u8(cursor: &mut &[u8]) -> Result<u8> {
+    Ok(take(cursor, 1)?[0])
+}
+
+fn get_u32(cursor: &mut &[u8]) -> Result<u32> {
+    let bytes = take(cursor, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().expect("took 4 bytes")))
+}
+
+fn get_u64(cursor: &mut &[u8]) -> Result<u64> {
+    let bytes = take(cursor, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().expect("took 8 bytes")))
+}
+
+fn get_str(cursor: &mut &[u8]) -> Result<String> {
+    let len = get_u32(cursor)? as usize;
+    Ok(String::from_utf8(take(cursor, len)?.to_vec())?)
+}
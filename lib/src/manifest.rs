@@ -0,0 +1,122 @@
+//! The `MANIFEST` every engine keeps in its directory.
+//!
+//! It records which engine the directory belongs to, the version of that engine's on-disk format,
+//! the files holding the data and the options the store was created with. Engines check it before reading
+//! anything else, so a directory written by another engine, or in a format this build doesn't know,
+//! is turned away with a clear error rather than misread. It's also what `kvs-server` looks at to tell
+//! which engine an existing directory was written by.
+//!
+//! Stores created before manifests existed get one the first time they're opened for writing.
+
+use crate::backup::{KVS_ENGINE, LSM_ENGINE, SLED_ENGINE};
+use crate::{DbError, Result};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Name of the manifest file inside a store directory
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Version of the on-disk format this build reads and writes for `engine`, `None` for unknown engines
+pub fn format_version(engine: &str) -> Option<u32> {
+    match engine {
+        KVS_ENGINE | LSM_ENGINE | SLED_ENGINE => Some(1),
+        _ => None,
+    }
+}
+
+/// Contents of `MANIFEST`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// Engine the directory belongs to
+    pub engine: String,
+    /// Version of the engine's on-disk format, see [`format_version`]
+    pub format_version: u32,
+    /// Version of kvs that created the store
+    pub created_by: String,
+    /// Seconds since the UNIX epoch at which the store was created
+    pub created: u64,
+    /// Files holding the data: log segments oldest first for kvs, SSTables level by level for lsm.
+    /// Empty for sled, which keeps track of its own files
+    pub segments: Vec<String>,
+    /// Options the store was created with, by name
+    pub options: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Manifest of a store `engine` is creating now with `options`
+    pub(crate) fn new(engine: &str, options: BTreeMap<String, String>) -> Manifest {
+        Manifest {
+            engine: engine.to_owned(),
+            format_version: format_version(engine).expect("engines have a format version"),
+            created_by: env!("CARGO_PKG_VERSION").to_owned(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            segments: vec![],
+            options,
+        }
+    }
+
+    /// Read the manifest of the store in `dir`, `None` when it has none
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+            Ok(contents) => Ok(Some(ron::from_str(&contents)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Read the manifest of the store in `dir`, making sure `engine` can open the store
+    pub(crate) fn check(dir: &Path, engine: &str) -> Result<Option<Manifest>> {
+        let Some(manifest) = Manifest::load(dir)? else {
+            return Ok(None);
+        };
+        if manifest.engine != engine {
+            return Err(DbError::Incompatible(format!(
+                "{dir:?} holds a {} store, it can't be opened with {engine}",
+                manifest.engine
+            )));
+        }
+        let supported = format_version(engine).expect("engines have a format version");
+        if manifest.format_version != supported {
+            return Err(DbError::Incompatible(format!(
+                "{dir:?} was written in {engine} format version {}, kvs {} only reads version {supported}",
+                manifest.format_version,
+                env!("CARGO_PKG_VERSION")
+            )));
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Write the manifest into `dir`, replacing the previous one in one go
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST_FILE).with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(ron::ser::to_string_pretty(self, Default::default())?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, dir.join(MANIFEST_FILE))?;
+        debug!(
+            "Wrote {MANIFEST_FILE} listing {} files",
+            self.segments.len()
+        );
+        Ok(())
+    }
+
+    /// Record `segments` as the files of the store, saving the manifest if they changed
+    pub(crate) fn update(&mut self, dir: &Path, segments: Vec<String>) -> Result<()> {
+        if self.segments != segments {
+            self.segments = segments;
+            self.save(dir)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Records making up the `KvStore` log.
+//!
+//! Sets and removes are logged much as the [`Action`](crate::cli::Action) the user issued, so `SET` and `RM` lines read
+//! the same as they always have, along with a [`Stamp`] telling when they were written. Records the user can't issue
+//! directly, like a set whose value lives in a blob file, get variants of their own.
+//!
+//! Compressed and encrypted records are framed rather than written as plain text, see [`crate::codec`]
+//! and [`crate::crypto`]:
+//!
+//! | marker `0xF5` | flags: u8 | payload length: u32 | CRC32 of the payload: u32 | payload |
+//!
+//! The low bits of the flags hold the codec id, the high bit is set when the payload is sealed.
+//! Integers are little endian. Like plain records, framed ones are followed by a newline.
+
+use crate::blob::BlobPointer;
+use crate::codec::Codec;
+use crate::crypto::Keyring;
+use crate::hint::HintEntry;
+use crate::{DbError, LogPointer, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// First byte of a framed record, which can't start a RON record, nor any UTF-8 text
+pub(crate) const FRAME_MARKER: u8 = 0xF5;
+/// Length of the header in front of a framed payload
+pub(crate) const FRAME_HEADER_LEN: usize = 10;
+/// Flag set on framed records whose payload is encrypted
+const ENCRYPTED: u8 = 0x80;
+/// Largest framed payload, as big as a request can get. A header announcing more is corrupt rather than torn
+pub(crate) const MAX_RECORD_LEN: u64 = 64 << 20;
+
+/// A single line of the log
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename = "")]
+pub(crate) enum Record {
+    /// Set a key to a value held inline
+    #[serde(rename = "SET")]
+    Set(SetRecord),
+    /// Remove a key
+    #[serde(rename = "RM")]
+    Remove(RmRecord),
+    /// Set a key to a value held in a blob file
+    #[serde(rename = "BLOB")]
+    Blob(BlobRecord),
+}
+
+/// When a record was written. Records logged before stamps existed read as stamped `0`, before any other
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Stamp {
+    /// Position of the write among all writes to the store, from 1
+    pub(crate) seq: u64,
+    /// Milliseconds since the UNIX epoch
+    pub(crate) time: u64,
+}
+
+impl Stamp {
+    /// Stamp for write number `seq`, happening now
+    pub(crate) fn now(seq: u64) -> Stamp {
+        Stamp {
+            seq,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+        }
+    }
+}
+
+/// Set whose value is held in the log
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct SetRecord {
+    pub(crate) key: String,
+    pub(crate) value: String,
+    #[serde(default)]
+    pub(crate) stamp: Stamp,
+}
+
+/// Remove of a key
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct RmRecord {
+    pub(crate) key: String,
+    #[serde(default)]
+    pub(crate) stamp: Stamp,
+}
+
+/// Set whose value was separated from the log
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BlobRecord {
+    pub(crate) key: String,
+    pub(crate) blob: BlobPointer,
+    #[serde(default)]
+    pub(crate) stamp: Stamp,
+}
+
+impl Record {
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            Record::Set(SetRecord { key, .. })
+            | Record::Remove(RmRecord { key, .. })
+            | Record::Blob(BlobRecord { key, .. }) => key,
+        }
+    }
+
+    pub(crate) fn stamp(&self) -> Stamp {
+        match self {
+            Record::Set(SetRecord { stamp, .. })
+            | Record::Remove(RmRecord { stamp, .. })
+            | Record::Blob(BlobRecord { stamp, .. }) => *stamp,
+        }
+    }
+
+    /// Effect of the record, found at `pointer`, on the in-memory index
+    pub(crate) fn into_entry(self, pointer: LogPointer) -> HintEntry {
+        let seq = self.stamp().seq;
+        let (key, removed, blob) = match self {
+            Record::Set(SetRecord { key, .. }) => (key, false, None),
+            Record::Remove(RmRecord { key, .. }) => (key, true, None),
+            Record::Blob(BlobRecord { key, blob, .. }) => (key, false, Some(blob)),
+        };
+        HintEntry {
+            key,
+            pointer,
+            removed,
+            blob,
+            seq,
+        }
+    }
+}
+
+/// Frame `payload`, compressed with `codec`, sealing it when the keyring has a key
+pub(crate) fn frame(codec: Codec, payload: &[u8], keyring: &Keyring) -> Result<Vec<u8>> {
+    let mut flags = codec.id();
+    let sealed;
+    let payload = if keyring.enabled() {
+        flags |= ENCRYPTED;
+        sealed = keyring.seal(payload, &[flags])?;
+        &sealed
+    } else {
+        payload
+    };
+    if payload.len() as u64 > MAX_RECORD_LEN {
+        return Err(DbError::RecordTooLarge {
+            size: payload.len() as u64,
+            limit: MAX_RECORD_LEN,
+        });
+    }
+    let mut framed = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    framed.push(FRAME_MARKER);
+    framed.push(flags);
+    framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    framed.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    framed.extend_from_slice(payload);
+    Ok(framed)
+}
+
+/// Parse a record as stored in the log, plain or framed
+pub(crate) fn decode(raw: &[u8], keyring: &Keyring) -> Result<Record> {
+    if raw.first() != Some(&FRAME_MARKER) {
+        return Ok(ron::de::from_bytes(raw)?);
+    }
+    if raw.len() < FRAME_HEADER_LEN {
+        return Err(DbError::Corrupted("truncated record header".to_owned()));
+    }
+    let flags = raw[1];
+    let codec = Codec::from_id(flags & !ENCRYPTED)?;
+    let len = u32::from_le_bytes([raw[2], raw[3], raw[4], raw[5]]) as usize;
+    let crc = u32::from_le_bytes([raw[6], raw[7], raw[8], raw[9]]);
+    let payload = &raw[FRAME_HEADER_LEN..];
+    if payload.len() != len {
+        return Err(DbError::Corrupted(format!(
+            "record holds {} bytes, its header announces {len}",
+            payload.len()
+        )));
+    }
+    if crc32fast::hash(payload) != crc {
+        return Err(DbError::Corrupted("record checksum mismatch".to_owned()));
+    }
+    let compressed = if flags & ENCRYPTED != 0 {
+        keyring.open(payload, &[flags])?
+    } else {
+        payload.to_vec()
+    };
+    Ok(ron::de::from_bytes(&codec.decompress(&compressed)?)?)
+}
+
+/// Read the next record from `reader`, without its trailing newline.
+/// Returns the record along with the number of bytes consumed, `None` at the end of the log.
+/// A record cut short, like one still being written, fails with [`io::ErrorKind::UnexpectedEof`].
+/// A framed record announcing more than [`MAX_RECORD_LEN`] bytes fails with [`DbError::Corrupted`];
+/// the payload is only allocated as it's read, so a bogus length can't claim more than what's left of the log.
+pub(crate) fn read_next(reader: &mut impl BufRead) -> Result<Option<(Vec<u8>, u64)>> {
+    let framed = match reader.fill_buf()?.first() {
+        None => return Ok(None),
+        Some(&first) => first == FRAME_MARKER,
+    };
+    if framed {
+        let mut raw = vec![0; FRAME_HEADER_LEN];
+        reader.read_exact(&mut raw)?;
+        let len = u32::from_le_bytes([raw[2], raw[3], raw[4], raw[5]]) as u64;
+        if len > MAX_RECORD_LEN {
+            return Err(DbError::Corrupted(format!(
+                "record header announces {len} bytes, more than the limit of {MAX_RECORD_LEN}"
+            )));
+        }
+        if reader.by_ref().take(len).read_to_end(&mut raw)? as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut newline = [0];
+        reader.read_exact(&mut newline)?;
+        let consumed = raw.len() as u64 + 1;
+        Ok(Some((raw, consumed)))
+    } else {
+        let mut raw = vec![];
+        let consumed = reader.read_until(b'\n', &mut raw)? as u64;
+        if raw.pop() != Some(b'\n') {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(Some((raw, consumed)))
+    }
+}
@@ -0,0 +1,271 @@
+//! Point-in-time recovery, as run by `kvs recover`.
+//!
+//! Every record is stamped with the sequence number of the write it logs and the time it was logged, see
+//! [`Stamp`]. Recovery replays the segments of a store, or of a backup, oldest first, keeps what was
+//! written up to the requested point and writes the resulting state into a new store. Records keep their
+//! stamps on the way, so the new store can itself be recovered from later on.
+//!
+//! Only history the log still holds can be replayed: compaction drops everything but the latest write of
+//! each key, so recovering to a point before the last compaction is refused. The point of the last compaction
+//! is kept in the hint of the segment it wrote: a frozen segment whose hint is missing or unusable can't be
+//! checked, so it's replayed anyway and listed in the [`RecoverReport`].
+
+use crate::backup::{self, BackupManifest, KVS_ENGINE, MANIFEST_FILE};
+use crate::hint::Hint;
+use crate::record::{self, BlobRecord, Record, SetRecord, Stamp};
+use crate::{
+    segment_ids, segment_name, segment_path, DbError, EncryptionKey, KvStore, KvStoreOptions,
+    LogPointer, Result,
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Cursor},
+    path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Directory inside the target a backup is restored into before it's replayed
+const STAGING_DIR: &str = ".recover-source";
+
+/// Point up to which writes are recovered, included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    /// Write with this sequence number
+    Seq(u64),
+    /// Last write logged at or before this time
+    Time(SystemTime),
+}
+
+impl Until {
+    /// Whether the write stamped `stamp` happened up to this point.
+    /// Records logged before stamps existed always are
+    fn includes(&self, stamp: Stamp) -> bool {
+        match self {
+            Until::Seq(seq) => stamp.seq <= *seq,
+            Until::Time(time) => stamp.time <= millis(*time),
+        }
+    }
+}
+
+/// Parses a sequence number, an RFC 3339 timestamp such as `2024-05-01T12:00:00Z`,
+/// or a duration such as `15min`, meaning that long ago
+impl FromStr for Until {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(seq) = s.parse() {
+            return Ok(Until::Seq(seq));
+        }
+        if let Ok(time) = humantime::parse_rfc3339_weak(s) {
+            return Ok(Until::Time(time));
+        }
+        match humantime::parse_duration(s) {
+            Ok(ago) => SystemTime::now()
+                .checked_sub(ago)
+                .map(Until::Time)
+                .ok_or_else(|| format!("{s} ago is out of range")),
+            Err(_) => Err(format!(
+                "expected a sequence number, a timestamp or a duration, found {s:?}"
+            )),
+        }
+    }
+}
+
+/// Outcome of [`recover`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RecoverReport {
+    /// Records written up to the requested point
+    pub replayed: u64,
+    /// Records written after it, left out
+    pub skipped: u64,
+    /// Keys set in the recovered store
+    pub keys: u64,
+    /// Sequence number of the last write recovered, `0` when there was none or it predates stamps
+    pub seq: u64,
+    /// When that write was logged, in milliseconds since the UNIX epoch
+    pub time: u64,
+    /// Frozen segments replayed without a usable hint, which may hold history compacted away before the point
+    pub unchecked: Vec<String>,
+}
+
+impl fmt::Display for RecoverReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Recovered {} keys from {} records, up to write #{}",
+            self.keys, self.replayed, self.seq
+        )?;
+        if self.time > 0 {
+            let time = UNIX_EPOCH + Duration::from_millis(self.time);
+            write!(f, " logged at {}", humantime::format_rfc3339_millis(time))?;
+        }
+        writeln!(f, ", left out {} later records", self.skipped)?;
+        if !self.unchecked.is_empty() {
+            writeln!(
+                f,
+                "Couldn't check {} for compacted history, their hints are missing: \
+                 the store may not be exactly as it was at that point",
+                self.unchecked.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Rebuild in `dst` the state the `kvs` store, or backup, in `src` was in right after the write `until` points at.
+/// `src` may be a live store, it's only read. `dst` must be empty or not exist yet, and is written with `key`,
+/// which along with `previous_keys` must also open `src` when it's encrypted.
+pub fn recover(
+    src: &Path,
+    dst: &Path,
+    until: Until,
+    key: Option<&EncryptionKey>,
+    previous_keys: &[EncryptionKey],
+) -> Result<RecoverReport> {
+    if !src.is_dir() {
+        return Err(DbError::DatabaseNotFound(src.to_path_buf()));
+    }
+    fs::create_dir_all(dst)?;
+    if fs::read_dir(dst)?.next().is_some() {
+        return Err(DbError::Recover(format!(
+            "Recovery target {dst:?} is not empty"
+        )));
+    }
+    if !src.join(MANIFEST_FILE).exists() {
+        return replay(src, dst, until, key, previous_keys);
+    }
+    let engine = BackupManifest::load(src)?.engine;
+    if engine != KVS_ENGINE {
+        return Err(DbError::Recover(format!(
+            "Only kvs stores keep their history, {src:?} is a {engine} backup"
+        )));
+    }
+    let staging = dst.join(STAGING_DIR);
+    let report = backup::restore(src, &staging)
+        .and_then(|_| replay(&staging, dst, until, key, previous_keys));
+    fs::remove_dir_all(&staging)?;
+    report
+}
+
+/// Replay the store in `src` up to `until` into `dst`
+fn replay(
+    src: &Path,
+    dst: &Path,
+    until: Until,
+    key: Option<&EncryptionKey>,
+    previous_keys: &[EncryptionKey],
+) -> Result<RecoverReport> {
+    let source = KvStore::open_with(
+        src,
+        KvStoreOptions {
+            encryption_key: key.cloned(),
+            previous_keys: previous_keys.to_vec(),
+            read_only: true,
+            ..Default::default()
+        },
+    )?;
+    let mut report = RecoverReport::default();
+    // Latest write of each key up to the point, `None` when it's a remove
+    let mut state: HashMap<String, (Stamp, Option<LogPointer>)> = HashMap::new();
+    let ids = segment_ids(src)?;
+    for &id in &ids {
+        let path = segment_path(src, id);
+        let len = fs::metadata(&path)?.len();
+        match Hint::load(src, id, len, &source.keyring) {
+            Some(Hint {
+                compacted: Some(compacted),
+                ..
+            }) if !until.includes(compacted) => {
+                return Err(DbError::Recover(format!(
+                    "History up to write #{} was compacted away, the store can't be recovered to an earlier point",
+                    compacted.seq
+                )));
+            }
+            Some(_) => {}
+            // The active segment never has one
+            None if ids.last() == Some(&id) => {}
+            None => {
+                warn!("Segment {id} has no usable hint, replaying it without checking for compacted history");
+                report.unchecked.push(segment_name(id));
+            }
+        }
+        let contents = fs::read(&path)?;
+        let mut reader = Cursor::new(contents.as_slice());
+        let mut offset = 0;
+        loop {
+            let (raw, consumed) = match record::read_next(&mut reader) {
+                Ok(Some(read)) => read,
+                Ok(None) => break,
+                // A record the writer is still appending
+                Err(DbError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            let record = record::decode(&raw, &source.keyring)?;
+            let pointer = LogPointer {
+                segment: id,
+                offset,
+                len: raw.len() as u64,
+            };
+            offset += consumed;
+            let stamp = record.stamp();
+            if !until.includes(stamp) {
+                report.skipped += 1;
+                continue;
+            }
+            report.replayed += 1;
+            report.seq = report.seq.max(stamp.seq);
+            report.time = report.time.max(stamp.time);
+            let pointer = match record {
+                Record::Remove(_) => None,
+                _ => Some(pointer),
+            };
+            // Moving a blob value logs its write again, later in the log but with the stamp it always had
+            match state.get(record.key()) {
+                Some((latest, _)) if latest.seq > stamp.seq => {}
+                _ => {
+                    state.insert(record.key().to_owned(), (stamp, pointer));
+                }
+            }
+        }
+    }
+    let mut survivors: Vec<(Stamp, String, LogPointer)> = state
+        .into_iter()
+        .filter_map(|(key, (stamp, pointer))| Some((stamp, key, pointer?)))
+        .collect();
+    // Writes keep their order in the new log
+    survivors.sort_by(|(a, a_key, _), (b, b_key, _)| (a, a_key).cmp(&(b, b_key)));
+    let mut target = KvStore::open_with(
+        dst,
+        KvStoreOptions {
+            encryption_key: key.cloned(),
+            ..Default::default()
+        },
+    )?;
+    for (stamp, key, pointer) in survivors {
+        let value = match source.read_record(pointer)? {
+            Record::Set(SetRecord { value, .. }) => value,
+            Record::Blob(BlobRecord { blob, .. }) => source.blobs.read(blob).map_err(|err| {
+                DbError::Recover(format!(
+                    "Value of {key:?} as of write #{} is no longer available: {err}",
+                    stamp.seq
+                ))
+            })?,
+            Record::Remove(_) => unreachable!("removes aren't kept"),
+        };
+        target.write(key, value, stamp)?;
+        report.keys += 1;
+    }
+    info!("Recovered {} keys from {src:?} into {dst:?}", report.keys);
+    Ok(report)
+}
+
+/// Milliseconds since the UNIX epoch
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -0,0 +1,182 @@
+//! Salvage what can be read from a damaged `KvStore` log, as run by `kvs repair`.
+//!
+//! Each segment is read record by record. When a record can't be read, whether its framing is broken, its checksum
+//! doesn't match or it doesn't decode, repair looks for the next position, right after a newline, at which a record
+//! reads fine again, and carries on from there. Segments that lost anything are rewritten from the records that
+//! survived, the original being kept next to them as `kv_<id>.log.corrupt`.
+
+use crate::crypto::Keyring;
+use crate::hint::hint_path;
+use crate::record;
+use crate::{
+    lock, segment_ids, segment_name, segment_path, DbError, EncryptionKey, Result, INDEX_FILE,
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+/// Bytes of a segment that had to be dropped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LostRegion {
+    /// Segment file name
+    pub file: String,
+    /// Byte position in the original segment at which the region starts
+    pub offset: u64,
+    /// Bytes dropped
+    pub len: u64,
+    /// Keys spotted in the dropped bytes. Only those of plain records can be, the keys of compressed or
+    /// encrypted records are lost along with them
+    pub keys: Vec<String>,
+}
+
+/// Outcome of [`repair`]
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RepairReport {
+    /// Records kept, across all segments
+    pub recovered: u64,
+    /// Everything dropped, in log order
+    pub lost: Vec<LostRegion>,
+    /// Original copies of the segments that were rewritten
+    pub backups: Vec<PathBuf>,
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in &self.lost {
+            write!(
+                f,
+                "{}: dropped {} bytes at byte {}",
+                region.file, region.len, region.offset
+            )?;
+            match region.keys.is_empty() {
+                true => writeln!(f)?,
+                false => writeln!(f, ", which mention keys {:?}", region.keys)?,
+            }
+        }
+        for backup in &self.backups {
+            writeln!(f, "Kept the original as {backup:?}")?;
+        }
+        writeln!(
+            f,
+            "Recovered {} records, dropped {} bytes in {} places",
+            self.recovered,
+            self.lost.iter().map(|region| region.len).sum::<u64>(),
+            self.lost.len()
+        )
+    }
+}
+
+/// Rewrite the damaged segments of the store in `dir` from the records that can still be read.
+/// Takes the writer lock, so the store must not be open elsewhere. Encrypted stores need their keys,
+/// without them every encrypted record would look damaged.
+pub fn repair(
+    dir: &Path,
+    key: Option<&EncryptionKey>,
+    previous_keys: &[EncryptionKey],
+) -> Result<RepairReport> {
+    if !dir.is_dir() {
+        return Err(DbError::DatabaseNotFound(dir.to_path_buf()));
+    }
+    let _lock = lock::acquire(dir)?;
+    let keyring = Keyring::new(key, previous_keys);
+    let mut report = RepairReport::default();
+    for id in segment_ids(dir)? {
+        let path = segment_path(dir, id);
+        let contents = fs::read(&path)?;
+        let (kept, lost) = salvage(&contents, &keyring);
+        report.recovered += kept.len() as u64;
+        if lost.is_empty() {
+            continue;
+        }
+        let backup = path.with_extension("log.corrupt");
+        if backup.exists() {
+            return Err(DbError::Corrupted(format!(
+                "{backup:?} is left from an earlier repair, move it away first"
+            )));
+        }
+        let tmp_path = path.with_extension("log.repairing");
+        let mut out = File::create(&tmp_path)?;
+        for (offset, len) in kept {
+            out.write_all(&contents[offset as usize..(offset + len) as usize])?;
+        }
+        out.sync_all()?;
+        fs::rename(&path, &backup)?;
+        fs::rename(&tmp_path, &path)?;
+        // Whatever pointed into the segment is now off
+        let _ = fs::remove_file(hint_path(dir, id));
+        warn!(
+            "Rewrote {} without {} damaged regions",
+            segment_name(id),
+            lost.len()
+        );
+        report
+            .lost
+            .extend(lost.into_iter().map(|(offset, len)| LostRegion {
+                file: segment_name(id),
+                offset,
+                len,
+                keys: spotted_keys(&contents[offset as usize..(offset + len) as usize]),
+            }));
+        report.backups.push(backup);
+    }
+    if !report.backups.is_empty() {
+        let _ = fs::remove_file(dir.join(INDEX_FILE));
+    }
+    Ok(report)
+}
+
+/// Stretch of a segment, as `(offset, len)`
+type Span = (u64, u64);
+
+/// Split a segment into the records that read fine and the regions that don't.
+/// Kept records include their trailing newline.
+fn salvage(contents: &[u8], keyring: &Keyring) -> (Vec<Span>, Vec<Span>) {
+    let (mut kept, mut lost) = (vec![], vec![]);
+    let mut offset = 0;
+    while (offset as usize) < contents.len() {
+        if let Some(len) = readable_at(contents, offset, keyring) {
+            kept.push((offset, len));
+            offset += len;
+            continue;
+        }
+        // Resynchronize on the next record boundary that reads fine
+        let resumed = (offset as usize + 1..contents.len())
+            .filter(|&at| contents[at - 1] == b'\n')
+            .map(|at| at as u64)
+            .find(|&at| readable_at(contents, at, keyring).is_some())
+            .unwrap_or(contents.len() as u64);
+        debug!("Dropping bytes {offset}..{resumed}");
+        lost.push((offset, resumed - offset));
+        offset = resumed;
+    }
+    (kept, lost)
+}
+
+/// Length of the record starting at `offset`, newline included, if it reads and decodes fine
+fn readable_at(contents: &[u8], offset: u64, keyring: &Keyring) -> Option<u64> {
+    let mut reader = Cursor::new(&contents[offset as usize..]);
+    let (raw, consumed) = record::read_next(&mut reader).ok()??;
+    let newline = contents[(offset + consumed) as usize - 1] == b'\n';
+    (newline && record::decode(&raw, keyring).is_ok()).then_some(consumed)
+}
+
+/// Keys of the plain records found in `bytes`, which may start or end halfway through a record
+fn spotted_keys(bytes: &[u8]) -> Vec<String> {
+    const KEY_FIELD: &str = "key: \"";
+    let text = String::from_utf8_lossy(bytes);
+    let mut keys = vec![];
+    let mut rest = text.as_ref();
+    while let Some(start) = rest.find(KEY_FIELD) {
+        rest = &rest[start + KEY_FIELD.len()..];
+        let Some(end) = rest.find('"') else { break };
+        keys.push(rest[..end].to_owned());
+        rest = &rest[end..];
+    }
+    keys
+}
@@ -0,0 +1,64 @@
+//! Engine shared between threads.
+
+use crate::{BackupManifest, EngineStats, KvPairs, KvsEngine, Result};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Handle on an engine shared between threads, each cloned handle reaching the same engine.
+/// Calls take turns on a lock around the engine, so any [`KvsEngine`] can be served from a thread pool.
+pub struct SharedEngine<E> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E> Clone for SharedEngine<E> {
+    fn clone(&self) -> Self {
+        SharedEngine {
+            engine: Arc::clone(&self.engine),
+        }
+    }
+}
+
+impl<E: KvsEngine> SharedEngine<E> {
+    /// Share `engine`
+    pub fn new(engine: E) -> Self {
+        SharedEngine {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// Exclusive access to the engine until the guard is dropped
+    pub fn lock(&self) -> MutexGuard<'_, E> {
+        // A call that panicked leaves the engine as it would a crash, which engines recover from
+        self.engine
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for SharedEngine<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.lock().set(key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.lock().get(key)
+    }
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.lock().remove(key)
+    }
+    /// Unlike other engines, pairs are all read up front, so the lock isn't held while they're consumed
+    fn scan<'a>(&'a self, prefix: &'a str) -> Result<KvPairs<'a>> {
+        let pairs = self.lock().scan(prefix)?.collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+    fn backup_to(&mut self, dir: &Path, since: Option<&Path>) -> Result<BackupManifest> {
+        self.lock().backup_to(dir, since)
+    }
+    fn stats(&self) -> Result<EngineStats> {
+        self.lock().stats()
+    }
+    fn sync(&mut self) -> Result<()> {
+        self.lock().sync()
+    }
+}
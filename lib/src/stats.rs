@@ -0,0 +1,100 @@
+//! Size and health figures of an engine, as returned by [`KvsEngine::stats`](crate::KvsEngine::stats).
+
+use crate::{CacheStats, CompressionStats};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How big and how fragmented a store is
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Engine the figures come from
+    pub engine: String,
+    /// Live keys
+    pub keys: u64,
+    /// Bytes on disk holding the current value of a key
+    pub live_bytes: u64,
+    /// Bytes on disk holding overwritten or removed values, until compaction reclaims them
+    pub stale_bytes: u64,
+    /// Files the data is spread across: log segments for kvs, SSTables for lsm
+    pub segments: u64,
+    /// Compactions run since the store was opened
+    pub compactions: u64,
+    /// When the last of them finished, in seconds since the UNIX epoch
+    pub last_compaction: Option<u64>,
+    /// Read cache counters, when there is a cache in front of the engine
+    pub cache: Option<CacheStats>,
+    /// Budget and eviction counters, when the engine runs in cache mode, see [`CacheEngine`](crate::CacheEngine)
+    pub cache_mode: Option<CacheStats>,
+    /// What compression achieved, for engines that compress
+    pub compression: Option<CompressionStats>,
+}
+
+/// Compaction counters kept by the engines that compact
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Compactions {
+    pub(crate) count: u64,
+    pub(crate) last: Option<u64>,
+}
+
+impl Compactions {
+    /// Count a compaction that just finished
+    pub(crate) fn record(&mut self) {
+        self.count += 1;
+        self.last = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .ok();
+    }
+}
+
+impl fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "engine: {}", self.engine)?;
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "stale bytes: {}", self.stale_bytes)?;
+        writeln!(f, "segments: {}", self.segments)?;
+        writeln!(f, "compactions: {}", self.compactions)?;
+        match self.last_compaction {
+            Some(secs) => writeln!(f, "last compaction: {secs} (seconds since epoch)")?,
+            None => writeln!(f, "last compaction: never")?,
+        }
+        if let Some(cache) = &self.cache {
+            writeln!(
+                f,
+                "cache: {} hits, {} misses, {} evictions, {} expired, {} entries, {}/{} bytes",
+                cache.hits,
+                cache.misses,
+                cache.evictions,
+                cache.expired,
+                cache.entries,
+                cache.bytes,
+                cache.capacity
+            )?;
+        }
+        if let Some(cache) = &self.cache_mode {
+            writeln!(
+                f,
+                "cache mode: {} hits, {} misses, {} evictions, {} expired, {} keys, {}/{} bytes",
+                cache.hits,
+                cache.misses,
+                cache.evictions,
+                cache.expired,
+                cache.entries,
+                cache.bytes,
+                cache.capacity
+            )?;
+        }
+        if let Some(compression) = &self.compression {
+            writeln!(
+                f,
+                "compression: {} of {} records compressed, ratio {:.2}",
+                compression.compressed,
+                compression.records,
+                compression.ratio()
+            )?;
+        }
+        Ok(())
+    }
+}
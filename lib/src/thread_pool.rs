@@ -0,0 +1,137 @@
+//! Thread pools `kvs-server` runs connections on.
+//!
+//! - [`NaiveThreadPool`] starts a thread per job and never reuses it.
+//! - [`SharedQueueThreadPool`] keeps a fixed set of threads taking jobs off a shared queue.
+//!   A job that panics takes its thread down with it, and a new thread takes its place.
+//! - [`RayonThreadPool`] hands jobs to a rayon pool, which balances them with work stealing.
+
+use crate::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// Runs jobs on other threads
+pub trait ThreadPool: Sized {
+    /// Start a pool of `threads` threads
+    fn new(threads: u32) -> Result<Self>;
+    /// Run `job` on one of the pool's threads. A job that panics doesn't affect the pool
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// Thread pool implementation to pick, as given to `kvs-server --pool`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PoolKind {
+    /// [`NaiveThreadPool`]
+    Naive,
+    /// [`SharedQueueThreadPool`]
+    #[default]
+    SharedQueue,
+    /// [`RayonThreadPool`]
+    Rayon,
+}
+
+/// Not a pool at all: every job gets a thread of its own
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed number of threads taking jobs off a shared queue.
+/// Dropping the pool lets the threads finish the jobs already queued, then stop.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            Worker(receiver.clone()).start();
+        }
+        debug!("Started a shared queue pool of {threads} threads");
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("pool threads replace themselves, so the queue outlives the pool");
+    }
+}
+
+/// A thread of a [`SharedQueueThreadPool`]
+struct Worker(Arc<Mutex<Receiver<Job>>>);
+
+impl Worker {
+    fn start(self) {
+        thread::spawn(move || self.run());
+    }
+
+    fn run(&self) {
+        loop {
+            // The lock is released as soon as a job is taken, before it runs
+            let job = match self.0.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(poisoned) => poisoned.into_inner().recv(),
+            };
+            match job {
+                Ok(job) => job(),
+                // The pool was dropped and the queue drained
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!("A job panicked, starting a thread to replace the one it took down");
+            Worker(self.0.clone()).start();
+        }
+    }
+}
+
+/// Jobs run on a [`rayon::ThreadPool`]
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| error!("A job panicked"))
+            .build()?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job);
+    }
+}
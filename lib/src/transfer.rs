@@ -0,0 +1,136 @@
+//! Streaming export and import of a store's contents as JSON Lines or CSV.
+//! Both directions work one record at a time, so neither the store nor the dump
+//! ever has to fit in memory.
+
+use crate::{DbError, KvsEngine, Result};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// Encoding used for an export or expected by an import
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One `{"key":..,"value":..}` JSON object per line
+    #[default]
+    Jsonl,
+    /// `key,value` rows preceded by a header row
+    Csv,
+}
+
+/// What an import does with a key that already exists in the store
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Replace the stored value with the imported one
+    #[default]
+    Overwrite,
+    /// Keep the stored value and move on
+    Skip,
+    /// Abort the import at the first existing key.
+    /// Records imported before the conflict are kept.
+    Fail,
+}
+
+/// A single exported record
+#[derive(Serialize, Deserialize, Debug)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Outcome of an [`import`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Records written to the store
+    pub imported: u64,
+    /// Records left alone because the key already existed and [`OnConflict::Skip`] was used
+    pub skipped: u64,
+    /// Records whose key didn't match the prefix filter
+    pub filtered: u64,
+}
+
+/// Write every live pair whose key starts with `prefix` to `writer`.
+/// Returns the number of records exported.
+pub fn export<E, W>(engine: &E, format: Format, prefix: &str, writer: W) -> Result<u64>
+where
+    E: KvsEngine + ?Sized,
+    W: Write,
+{
+    let mut exported = 0;
+    match format {
+        Format::Jsonl => {
+            let mut writer = writer;
+            for pair in engine.scan(prefix)? {
+                let (key, value) = pair?;
+                serde_json::to_writer(&mut writer, &Pair { key, value })?;
+                writer.write_all(b"\n")?;
+                exported += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for pair in engine.scan(prefix)? {
+                let (key, value) = pair?;
+                writer.serialize(Pair { key, value })?;
+                exported += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    debug!("Exported {exported} records as {format:?}");
+    Ok(exported)
+}
+
+/// Read records from `reader` into the store, keeping only keys that start with `prefix`.
+pub fn import<E, R>(
+    engine: &mut E,
+    format: Format,
+    prefix: &str,
+    on_conflict: OnConflict,
+    reader: R,
+) -> Result<ImportSummary>
+where
+    E: KvsEngine + ?Sized,
+    R: BufRead,
+{
+    let mut summary = ImportSummary::default();
+    let mut apply = |Pair { key, value }: Pair| -> Result<()> {
+        if !key.starts_with(prefix) {
+            summary.filtered += 1;
+            return Ok(());
+        }
+        if on_conflict != OnConflict::Overwrite && engine.get(key.clone())?.is_some() {
+            if on_conflict == OnConflict::Fail {
+                warn!("Import aborted, key already exists: {:?}", key);
+                return Err(DbError::KeyExists(key));
+            }
+            summary.skipped += 1;
+            return Ok(());
+        }
+        engine.set(key, value)?;
+        summary.imported += 1;
+        Ok(())
+    };
+    match format {
+        Format::Jsonl => {
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                apply(serde_json::from_str(&line)?)?;
+            }
+        }
+        Format::Csv => {
+            for pair in csv::Reader::from_reader(reader).deserialize() {
+                apply(pair?)?;
+            }
+        }
+    }
+    info!(
+        "Imported {} records, skipped {}, filtered {}",
+        summary.imported, summary.skipped, summary.filtered
+    );
+    Ok(summary)
+}
@@ -0,0 +1,397 @@
+//! Offline integrity checks of a `KvStore` directory, as run by `kvs verify`.
+//!
+//! Every segment is read record by record, checking the framing, the checksum of framed records, and that each
+//! record decodes. The hint files and `kv_memory.index` are then compared with what replaying the log gives,
+//! every blob a live key points to must lie within its blob file, and the `MANIFEST` must list the segments found. Nothing is written, and no lock is taken,
+//! so a store can be verified next to the process serving it.
+
+use crate::backup::KVS_ENGINE;
+use crate::blob::{blob_path, BlobPointer};
+use crate::crypto::Keyring;
+use crate::hint::{hint_name, hint_path, Hint, HintEntry};
+use crate::manifest::{self, Manifest, MANIFEST_FILE};
+use crate::record;
+use crate::{
+    segment_ids, segment_name, segment_path, DbError, EncryptionKey, IndexSnapshot, LogPointer,
+    Result, INDEX_FILE,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Cursor},
+    path::Path,
+};
+
+/// Something found wrong with a file of the store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    /// File name, relative to the store's directory
+    pub file: String,
+    /// Byte position in the file the problem was found at, if it can be pinned down
+    pub offset: Option<u64>,
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at byte {offset}: {}", self.file, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// Outcome of [`verify`]
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct VerifyReport {
+    /// Log segments read
+    pub segments: usize,
+    /// Records read across all segments
+    pub records: u64,
+    /// Hint files compared with their segment
+    pub hints: usize,
+    /// Whether `kv_memory.index` was compared with the log. It isn't when missing, or when taken from
+    /// other segments than those on disk, in which case opening the store ignores it anyway
+    pub index: bool,
+    /// Everything found wrong, in the order it was found
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    /// `true` when no problem was found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(
+        &mut self,
+        file: impl Into<String>,
+        offset: Option<u64>,
+        message: impl Into<String>,
+    ) {
+        self.problems.push(Problem {
+            file: file.into(),
+            offset,
+            message: message.into(),
+        });
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{problem}")?;
+        }
+        writeln!(
+            f,
+            "Checked {} records in {} segments, {} hint files and {} index: {} problems",
+            self.records,
+            self.segments,
+            self.hints,
+            if self.index { "the" } else { "no" },
+            self.problems.len()
+        )
+    }
+}
+
+/// Check the store in `dir`, without opening it. Encrypted stores need their keys.
+/// Fails only when the check can't be carried out, problems found with the store are listed in the report.
+pub fn verify(
+    dir: &Path,
+    key: Option<&EncryptionKey>,
+    previous_keys: &[EncryptionKey],
+) -> Result<VerifyReport> {
+    if !dir.is_dir() {
+        return Err(DbError::DatabaseNotFound(dir.to_path_buf()));
+    }
+    let keyring = Keyring::new(key, previous_keys);
+    let mut report = VerifyReport::default();
+    let segments = segment_ids(dir)?;
+    let mut log: Vec<(u64, Vec<HintEntry>, u64)> = vec![];
+    for &id in &segments {
+        let (entries, end) = check_segment(dir, id, &keyring, &mut report)?;
+        report.segments += 1;
+        report.records += entries.len() as u64;
+        log.push((id, entries, end));
+    }
+    for (id, entries, end) in log.iter().rev().skip(1) {
+        if hint_path(dir, *id).exists() {
+            check_hint(dir, *id, entries, *end, &keyring, &mut report);
+        }
+    }
+    check_index(dir, &segments, &log, &keyring, &mut report)?;
+    check_manifest(dir, &segments, &mut report);
+
+    let mut blobs: HashMap<&str, BlobPointer> = HashMap::new();
+    for entry in log.iter().flat_map(|(_, entries, _)| entries) {
+        match entry.blob {
+            Some(blob) => blobs.insert(&entry.key, blob),
+            None => blobs.remove(entry.key.as_str()),
+        };
+    }
+    for (key, blob) in blobs {
+        let file = blob_path(dir, blob.file);
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        match fs::metadata(&file) {
+            Ok(metadata) if blob.offset + blob.len <= metadata.len() => {}
+            Ok(metadata) => report.problem(
+                name,
+                Some(blob.offset),
+                format!(
+                    "value of {key:?} runs {} bytes past the end of the file",
+                    blob.offset + blob.len - metadata.len()
+                ),
+            ),
+            Err(_) => report.problem(
+                name,
+                None,
+                format!("missing, yet holds the value of {key:?}"),
+            ),
+        }
+    }
+    Ok(report)
+}
+
+/// Read every record of segment `id`, reporting those that can't be read.
+/// Returns the index entries of the readable records, and the offset at which reading stopped.
+fn check_segment(
+    dir: &Path,
+    id: u64,
+    keyring: &Keyring,
+    report: &mut VerifyReport,
+) -> Result<(Vec<HintEntry>, u64)> {
+    let contents = fs::read(segment_path(dir, id))?;
+    let mut reader = Cursor::new(contents.as_slice());
+    let mut entries = vec![];
+    let mut offset = 0;
+    loop {
+        let (raw, consumed) = match record::read_next(&mut reader) {
+            Ok(Some(next)) => next,
+            Ok(None) => break,
+            Err(DbError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                report.problem(
+                    segment_name(id),
+                    Some(offset),
+                    format!(
+                        "record cut short, {} bytes left in the segment",
+                        contents.len() as u64 - offset
+                    ),
+                );
+                break;
+            }
+            // Nothing tells where the next record starts
+            Err(DbError::Corrupted(reason)) => {
+                report.problem(segment_name(id), Some(offset), reason);
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        let pointer = LogPointer {
+            segment: id,
+            offset,
+            len: raw.len() as u64,
+        };
+        offset += consumed;
+        if contents.get(offset as usize - 1) != Some(&b'\n') {
+            report.problem(
+                segment_name(id),
+                Some(offset - 1),
+                "record isn't followed by a newline",
+            );
+        }
+        let entry = match record::decode(&raw, keyring) {
+            Ok(record) => record.into_entry(pointer),
+            Err(err) => {
+                report.problem(segment_name(id), Some(pointer.offset), err.to_string());
+                continue;
+            }
+        };
+        entries.push(entry);
+    }
+    Ok((entries, offset))
+}
+
+/// Compare the hint of frozen segment `id` with the `entries` read from the segment itself
+fn check_hint(
+    dir: &Path,
+    id: u64,
+    entries: &[HintEntry],
+    len: u64,
+    keyring: &Keyring,
+    report: &mut VerifyReport,
+) {
+    report.hints += 1;
+    let parsed = fs::read(hint_path(dir, id))
+        .map_err(DbError::from)
+        .and_then(|contents| keyring.open_file(contents))
+        .and_then(|contents| Ok(ron::de::from_bytes::<Hint>(&contents)?));
+    let hint = match parsed {
+        Ok(hint) => hint,
+        Err(err) => return report.problem(hint_name(id), None, format!("unreadable: {err}")),
+    };
+    if hint.len != len {
+        return report.problem(
+            hint_name(id),
+            None,
+            format!("describes {} bytes of log, the segment has {len}", hint.len),
+        );
+    }
+    let mismatch = hint
+        .entries
+        .iter()
+        .zip(entries)
+        .find(|(hinted, logged)| hinted != logged);
+    if let Some((hinted, logged)) = mismatch {
+        report.problem(
+            segment_name(id),
+            Some(logged.pointer.offset),
+            format!(
+                "hint has {:?} at byte {}, the log has {:?}",
+                hinted.key, hinted.pointer.offset, logged.key
+            ),
+        );
+    } else if hint.entries.len() != entries.len() {
+        report.problem(
+            hint_name(id),
+            None,
+            format!(
+                "holds {} entries, the segment {} records",
+                hint.entries.len(),
+                entries.len()
+            ),
+        );
+    }
+}
+
+/// Check `MANIFEST`, if the store has one, describes a kvs store in a format this build reads, made of `segments`
+fn check_manifest(dir: &Path, segments: &[u64], report: &mut VerifyReport) {
+    let manifest = match Manifest::load(dir) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => return,
+        Err(err) => return report.problem(MANIFEST_FILE, None, format!("can't be read: {err}")),
+    };
+    if manifest.engine != KVS_ENGINE {
+        report.problem(
+            MANIFEST_FILE,
+            None,
+            format!("describes a {} store", manifest.engine),
+        );
+    }
+    if Some(manifest.format_version) != manifest::format_version(KVS_ENGINE) {
+        report.problem(
+            MANIFEST_FILE,
+            None,
+            format!("format version {} isn't supported", manifest.format_version),
+        );
+    }
+    let found: Vec<String> = segments.iter().map(|&id| segment_name(id)).collect();
+    if manifest.segments != found {
+        report.problem(
+            MANIFEST_FILE,
+            None,
+            format!(
+                "lists segments {:?}, the store holds {found:?}",
+                manifest.segments
+            ),
+        );
+    }
+}
+
+/// Compare `kv_memory.index` with the index obtained by replaying the log up to where the index was taken
+fn check_index(
+    dir: &Path,
+    segments: &[u64],
+    log: &[(u64, Vec<HintEntry>, u64)],
+    keyring: &Keyring,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let contents = match fs::read(dir.join(INDEX_FILE)) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let parsed = keyring
+        .open_file(contents)
+        .and_then(|contents| Ok(ron::de::from_bytes::<IndexSnapshot>(&contents)?));
+    let snapshot = match parsed {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            report.problem(INDEX_FILE, None, format!("unreadable: {err}"));
+            return Ok(());
+        }
+    };
+    if snapshot.segments != segments {
+        return Ok(());
+    }
+    report.index = true;
+    let Some((active, _, end)) = log.last() else {
+        return Ok(());
+    };
+    if snapshot.offset > *end {
+        report.problem(
+            INDEX_FILE,
+            None,
+            format!(
+                "taken at byte {} of {}, past its last record at {end}",
+                snapshot.offset,
+                segment_name(*active)
+            ),
+        );
+        return Ok(());
+    }
+    let mut replayed: HashMap<&str, LogPointer> = HashMap::new();
+    let mut boundary = snapshot.offset == 0;
+    for entry in log.iter().flat_map(|(_, entries, _)| entries) {
+        let pointer = entry.pointer;
+        if pointer.segment == *active && pointer.offset >= snapshot.offset {
+            break;
+        }
+        boundary |=
+            pointer.segment == *active && pointer.offset + pointer.len + 1 == snapshot.offset;
+        match entry.removed {
+            true => replayed.remove(entry.key.as_str()),
+            false => replayed.insert(&entry.key, pointer),
+        };
+    }
+    if !boundary {
+        report.problem(
+            segment_name(*active),
+            Some(snapshot.offset),
+            format!("{INDEX_FILE} was taken here, which isn't a record boundary"),
+        );
+    }
+    let mut keys: Vec<&String> = snapshot.map.keys().collect();
+    keys.sort();
+    for key in keys {
+        let indexed = snapshot.map[key];
+        match replayed.remove(key.as_str()) {
+            Some(pointer) if pointer == indexed => {}
+            Some(pointer) => report.problem(
+                segment_name(indexed.segment),
+                Some(indexed.offset),
+                format!(
+                    "{INDEX_FILE} points {key:?} here, the log has it in {} at byte {}",
+                    segment_name(pointer.segment),
+                    pointer.offset
+                ),
+            ),
+            None => report.problem(
+                segment_name(indexed.segment),
+                Some(indexed.offset),
+                format!("{INDEX_FILE} points {key:?} here, the log has no such key"),
+            ),
+        }
+    }
+    let mut missing: Vec<(&str, LogPointer)> = replayed.into_iter().collect();
+    missing.sort_unstable_by_key(|&(key, _)| key);
+    for (key, pointer) in missing {
+        report.problem(
+            segment_name(pointer.segment),
+            Some(pointer.offset),
+            format!("{key:?} is set here but missing from {INDEX_FILE}"),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,266 @@
+use kvs::backup::{restore, MANIFEST_FILE};
+use kvs::{Codec, KvStore, KvStoreOptions, KvsEngine, LsmStore, Manifest, Result, SledKvsEngine};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+// A backup taken while the store is open should capture exactly the writes made before it
+#[test]
+fn kvs_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let manifest = store.backup_to(backup_dir.path(), None)?;
+    assert_eq!(manifest.engine, "kvs");
+    assert!(!manifest.segments.is_empty());
+
+    // The store keeps serving writes after the backup
+    store.set("key1".to_owned(), "after".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(backup_dir.path(), restore_dir.path())?;
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, None);
+    assert_eq!(restored.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let manifest = store.backup_to(backup_dir.path(), None)?;
+    assert_eq!(manifest.engine, "sled");
+    assert!(manifest.checksum.is_some());
+
+    // Restoring leaves the backup as it was
+    let before = listing(backup_dir.path())?;
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(backup_dir.path(), restore_dir.path())?;
+    assert_eq!(listing(backup_dir.path())?, before);
+    assert!(!restore_dir.path().join(MANIFEST_FILE).exists());
+    let restored = SledKvsEngine::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+/// Names and contents of the files in `dir`, recursively
+fn listing(dir: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(listing(&path)?);
+        } else {
+            files.push((path.display().to_string(), fs::read(&path)?));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// The restored store keeps the format version and creation options of the original
+#[test]
+fn backup_keeps_store_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compression: Codec::Lz4,
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let manifest = store.backup_to(backup_dir.path(), None)?;
+    assert!(manifest.manifest.is_some());
+    let original = Manifest::load(temp_dir.path())?.unwrap();
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(backup_dir.path(), restore_dir.path())?;
+    let restored = Manifest::load(restore_dir.path())?.unwrap();
+    assert_eq!(restored.format_version, original.format_version);
+    assert_eq!(restored.created, original.created);
+    assert_eq!(restored.options, original.options);
+    assert_eq!(restored.options["compression"], "lz4");
+    Ok(())
+}
+
+#[test]
+fn lsm_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let manifest = store.backup_to(backup_dir.path(), None)?;
+    assert_eq!(manifest.engine, "lsm");
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(backup_dir.path(), restore_dir.path())?;
+    let restored = LsmStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, None);
+    assert_eq!(restored.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn restore_rejects_bad_input() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let manifest = store.backup_to(backup_dir.path(), None)?;
+
+    // Backing up twice into the same directory is refused
+    assert!(store.backup_to(backup_dir.path(), None).is_err());
+    // A non empty target is refused
+    assert!(restore(backup_dir.path(), temp_dir.path()).is_err());
+    // A directory without a manifest isn't a backup
+    let empty = TempDir::new().expect("unable to create temporary working directory");
+    assert!(restore(empty.path(), &empty.path().join("target")).is_err());
+
+    // A segment that doesn't match the manifest fails validation
+    let segment = backup_dir.path().join(&manifest.segments[0].name);
+    OpenOptions::new()
+        .append(true)
+        .open(segment)?
+        .write_all(b"garbage\n")?;
+    assert!(backup_dir.path().join(MANIFEST_FILE).exists());
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(restore(backup_dir.path(), restore_dir.path()).is_err());
+    Ok(())
+}
+
+// Incremental backups only copy new segments, and restoring one replays the whole chain
+#[test]
+fn incremental_backup_chain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backups = TempDir::new().expect("unable to create temporary working directory");
+    let (full, incr1, incr2) = (
+        backups.path().join("full"),
+        backups.path().join("incr1"),
+        backups.path().join("incr2"),
+    );
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let base = store.backup_to(&full, None)?;
+    // Freezing a segment leaves a hint file next to it
+    assert!(temp_dir.path().join("kv_00001.hint").exists());
+    assert_eq!(base.hints.len(), 1);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let manifest = store.backup_to(&incr1, Some(&full.join(MANIFEST_FILE)))?;
+    assert!(manifest.segments[0].inherited);
+    assert!(!manifest.segments[1].inherited);
+    assert!(!incr1.join("kv_00001.log").exists());
+    assert!(incr1.join("kv_00002.log").exists());
+
+    // Compaction replaces every segment, so the next incremental copies only the compacted one
+    store.remove("key1".to_owned())?;
+    store.compaction()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    let manifest = store.backup_to(&incr2, Some(&incr1))?;
+    assert!(manifest.segments.iter().all(|file| !file.inherited));
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(&incr1, restore_dir.path())?;
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(restored.get("key4".to_owned())?, None);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(&incr2, restore_dir.path())?;
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, None);
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(restored.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(restored);
+
+    // The chain still holds together once moved elsewhere
+    let moved = TempDir::new().expect("unable to create temporary working directory");
+    for name in ["full", "incr1", "incr2"] {
+        fs::rename(backups.path().join(name), moved.path().join(name))?;
+    }
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(&moved.path().join("incr1"), restore_dir.path())?;
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A collected blob file's id comes back once the store is reopened: the base's copy of the old file
+// has the same name and may well have the same size, but it's not the same file
+#[test]
+fn incremental_backup_copies_reused_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backups = TempDir::new().expect("unable to create temporary working directory");
+    let (full, incr) = (backups.path().join("full"), backups.path().join("incr"));
+    let options = || KvStoreOptions {
+        blob_threshold: Some(100),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options())?;
+    store.set("big".to_owned(), "a".repeat(1_000))?;
+    store.backup_to(&full, None)?;
+    store.remove("big".to_owned())?;
+    store.blob_gc()?;
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), options())?;
+    store.set("big".to_owned(), "b".repeat(1_000))?;
+    let manifest = store.backup_to(&incr, Some(&full))?;
+    assert_eq!(manifest.blobs.len(), 1);
+    assert!(!manifest.blobs[0].inherited);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(&incr, restore_dir.path())?;
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("big".to_owned())?, Some("b".repeat(1_000)));
+    Ok(())
+}
+
+// A corrupted file anywhere in the chain is caught by its checksum
+#[test]
+fn incremental_backup_checksums() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backups = TempDir::new().expect("unable to create temporary working directory");
+    let (full, incr) = (backups.path().join("full"), backups.path().join("incr"));
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup_to(&full, None)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup_to(&incr, Some(&full))?;
+
+    // Same length, different contents
+    let segment = full.join("kv_00001.log");
+    let contents = std::fs::read_to_string(&segment)?.replace("value1", "value9");
+    std::fs::write(&segment, contents)?;
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(restore(&incr, restore_dir.path()).is_err());
+
+    // Sled has no immutable segments to build on
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut sled = SledKvsEngine::open(sled_dir.path())?;
+    assert!(sled
+        .backup_to(&backups.path().join("sled"), Some(&incr))
+        .is_err());
+    Ok(())
+}
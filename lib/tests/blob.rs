@@ -0,0 +1,142 @@
+use kvs::backup::restore;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn blob_store(path: &Path) -> Result<KvStore> {
+    KvStore::open_with(
+        path,
+        KvStoreOptions {
+            blob_threshold: Some(100),
+            ..Default::default()
+        },
+    )
+}
+
+/// Total size of the files in `dir` with the given extension
+fn files_size(dir: &Path, extension: &str) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == extension))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// Large values go to blob files, small ones stay in the log
+#[test]
+fn large_values_are_separated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let big = "x".repeat(10_000);
+    let mut store = blob_store(temp_dir.path())?;
+    store.set("big".to_owned(), big.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert!(files_size(temp_dir.path(), "log") < 1_000);
+    assert_eq!(files_size(temp_dir.path(), "blob"), 10_000);
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+
+    // Replay, then compaction, keep following the pointers
+    drop(store);
+    let mut store = blob_store(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+    store.compaction()?;
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(files_size(temp_dir.path(), "blob"), 10_000);
+
+    // Reads don't depend on the threshold the value was written with
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+    Ok(())
+}
+
+#[test]
+fn blob_garbage_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = blob_store(temp_dir.path())?;
+    for round in 0..5 {
+        store.set("big".to_owned(), format!("{round}").repeat(1_000))?;
+        store.set("other".to_owned(), "y".repeat(1_000))?;
+    }
+    store.remove("other".to_owned())?;
+    assert_eq!(files_size(temp_dir.path(), "blob"), 10_000);
+
+    assert_eq!(store.blob_gc()?, 9_000);
+    assert_eq!(files_size(temp_dir.path(), "blob"), 1_000);
+    assert_eq!(store.get("big".to_owned())?, Some("4".repeat(1_000)));
+
+    // Replaying the log comes across pointers into the collected files, which are gone for good
+    drop(store);
+    let mut store = blob_store(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some("4".repeat(1_000)));
+    assert_eq!(store.get("other".to_owned())?, None);
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("big".to_owned(), "5".repeat(1_000))?;
+    assert_eq!(store.blob_gc()?, 1_000);
+    assert_eq!(store.get("big".to_owned())?, Some("5".repeat(1_000)));
+    Ok(())
+}
+
+// Blob files that are mostly garbage get collected as writes go, without key compaction
+#[test]
+fn automatic_blob_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = blob_store(temp_dir.path())?;
+    for round in 0..20 {
+        store.set("big".to_owned(), format!("{}", round % 10).repeat(200_000))?;
+    }
+    assert!(files_size(temp_dir.path(), "blob") <= 2_000_000);
+    assert_eq!(store.get("big".to_owned())?, Some("9".repeat(200_000)));
+
+    drop(store);
+    let mut store = blob_store(temp_dir.path())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.keys, 2);
+    Ok(())
+}
+
+#[test]
+fn backup_includes_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = blob_store(temp_dir.path())?;
+    store.set("big".to_owned(), "z".repeat(1_000))?;
+    let manifest = store.backup_to(backup_dir.path(), None)?;
+    assert_eq!(manifest.blobs.len(), 1);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(backup_dir.path(), restore_dir.path())?;
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("big".to_owned())?, Some("z".repeat(1_000)));
+    Ok(())
+}
+
+// Blob file sizes are tracked in memory as values come and go, and agree with what's on disk
+#[test]
+fn blob_sizes_tracked_in_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = blob_store(temp_dir.path())?;
+    for round in 0..3 {
+        store.set("big".to_owned(), format!("{round}").repeat(1_000))?;
+    }
+    store.set("small".to_owned(), "s".to_owned())?;
+    let stats = store.stats()?;
+    drop(store);
+
+    let mut store = blob_store(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    assert_eq!(reopened.stale_bytes, stats.stale_bytes);
+    assert_eq!(store.blob_gc()?, 2_000);
+    assert_eq!(files_size(temp_dir.path(), "blob"), 1_000);
+
+    // Files collected above don't come back when the log is replayed
+    drop(store);
+    let mut store = blob_store(temp_dir.path())?;
+    store.set("small".to_owned(), "t".to_owned())?;
+    assert_eq!(store.blob_gc()?, 0);
+    assert_eq!(store.stats()?.keys, 2);
+    Ok(())
+}
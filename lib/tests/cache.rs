@@ -0,0 +1,75 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use tempfile::TempDir;
+
+fn cached_store(temp_dir: &TempDir, cache_size: u64) -> Result<KvStore> {
+    KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            cache_size,
+            ..Default::default()
+        },
+    )
+}
+
+#[test]
+fn cache_disabled_by_default() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.cache_stats(), None);
+    Ok(())
+}
+
+// Repeated reads are served from the cache, writes never leave a stale value behind
+#[test]
+fn cache_hits_and_invalidation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = cached_store(&temp_dir, 1024)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats().expect("cache is enabled");
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!((stats.entries, stats.bytes), (1, 10));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let stats = store.cache_stats().expect("cache is enabled");
+    assert_eq!((stats.hits, stats.misses), (1, 3));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.compaction()?;
+    assert_eq!(store.cache_stats().expect("cache is enabled").entries, 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// The cache never holds more bytes than it was given, evicting the least recently used values
+#[test]
+fn cache_size_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = cached_store(&temp_dir, 25)?;
+    for key in ["key1", "key2", "key3"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.set("big".to_owned(), "x".repeat(100))?;
+
+    store.get("key1".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.get("big".to_owned())?;
+    let stats = store.cache_stats().expect("cache is enabled");
+    assert!(stats.bytes <= stats.capacity);
+    assert_eq!((stats.entries, stats.evictions), (2, 1));
+
+    // key2 was evicted, key1 survived
+    store.get("key1".to_owned())?;
+    store.get("key2".to_owned())?;
+    let stats = store.cache_stats().expect("cache is enabled");
+    assert_eq!((stats.hits, stats.misses), (2, 5));
+    Ok(())
+}
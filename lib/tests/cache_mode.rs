@@ -0,0 +1,144 @@
+use kvs::cache_mode::{CacheOptions, EvictionPolicy};
+use kvs::{CacheEngine, DbError, KvStore, KvsEngine, Result};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn cache(temp_dir: &TempDir, options: CacheOptions) -> Result<CacheEngine<KvStore>> {
+    CacheEngine::new(KvStore::open(temp_dir.path())?, options)
+}
+
+fn keys(engine: &impl KvsEngine) -> Result<Vec<String>> {
+    let mut keys = engine
+        .scan("")?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    keys.sort();
+    Ok(keys)
+}
+
+#[test]
+fn lru_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = cache(
+        &temp_dir,
+        CacheOptions {
+            max_keys: Some(2),
+            ..Default::default()
+        },
+    )?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.get("key1".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(keys(&engine)?, ["key1", "key3"]);
+    assert_eq!(engine.cache_stats().evictions, 1);
+
+    // Evicted keys are gone from the wrapped engine too
+    assert_eq!(keys(engine.inner())?, ["key1", "key3"]);
+    Ok(())
+}
+
+#[test]
+fn lfu_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = cache(
+        &temp_dir,
+        CacheOptions {
+            max_keys: Some(2),
+            policy: EvictionPolicy::Lfu,
+            ..Default::default()
+        },
+    )?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.get("key1".to_owned())?;
+    engine.get("key1".to_owned())?;
+    engine.get("key2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(keys(&engine)?, ["key1", "key3"]);
+
+    // Updating a hot key keeps what it was used for
+    engine.get("key3".to_owned())?;
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    engine.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(keys(&engine)?, ["key1", "key5"]);
+    Ok(())
+}
+
+#[test]
+fn memory_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = cache(
+        &temp_dir,
+        CacheOptions {
+            max_bytes: Some(30),
+            ..Default::default()
+        },
+    )?;
+    for id in 0..10 {
+        engine.set(format!("key{id}"), "value".to_owned())?;
+        assert!(engine.cache_stats().bytes <= 30);
+    }
+    assert_eq!(keys(&engine)?, ["key7", "key8", "key9"]);
+    assert_eq!(engine.cache_stats().evictions, 7);
+    assert!(matches!(
+        engine.set("big".to_owned(), "x".repeat(30)),
+        Err(DbError::OverBudget {
+            size: 33,
+            limit: 30
+        })
+    ));
+    assert_eq!(keys(&engine)?.len(), 3);
+    Ok(())
+}
+
+// Expired keys read as missing, and are evicted first by the TTL-first policy
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = cache(
+        &temp_dir,
+        CacheOptions {
+            ttl: Some(Duration::from_millis(100)),
+            policy: EvictionPolicy::TtlFirst,
+            max_keys: Some(2),
+            ..Default::default()
+        },
+    )?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(10));
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    // Recently read, but still the closest to expiring
+    engine.get("key1".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(keys(&engine)?, ["key2", "key3"]);
+
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert!(keys(&engine)?.is_empty());
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(keys(engine.inner())?, ["key4"]);
+    assert_eq!(engine.cache_stats().expired, 2);
+    Ok(())
+}
+
+// Keys the wrapped engine already holds count against the budget
+#[test]
+fn existing_keys_are_tracked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for id in 0..5 {
+        store.set(format!("key{id}"), "value".to_owned())?;
+    }
+    let engine = CacheEngine::new(
+        store,
+        CacheOptions {
+            max_keys: Some(3),
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(engine.cache_stats().entries, 3);
+    assert_eq!(keys(&engine)?.len(), 3);
+    Ok(())
+}
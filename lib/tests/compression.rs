@@ -0,0 +1,104 @@
+use kvs::{Codec, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn compressed_store(path: &Path, compression: Codec) -> Result<KvStore> {
+    KvStore::open_with(
+        path,
+        KvStoreOptions {
+            compression,
+            ..Default::default()
+        },
+    )
+}
+
+fn json_value(id: u32) -> String {
+    format!(
+        r#"{{"id": {id}, "name": "user {id}", "tags": ["alpha", "beta", "gamma"], "description": "{}"}}"#,
+        "lorem ipsum ".repeat(20)
+    )
+}
+
+fn log_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// Compressed values read back the same through get, replay and compaction
+#[test]
+fn compressed_values_roundtrip() -> Result<()> {
+    for codec in [Codec::Lz4, Codec::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = compressed_store(temp_dir.path(), codec)?;
+        for id in 0..100 {
+            store.set(format!("key{id}"), json_value(id))?;
+        }
+        let stats = store.compression_stats();
+        assert_eq!((stats.records, stats.compressed), (100, 100));
+        assert!(stats.ratio() > 2.0, "{codec:?} ratio {}", stats.ratio());
+        assert!(log_size(temp_dir.path()) < stats.raw_bytes / 2);
+        assert_eq!(store.get("key7".to_owned())?, Some(json_value(7)));
+
+        drop(store);
+        let mut store = compressed_store(temp_dir.path(), codec)?;
+        assert_eq!(store.get("key42".to_owned())?, Some(json_value(42)));
+        store.compaction()?;
+        for id in 0..100 {
+            assert_eq!(store.get(format!("key{id}"))?, Some(json_value(id)));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn short_values_stay_plain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = compressed_store(temp_dir.path(), Codec::Zstd)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let stats = store.compression_stats();
+    assert_eq!((stats.records, stats.compressed), (2, 0));
+    assert_eq!(stats.ratio(), 1.0);
+    let log = fs::read_to_string(temp_dir.path().join("kv_00001.log"))?;
+    assert!(log.starts_with("SET("));
+    Ok(())
+}
+
+// Records are decoded with the codec they were written with, compaction moves them to the current one
+#[test]
+fn codec_change_applies_on_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = compressed_store(temp_dir.path(), Codec::Lz4)?;
+    store.set("key1".to_owned(), json_value(1))?;
+    drop(store);
+
+    let mut store = compressed_store(temp_dir.path(), Codec::None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some(json_value(1)));
+    store.compaction()?;
+    assert_eq!(store.compression_stats().compressed, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(json_value(1)));
+    let log = fs::read_to_string(temp_dir.path().join("kv_00002.log"))?;
+    assert!(log.contains("lorem ipsum"));
+    Ok(())
+}
+
+#[test]
+fn corrupted_record_is_detected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = compressed_store(temp_dir.path(), Codec::Zstd)?;
+    store.set("key1".to_owned(), json_value(1))?;
+    drop(store);
+
+    let segment = temp_dir.path().join("kv_00001.log");
+    let mut contents = fs::read(&segment)?;
+    let last = contents.len() - 2;
+    contents[last] ^= 0xff;
+    fs::write(&segment, contents)?;
+    assert!(compressed_store(temp_dir.path(), Codec::Zstd).is_err());
+    Ok(())
+}
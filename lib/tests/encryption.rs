@@ -0,0 +1,147 @@
+use kvs::{Codec, DbError, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn key(byte: u8) -> EncryptionKey {
+    EncryptionKey::from_bytes([byte; 32])
+}
+
+fn encrypted_store(path: &Path, current: Option<u8>, previous: &[u8]) -> Result<KvStore> {
+    KvStore::open_with(
+        path,
+        KvStoreOptions {
+            encryption_key: current.map(key),
+            previous_keys: previous.iter().copied().map(key).collect(),
+            blob_threshold: Some(100),
+            compression: Codec::Lz4,
+            ..Default::default()
+        },
+    )
+}
+
+/// `true` if any file in `dir` contains `needle`
+fn appears_on_disk(dir: &Path, needle: &str) -> bool {
+    fs::read_dir(dir).unwrap().flatten().any(|entry| {
+        let contents = fs::read(entry.path()).unwrap();
+        contents
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    })
+}
+
+fn fill(store: &mut KvStore) -> Result<()> {
+    store.set("customer-1234".to_owned(), "secret-value".to_owned())?;
+    store.set("customer-5678".to_owned(), "secret-blob ".repeat(20))?;
+    store.set("customer-9999".to_owned(), "secret-long ".repeat(10))?;
+    store.remove("customer-9999".to_owned())
+}
+
+// Neither keys nor values show up in the log, blob, hint or index files
+#[test]
+fn nothing_in_the_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = encrypted_store(temp_dir.path(), Some(1), &[])?;
+    fill(&mut store)?;
+    store.compaction()?;
+    store.set("customer-0000".to_owned(), "secret-tail".to_owned())?;
+    store.persist_index()?;
+    drop(store);
+    assert!(!appears_on_disk(temp_dir.path(), "customer"));
+    assert!(!appears_on_disk(temp_dir.path(), "secret"));
+
+    let store = encrypted_store(temp_dir.path(), Some(1), &[])?;
+    assert_eq!(
+        store.get("customer-1234".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(
+        store.get("customer-5678".to_owned())?,
+        Some("secret-blob ".repeat(20))
+    );
+    assert_eq!(store.get("customer-9999".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn missing_or_wrong_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = encrypted_store(temp_dir.path(), Some(1), &[])?;
+    fill(&mut store)?;
+    drop(store);
+    assert!(matches!(
+        encrypted_store(temp_dir.path(), None, &[]),
+        Err(DbError::Encryption(_))
+    ));
+    assert!(matches!(
+        encrypted_store(temp_dir.path(), Some(2), &[]),
+        Err(DbError::Encryption(_))
+    ));
+    Ok(())
+}
+
+// Data sealed with the previous key stays readable, and compaction moves it to the new one
+#[test]
+fn key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = encrypted_store(temp_dir.path(), Some(1), &[])?;
+    fill(&mut store)?;
+    drop(store);
+
+    let mut store = encrypted_store(temp_dir.path(), Some(2), &[1])?;
+    assert_eq!(
+        store.get("customer-5678".to_owned())?,
+        Some("secret-blob ".repeat(20))
+    );
+    store.compaction()?;
+    drop(store);
+
+    let store = encrypted_store(temp_dir.path(), Some(2), &[])?;
+    assert_eq!(
+        store.get("customer-1234".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(
+        store.get("customer-5678".to_owned())?,
+        Some("secret-blob ".repeat(20))
+    );
+    Ok(())
+}
+
+// A store written in the clear gets encrypted by compacting it with a key
+#[test]
+fn encrypt_existing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = encrypted_store(temp_dir.path(), None, &[])?;
+    fill(&mut store)?;
+    drop(store);
+    assert!(appears_on_disk(temp_dir.path(), "customer"));
+
+    let mut store = encrypted_store(temp_dir.path(), Some(1), &[])?;
+    store.compaction()?;
+    drop(store);
+    assert!(!appears_on_disk(temp_dir.path(), "customer"));
+    assert!(!appears_on_disk(temp_dir.path(), "secret"));
+    let store = encrypted_store(temp_dir.path(), Some(1), &[])?;
+    assert_eq!(
+        store.get("customer-5678".to_owned())?,
+        Some("secret-blob ".repeat(20))
+    );
+    Ok(())
+}
+
+#[test]
+fn key_parsing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hex_file = temp_dir.path().join("hex.key");
+    fs::write(&hex_file, format!("{}\n", "0a".repeat(32)))?;
+    let raw_file = temp_dir.path().join("raw.key");
+    fs::write(&raw_file, [0x0a; 32])?;
+    assert_eq!(
+        format!("{:?}", EncryptionKey::from_file(&hex_file)?),
+        format!("{:?}", EncryptionKey::from_file(&raw_file)?)
+    );
+    assert!(EncryptionKey::from_hex("0a0b").is_err());
+    assert!(EncryptionKey::from_hex(&"zz".repeat(32)).is_err());
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use assert_cmd::prelude::*;
+use kvs::inspect::{inspect, LogEntry, LogFilter, Op};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use regex::Regex;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn fill(dir: &Path) -> Result<()> {
+    let mut store = KvStore::open_with(
+        dir,
+        KvStoreOptions {
+            blob_threshold: Some(100),
+            ..Default::default()
+        },
+    )?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:2".to_owned(), "b".repeat(200))?;
+    store.set("order:1".to_owned(), "pending".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    Ok(())
+}
+
+// Positions line up with what reading the log at them gives
+#[test]
+fn lists_every_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let entries = inspect(temp_dir.path(), None, &[], &LogFilter::default())?;
+    let summary: Vec<(u64, Op, &str, Option<u64>)> = entries
+        .iter()
+        .map(|entry| (entry.seq, entry.op, entry.key.as_str(), entry.value_size))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (1, Op::Set, "user:1", Some(5)),
+            (2, Op::Blob, "user:2", Some(200)),
+            (3, Op::Set, "order:1", Some(7)),
+            (4, Op::Rm, "user:1", None),
+        ]
+    );
+    assert_eq!(entries[0].offset, 0);
+    for pair in entries.windows(2) {
+        assert_eq!(pair[1].offset, pair[0].offset + pair[0].len + 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let keys = |filter: LogFilter| -> Result<Vec<(u64, String)>> {
+        Ok(inspect(temp_dir.path(), None, &[], &filter)?
+            .into_iter()
+            .map(|entry| (entry.seq, entry.key))
+            .collect())
+    };
+    assert_eq!(
+        keys(LogFilter {
+            key: Some("user:1".to_owned()),
+            ..Default::default()
+        })?,
+        vec![(1, "user:1".to_owned()), (4, "user:1".to_owned())]
+    );
+    assert_eq!(
+        keys(LogFilter {
+            pattern: Some(Regex::new("^user:").unwrap()),
+            ops: vec![Op::Set, Op::Blob],
+            ..Default::default()
+        })?,
+        vec![(1, "user:1".to_owned()), (2, "user:2".to_owned())]
+    );
+    assert_eq!(
+        keys(LogFilter {
+            ops: vec![Op::Rm],
+            ..Default::default()
+        })?,
+        vec![(4, "user:1".to_owned())]
+    );
+    Ok(())
+}
+
+#[test]
+fn cli_json_lines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log", "inspect", "--json", "--op", "rm"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let lines: Vec<LogEntry> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<_>>()?;
+    assert_eq!(lines.len(), 1);
+    assert_eq!((lines[0].op, lines[0].key.as_str()), (Op::Rm, "user:1"));
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use kvs::{DbError, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+
+fn read_only() -> KvStoreOptions {
+    KvStoreOptions {
+        read_only: true,
+        ..Default::default()
+    }
+}
+
+// Only one writer at a time, and the one turned away learns who holds the store
+#[test]
+fn second_writer_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(err @ DbError::Locked { pid, .. }) => {
+            assert_eq!(pid, Some(std::process::id()));
+            assert!(err
+                .to_string()
+                .contains(&format!("process {}", std::process::id())));
+        }
+        other => panic!("expected the store to be locked, got {other:?}"),
+    }
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+#[test]
+fn read_only_alongside_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = KvStore::open_with(temp_dir.path(), read_only())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key2".to_owned(), "value2".to_owned()),
+        Err(DbError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(DbError::ReadOnly)
+    ));
+    assert!(matches!(reader.compaction(), Err(DbError::ReadOnly)));
+    assert!(matches!(reader.persist_index(), Err(DbError::ReadOnly)));
+
+    // The writer carries on undisturbed
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(writer.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_only_creates_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), read_only())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use kvs::lsm::LsmOptions;
+use kvs::{DbError, KvsEngine, LsmStore, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// Small enough for a few thousand writes to go through every level
+fn tiny_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 1 << 10,
+        block_size: 256,
+        table_size: 2 << 10,
+        level0_tables: 2,
+        level1_size: 4 << 10,
+        level_ratio: 2,
+    }
+}
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    // Reopening replays the WAL
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.tables_per_level(), Vec::<usize>::new());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Values stay correct as they move from the memtable down the levels
+#[test]
+fn flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open_with(temp_dir.path(), tiny_options())?;
+    for round in 0..5 {
+        for id in 0..500 {
+            store.set(format!("key{id:04}"), format!("value{round}-{id}"))?;
+        }
+    }
+    for id in (0..500).step_by(3) {
+        store.remove(format!("key{id:04}"))?;
+    }
+    let levels = store.tables_per_level();
+    assert!(levels.len() > 2, "expected several levels, got {levels:?}");
+    assert!(levels[0] < 2);
+
+    drop(store);
+    let store = LsmStore::open_with(temp_dir.path(), tiny_options())?;
+    for id in 0..500 {
+        let expected = (id % 3 != 0).then(|| format!("value4-{id}"));
+        assert_eq!(store.get(format!("key{id:04}"))?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn scan_merges_levels() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open_with(temp_dir.path(), tiny_options())?;
+    for id in 0..300 {
+        store.set(format!("a{id:03}"), "old".to_owned())?;
+        store.set(format!("b{id:03}"), "old".to_owned())?;
+    }
+    store.flush()?;
+    store.set("a001".to_owned(), "new".to_owned())?;
+    store.remove("a002".to_owned())?;
+
+    let pairs = store.scan("a")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 299);
+    assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert!(pairs.iter().all(|(key, _)| key.starts_with('a')));
+    assert_eq!(pairs[1], ("a001".to_owned(), "new".to_owned()));
+    assert_eq!(pairs[2].0, "a003");
+    assert_eq!(store.scan("")?.count(), 599);
+    Ok(())
+}
+
+// Syncing moves the memtable into a table, leaving the WAL empty
+#[test]
+fn sync_flushes_memtable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(fs::metadata(temp_dir.path().join("lsm.wal"))?.len() > 0);
+    store.sync()?;
+    assert_eq!(fs::metadata(temp_dir.path().join("lsm.wal"))?.len(), 0);
+    assert_eq!(store.tables_per_level(), vec![1]);
+
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A write cut short by a crash leaves half a record at the end of the WAL, which is dropped on reopening
+#[test]
+fn torn_wal_tail_is_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal = temp_dir.path().join("lsm.wal");
+    let mut store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let sound = fs::read(&wal)?;
+    let second = sound[..sound.len() - 1]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .unwrap()
+        + 1;
+    let mut torn = sound.clone();
+    torn.extend_from_slice(&sound[second..second + (sound.len() - second) / 2]);
+    fs::write(&wal, torn)?;
+
+    let mut store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(fs::read(&wal)?, sound);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Damage before the tail is not a crash
+    let mut contents = fs::read(&wal)?;
+    contents[1] = b'#';
+    fs::write(&wal, contents)?;
+    assert!(matches!(
+        LsmStore::open(temp_dir.path()),
+        Err(DbError::Corrupted(_))
+    ));
+    Ok(())
+}
+
+// A footer whose index offset and length only add up to the table size by overflowing is refused
+#[test]
+fn overflowing_footer_is_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.sync()?;
+    drop(store);
+
+    let table = temp_dir.path().join("lsm_00001.sst");
+    let mut contents = fs::read(&table)?;
+    let footer = contents.len() - 24;
+    let index_len = (contents.len() as u64 - 24).wrapping_add(1);
+    contents[footer..footer + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    contents[footer + 8..footer + 16].copy_from_slice(&index_len.to_le_bytes());
+    fs::write(&table, contents)?;
+    assert!(matches!(
+        LsmStore::open(temp_dir.path()),
+        Err(DbError::Corrupted(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use kvs::manifest::{format_version, Manifest, MANIFEST_FILE};
+use kvs::{Codec, DbError, KvStore, KvStoreOptions, KvsEngine, LsmStore, Result, SledKvsEngine};
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn records_engine_segments_and_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            compression: Codec::Zstd,
+            ..Default::default()
+        },
+    )?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let manifest = Manifest::load(temp_dir.path())?.expect("manifest written on open");
+    assert_eq!(manifest.engine, "kvs");
+    assert_eq!(Some(manifest.format_version), format_version("kvs"));
+    assert_eq!(manifest.segments, vec!["kv_00001.log"]);
+    assert_eq!(manifest.options["compression"], "zstd");
+
+    // Compaction replaces every segment
+    store.compaction()?;
+    let manifest = Manifest::load(temp_dir.path())?.unwrap();
+    assert_eq!(manifest.segments, vec!["kv_00002.log", "kv_00003.log"]);
+    drop(store);
+
+    // Options given later don't change what the store was created with
+    KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            compression: Codec::Lz4,
+            ..Default::default()
+        },
+    )?;
+    let reopened = Manifest::load(temp_dir.path())?.unwrap();
+    assert_eq!(reopened.options["compression"], "zstd");
+    assert_eq!(reopened.created, manifest.created);
+    Ok(())
+}
+
+#[test]
+fn refuses_other_engines() -> Result<()> {
+    let lsm_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(LsmStore::open(lsm_dir.path())?);
+    assert_eq!(Manifest::load(lsm_dir.path())?.unwrap().engine, "lsm");
+    let err = KvStore::open(lsm_dir.path()).unwrap_err();
+    assert!(matches!(err, DbError::Incompatible(_)), "{err:?}");
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(kvs_dir.path())?);
+    assert!(matches!(
+        LsmStore::open(kvs_dir.path()),
+        Err(DbError::Incompatible(_))
+    ));
+    assert!(matches!(
+        SledKvsEngine::open(kvs_dir.path()),
+        Err(DbError::Incompatible(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn refuses_unknown_format_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    let path = temp_dir.path().join(MANIFEST_FILE);
+    let contents = fs::read_to_string(&path)?;
+    fs::write(
+        &path,
+        contents.replace("format_version: 1", "format_version: 99"),
+    )?;
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(matches!(err, DbError::Incompatible(_)), "{err:?}");
+    assert!(err.to_string().contains("format version 99"));
+    // Reading is refused just the same
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    Ok(())
+}
+
+#[test]
+fn older_stores_get_one() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join(MANIFEST_FILE))?;
+
+    // Readers leave the directory alone
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(Manifest::load(temp_dir.path())?.is_none());
+
+    drop(KvStore::open(temp_dir.path())?);
+    let manifest = Manifest::load(temp_dir.path())?.unwrap();
+    assert_eq!(manifest.segments, vec!["kv_00001.log"]);
+    Ok(())
+}
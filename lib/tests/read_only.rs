@@ -0,0 +1,77 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use tempfile::TempDir;
+
+#[test]
+fn refresh_picks_up_appends() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!reader.refresh()?);
+
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    writer.remove("key1".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert!(reader.refresh()?);
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!reader.refresh()?);
+    Ok(())
+}
+
+// New segments are followed into, and a compaction by the writer makes the reader start over
+#[test]
+fn refresh_follows_segments_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    let value = "x".repeat(1_000);
+    for id in 0..3_000 {
+        writer.set(format!("key{id}"), format!("{id}{value}"))?;
+    }
+    assert!(reader.refresh()?);
+    for id in [0, 1_500, 2_999] {
+        assert_eq!(
+            reader.get(format!("key{id}"))?,
+            Some(format!("{id}{value}"))
+        );
+    }
+
+    writer.compaction()?;
+    writer.set("key0".to_owned(), "new".to_owned())?;
+    assert!(reader.refresh()?);
+    assert_eq!(reader.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(
+        reader.get("key2999".to_owned())?,
+        Some(format!("2999{value}"))
+    );
+    Ok(())
+}
+
+// A command the writer is still in the middle of appending is picked up once complete
+#[test]
+fn partial_command_is_left_for_later() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = fs::read_dir(temp_dir.path())?.count();
+
+    let segment = temp_dir.path().join("kv_00001.log");
+    let mut log = OpenOptions::new().append(true).open(&segment)?;
+    log.write_all(br#"SET((key: "key2", "#)?;
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert!(!reader.refresh()?);
+
+    log.write_all(b"value: \"value2\"))\n")?;
+    assert!(reader.refresh()?);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), files);
+    Ok(())
+}
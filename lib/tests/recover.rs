@@ -0,0 +1,207 @@
+use assert_cmd::prelude::*;
+use kvs::inspect::{inspect, LogFilter};
+use kvs::recover::{recover, Until};
+use kvs::{DbError, KvStore, KvStoreOptions, KvsEngine, Result};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+/// Writes #1 to #5, the first value of `b` held in a blob file
+fn fill(dir: &Path) -> Result<()> {
+    let mut store = KvStore::open_with(
+        dir,
+        KvStoreOptions {
+            blob_threshold: Some(100),
+            ..Default::default()
+        },
+    )?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".repeat(200))?;
+    store.set("a".to_owned(), "3".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.set("c".to_owned(), "5".to_owned())?;
+    Ok(())
+}
+
+fn contents(dir: &Path) -> Result<Vec<(String, String)>> {
+    let store = KvStore::open_read_only(dir)?;
+    let mut pairs = store.scan("")?.collect::<Result<Vec<_>>>()?;
+    pairs.sort();
+    Ok(pairs)
+}
+
+#[test]
+fn up_to_a_sequence_number() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    fill(src.path())?;
+
+    let report = recover(src.path(), &dst.path().join("3"), Until::Seq(3), None, &[])?;
+    assert_eq!((report.replayed, report.skipped, report.keys), (3, 2, 2));
+    assert_eq!(report.seq, 3);
+    assert_eq!(
+        contents(&dst.path().join("3"))?,
+        vec![
+            ("a".to_owned(), "3".to_owned()),
+            ("b".to_owned(), "2".repeat(200))
+        ]
+    );
+    // Recovered writes keep their stamps, in order
+    let seqs: Vec<u64> = inspect(&dst.path().join("3"), None, &[], &LogFilter::default())?
+        .iter()
+        .map(|entry| entry.seq)
+        .collect();
+    assert_eq!(seqs, vec![2, 3]);
+
+    recover(src.path(), &dst.path().join("4"), Until::Seq(4), None, &[])?;
+    assert_eq!(
+        contents(&dst.path().join("4"))?,
+        vec![("a".to_owned(), "3".to_owned())]
+    );
+    // Numbering carries on after a restart
+    let mut store = KvStore::open(dst.path().join("4"))?;
+    store.set("d".to_owned(), "6".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(dst.path().join("4"))?;
+    store.set("e".to_owned(), "7".to_owned())?;
+    let last = inspect(&dst.path().join("4"), None, &[], &LogFilter::default())?;
+    assert_eq!(last.last().unwrap().seq, 5);
+    Ok(())
+}
+
+#[test]
+fn up_to_a_time() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(src.path())?;
+    store.set("a".to_owned(), "before".to_owned())?;
+    thread::sleep(Duration::from_millis(20));
+    let point = SystemTime::now();
+    thread::sleep(Duration::from_millis(20));
+    store.set("a".to_owned(), "after".to_owned())?;
+    store.set("b".to_owned(), "after".to_owned())?;
+
+    let report = recover(src.path(), dst.path(), Until::Time(point), None, &[])?;
+    assert_eq!((report.replayed, report.skipped), (1, 2));
+    assert_eq!(
+        contents(dst.path())?,
+        vec![("a".to_owned(), "before".to_owned())]
+    );
+    Ok(())
+}
+
+#[test]
+fn refuses_compacted_history() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    fill(src.path())?;
+    let mut store = KvStore::open(src.path())?;
+    store.compaction()?;
+    store.set("d".to_owned(), "6".to_owned())?;
+    drop(store);
+
+    let err = recover(src.path(), &dst.path().join("2"), Until::Seq(2), None, &[]).unwrap_err();
+    assert!(matches!(err, DbError::Recover(_)), "{err:?}");
+    // Anything from the compaction on is still there
+    recover(src.path(), &dst.path().join("5"), Until::Seq(5), None, &[])?;
+    assert_eq!(
+        contents(&dst.path().join("5"))?,
+        vec![
+            ("a".to_owned(), "3".to_owned()),
+            ("c".to_owned(), "5".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// Without the hint of the compacted segment, nothing tells where history was cut: recovery goes on, but says so
+#[test]
+fn reports_segments_without_hint() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    fill(src.path())?;
+    let mut store = KvStore::open(src.path())?;
+    store.compaction()?;
+    store.set("d".to_owned(), "6".to_owned())?;
+    drop(store);
+    let hints: Vec<_> = fs::read_dir(src.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .collect();
+    assert_eq!(hints.len(), 1);
+    fs::remove_file(&hints[0])?;
+    let segment = hints[0].with_extension("log");
+
+    let report = recover(src.path(), &dst.path().join("2"), Until::Seq(2), None, &[])?;
+    assert_eq!(
+        report.unchecked,
+        vec![segment.file_name().unwrap().to_string_lossy().into_owned()]
+    );
+    assert!(report.to_string().contains("hints are missing"));
+    Ok(())
+}
+
+#[test]
+fn from_a_backup() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let backup = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    fill(src.path())?;
+    let mut store = KvStore::open(src.path())?;
+    store.backup_to(backup.path(), None)?;
+    drop(store);
+
+    recover(backup.path(), dst.path(), Until::Seq(2), None, &[])?;
+    assert_eq!(
+        contents(dst.path())?,
+        vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".repeat(200))
+        ]
+    );
+    assert!(!dst.path().join(".recover-source").exists());
+    Ok(())
+}
+
+#[test]
+fn cli_recover() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    fill(src.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["recover", "--until", "1"])
+        .arg(src.path())
+        .arg(dst.path())
+        .assert()
+        .success()
+        .stdout(contains("Recovered 1 keys from 1 records, up to write #1"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "a"])
+        .current_dir(&dst)
+        .assert()
+        .success()
+        .stdout(contains("1"));
+    // The target must be empty
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["recover", "--until", "1h"])
+        .arg(src.path())
+        .arg(dst.path())
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["recover", "--until", "yesterday-ish"])
+        .arg(src.path())
+        .arg(dst.path())
+        .assert()
+        .failure()
+        .stderr(contains("expected a sequence number"));
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use kvs::repair::repair;
+use kvs::verify::verify;
+use kvs::{Codec, DbError, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn compressed(dir: &Path) -> Result<KvStore> {
+    KvStore::open_with(
+        dir,
+        KvStoreOptions {
+            compression: Codec::Zstd,
+            compress_min_size: 100,
+            ..Default::default()
+        },
+    )
+}
+
+/// Plain and framed records in a single segment
+fn fill(dir: &Path) -> Result<()> {
+    let mut store = compressed(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("packed".to_owned(), "z".repeat(500))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    Ok(())
+}
+
+/// Overwrite `len` bytes of the segment, starting at the first occurrence of `at`
+fn damage(dir: &Path, at: &[u8], len: usize) {
+    let segment = dir.join("kv_00001.log");
+    let mut contents = fs::read(&segment).unwrap();
+    let start = contents
+        .windows(at.len())
+        .position(|window| window == at)
+        .unwrap();
+    contents[start..start + len].fill(b'#');
+    fs::write(&segment, contents).unwrap();
+}
+
+#[test]
+fn skips_damaged_plain_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let original = fs::read(temp_dir.path().join("kv_00001.log"))?;
+    damage(temp_dir.path(), b"key: \"key2", 2);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = repair(temp_dir.path(), None, &[])?;
+    assert_eq!(report.recovered, 4);
+    assert_eq!(report.lost.len(), 1);
+    assert_eq!(report.lost[0].file, "kv_00001.log");
+    assert_eq!(
+        report.lost[0].offset,
+        original.iter().position(|&b| b == b'\n').unwrap() as u64 + 1
+    );
+    assert!(report.lost[0].keys.is_empty());
+    assert!(report.to_string().contains("Recovered 4 records"));
+    assert!(temp_dir.path().join("kv_00001.log.corrupt").exists());
+    assert!(verify(temp_dir.path(), None, &[])?.is_ok());
+
+    let store = compressed(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("packed".to_owned())?, Some("z".repeat(500)));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// A framed record whose header is damaged is skipped too, up to the next readable record
+#[test]
+fn skips_damaged_framed_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    damage(temp_dir.path(), &[0xF5], 3);
+
+    let report = repair(temp_dir.path(), None, &[])?;
+    assert_eq!(report.recovered, 4);
+    let store = compressed(temp_dir.path())?;
+    assert_eq!(store.get("packed".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A header announcing a huge payload is corrupt, not a reason to allocate gigabytes
+#[test]
+fn skips_framed_record_with_bogus_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let segment = temp_dir.path().join("kv_00001.log");
+    let mut contents = fs::read(&segment)?;
+    let start = contents.iter().position(|&b| b == 0xF5).unwrap();
+    contents[start + 2..start + 6].fill(0xFF);
+    fs::write(&segment, contents)?;
+
+    let problems = verify(temp_dir.path(), None, &[])?;
+    assert!(!problems.is_ok());
+    assert!(problems.to_string().contains("more than the limit"));
+
+    let report = repair(temp_dir.path(), None, &[])?;
+    assert_eq!(report.recovered, 4);
+    let store = compressed(temp_dir.path())?;
+    assert_eq!(store.get("packed".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn reports_keys_of_lost_plain_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    damage(temp_dir.path(), b"value: \"value3", 1);
+
+    let report = repair(temp_dir.path(), None, &[])?;
+    assert_eq!(report.lost[0].keys, vec!["key3".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn sound_store_is_left_alone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let before = fs::read(temp_dir.path().join("kv_00001.log"))?;
+    let report = repair(temp_dir.path(), None, &[])?;
+    assert_eq!((report.recovered, report.lost.len()), (5, 0));
+    assert_eq!(fs::read(temp_dir.path().join("kv_00001.log"))?, before);
+    assert!(!temp_dir.path().join("kv_00001.log.corrupt").exists());
+    Ok(())
+}
+
+#[test]
+fn refuses_open_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let _store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        repair(temp_dir.path(), None, &[]),
+        Err(DbError::Locked { .. })
+    ));
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use kvs::cache_mode::CacheOptions;
+use kvs::{
+    CacheEngine, Codec, EngineStats, KvStore, KvStoreOptions, KvsEngine, LsmStore, Result,
+    SledKvsEngine,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+fn subdir(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::create_dir(&path).expect("unable to create store directory");
+    path
+}
+
+#[test]
+fn kvs_stats_track_live_and_stale_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            cache_size: 1 << 10,
+            blob_threshold: Some(100),
+            ..Default::default()
+        },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "x".repeat(200))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.get("key1".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.keys, 2);
+    assert!(stats.live_bytes > 200);
+    assert!(stats.stale_bytes > 0);
+    assert_eq!(stats.segments, 1);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction, None);
+    assert_eq!(stats.cache.map(|cache| cache.misses), Some(1));
+
+    store.compaction()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.keys, 2);
+    assert_eq!(compacted.stale_bytes, 0);
+    assert_eq!(compacted.live_bytes, stats.live_bytes);
+    assert_eq!(compacted.compactions, 1);
+    assert!(compacted.last_compaction.is_some());
+    Ok(())
+}
+
+// Stats go over the wire as JSON and come back unchanged
+#[test]
+fn stats_round_trip_through_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let stats = store.stats()?;
+    let parsed: EngineStats = serde_json::from_str(&serde_json::to_string(&stats)?)?;
+    assert_eq!(parsed, stats);
+    assert!(stats.to_string().contains("keys: 1\n"));
+    Ok(())
+}
+
+#[test]
+fn other_engines_report_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut lsm = LsmStore::open(subdir(temp_dir.path(), "lsm"))?;
+    lsm.set("key1".to_owned(), "value1".to_owned())?;
+    lsm.set("key2".to_owned(), "value2".to_owned())?;
+    lsm.remove("key2".to_owned())?;
+    let stats = lsm.stats()?;
+    assert_eq!((stats.engine.as_str(), stats.keys), ("lsm", 1));
+    assert_eq!(stats.live_bytes, 10);
+    assert!(stats.stale_bytes > 0);
+
+    let mut sled = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    sled.set("key1".to_owned(), "value1".to_owned())?;
+    let stats = sled.stats()?;
+    assert_eq!((stats.engine.as_str(), stats.keys), ("sled", 1));
+
+    let store = KvStore::open_with(
+        subdir(temp_dir.path(), "kvs"),
+        KvStoreOptions {
+            cache_size: 1024,
+            compression: Codec::Lz4,
+            ..Default::default()
+        },
+    )?;
+    let mut cached = CacheEngine::new(
+        store,
+        CacheOptions {
+            max_keys: Some(1),
+            ..Default::default()
+        },
+    )?;
+    cached.set("key1".to_owned(), "value1".to_owned())?;
+    cached.set("key2".to_owned(), "value2".to_owned())?;
+    cached.get("key2".to_owned())?;
+    let stats = cached.stats()?;
+    assert_eq!((stats.engine.as_str(), stats.keys), ("kvs", 1));
+    assert_eq!(stats.cache_mode.map(|cache| cache.evictions), Some(1));
+    // The read cache and compression figures of the wrapped store come through
+    assert_eq!(stats.cache, cached.inner().stats()?.cache);
+    assert!(stats.cache.is_some());
+    assert!(stats
+        .compression
+        .is_some_and(|compression| compression.records > 0));
+    Ok(())
+}
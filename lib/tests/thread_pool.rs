@@ -0,0 +1,80 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Result, SharedEngine};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use tempfile::TempDir;
+
+const JOBS: usize = 50;
+
+/// Run `JOBS` jobs on `pool` and wait for all of them
+fn run_jobs<P: ThreadPool>(pool: &P) {
+    let done = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..JOBS {
+        let (done, sender) = (done.clone(), sender.clone());
+        pool.spawn(move || {
+            done.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..JOBS {
+        receiver.recv().unwrap();
+    }
+    assert_eq!(done.load(Ordering::SeqCst), JOBS);
+}
+
+/// A pool keeps serving after jobs panicked, even with every one of its threads taken down once.
+/// The panics are expected, and print like any other
+fn survives_panics<P: ThreadPool>(pool: &P) {
+    for _ in 0..4 {
+        pool.spawn(|| panic!("job failed on purpose"));
+    }
+    run_jobs(pool);
+}
+
+#[test]
+fn naive_pool() -> Result<()> {
+    let pool = NaiveThreadPool::new(4)?;
+    run_jobs(&pool);
+    survives_panics(&pool);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_pool() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    run_jobs(&pool);
+    survives_panics(&pool);
+    Ok(())
+}
+
+#[test]
+fn rayon_pool() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    run_jobs(&pool);
+    survives_panics(&pool);
+    Ok(())
+}
+
+// Writes from every thread land in the one engine
+#[test]
+fn shared_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SharedEngine::new(KvStore::open(temp_dir.path())?);
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (sender, receiver) = mpsc::channel();
+    for id in 0..JOBS {
+        let (mut engine, sender) = (engine.clone(), sender.clone());
+        pool.spawn(move || {
+            let result = engine.set(format!("key{id}"), format!("value{id}"));
+            sender.send(result).unwrap();
+        });
+    }
+    for _ in 0..JOBS {
+        receiver.recv().unwrap()?;
+    }
+    assert_eq!(engine.scan("key")?.count(), JOBS);
+    assert_eq!(engine.get("key7".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(engine.stats()?.keys, JOBS as u64);
+    Ok(())
+}
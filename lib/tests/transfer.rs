@@ -0,0 +1,104 @@
+use kvs::transfer::{export, import, Format, ImportSummary, OnConflict};
+use kvs::{DbError, KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+fn seeded_store(temp_dir: &TempDir) -> Result<KvStore> {
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set(
+        "user:2".to_owned(),
+        "bob, \"the builder\"\nline two".to_owned(),
+    )?;
+    store.set("user:1".to_owned(), "carol".to_owned())?;
+    store.set("order:1".to_owned(), "{\"total\": 3}".to_owned())?;
+    store.set("order:2".to_owned(), "stale".to_owned())?;
+    store.remove("order:2".to_owned())?;
+    Ok(store)
+}
+
+// Export should only contain live values, and import should reproduce them
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    for format in [Format::Jsonl, Format::Csv] {
+        let source_dir = TempDir::new().expect("unable to create temporary working directory");
+        let source = seeded_store(&source_dir)?;
+        let mut dump = vec![];
+        assert_eq!(export(&source, format, "", &mut dump)?, 3);
+
+        let target_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut target = KvStore::open(target_dir.path())?;
+        let summary = import(&mut target, format, "", OnConflict::Fail, dump.as_slice())?;
+        assert_eq!(summary.imported, 3);
+        assert_eq!(target.get("user:1".to_owned())?, Some("carol".to_owned()));
+        assert_eq!(
+            target.get("user:2".to_owned())?,
+            Some("bob, \"the builder\"\nline two".to_owned())
+        );
+        assert_eq!(
+            target.get("order:1".to_owned())?,
+            Some("{\"total\": 3}".to_owned())
+        );
+        assert_eq!(target.get("order:2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn prefix_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = seeded_store(&temp_dir)?;
+    let mut dump = vec![];
+    assert_eq!(export(&store, Format::Jsonl, "user:", &mut dump)?, 2);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let summary = import(
+        &mut store,
+        Format::Jsonl,
+        "user:1",
+        OnConflict::Overwrite,
+        dump.as_slice(),
+    )?;
+    assert_eq!(
+        summary,
+        ImportSummary {
+            imported: 1,
+            skipped: 0,
+            filtered: 1
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn import_conflicts() -> Result<()> {
+    let dump = "{\"key\":\"a\",\"value\":\"new\"}\n{\"key\":\"b\",\"value\":\"new\"}\n";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("a".to_owned(), "old".to_owned())?;
+    let summary = import(
+        &mut store,
+        Format::Jsonl,
+        "",
+        OnConflict::Skip,
+        dump.as_bytes(),
+    )?;
+    assert_eq!((summary.imported, summary.skipped), (1, 1));
+    assert_eq!(store.get("a".to_owned())?, Some("old".to_owned()));
+
+    assert!(matches!(
+        import(&mut store, Format::Jsonl, "", OnConflict::Fail, dump.as_bytes()),
+        Err(DbError::KeyExists(key)) if key == "a"
+    ));
+
+    import(
+        &mut store,
+        Format::Jsonl,
+        "",
+        OnConflict::Overwrite,
+        dump.as_bytes(),
+    )?;
+    assert_eq!(store.get("a".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
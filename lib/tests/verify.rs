@@ -0,0 +1,137 @@
+use assert_cmd::prelude::*;
+use kvs::verify::verify;
+use kvs::{Codec, KvStore, KvStoreOptions, KvsEngine, Result};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Two segments, the first one frozen with a hint and starting with a compressed record, and an up to date index
+fn fill(dir: &Path) -> Result<()> {
+    let mut store = KvStore::open_with(
+        dir,
+        KvStoreOptions {
+            compression: Codec::Lz4,
+            compress_min_size: 1_500,
+            blob_threshold: Some(2_000),
+            ..Default::default()
+        },
+    )?;
+    store.set("packed".to_owned(), "z".repeat(1_800))?;
+    let value = "x".repeat(1_000);
+    for id in 0..1_100 {
+        store.set(format!("key{id:04}"), format!("{id}{value}"))?;
+    }
+    store.set("blob".to_owned(), "y".repeat(3_000))?;
+    store.remove("key0000".to_owned())?;
+    store.persist_index()
+}
+
+fn replace_in(path: &Path, from: &str, to: &str) {
+    let contents = fs::read_to_string(path).unwrap();
+    assert!(contents.contains(from));
+    fs::write(path, contents.replacen(from, to, 1)).unwrap();
+}
+
+#[test]
+fn sound_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let report = verify(temp_dir.path(), None, &[])?;
+    assert!(report.is_ok(), "{report}");
+    assert_eq!(report.segments, 2);
+    assert_eq!(report.records, 1_103);
+    assert_eq!(report.hints, 1);
+    assert!(report.index);
+    Ok(())
+}
+
+// A flipped bit in a framed record is caught by its checksum, and the records after it are still checked
+#[test]
+fn checksum_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let segment = temp_dir.path().join("kv_00001.log");
+    let mut contents = fs::read(&segment)?;
+    contents[20] ^= 0x01;
+    fs::write(&segment, contents)?;
+
+    let report = verify(temp_dir.path(), None, &[])?;
+    assert_eq!(report.records, 1_102);
+    let problem = &report.problems[0];
+    assert_eq!(
+        (problem.file.as_str(), problem.offset),
+        ("kv_00001.log", Some(0))
+    );
+    assert!(problem.message.contains("checksum"));
+    Ok(())
+}
+
+#[test]
+fn truncated_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let segment = temp_dir.path().join("kv_00002.log");
+    let len = fs::metadata(&segment)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&segment)?
+        .set_len(len - 5)?;
+
+    let report = verify(temp_dir.path(), None, &[])?;
+    assert!(report
+        .problems
+        .iter()
+        .any(|problem| problem.file == "kv_00002.log" && problem.message.contains("cut short")));
+    Ok(())
+}
+
+// Hints and the index that disagree with the log are reported along with where the log has the key
+#[test]
+fn hint_and_index_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    replace_in(&temp_dir.path().join("kv_00001.hint"), "key0005", "kex0005");
+    replace_in(
+        &temp_dir.path().join("kv_memory.index"),
+        "key0007",
+        "kex0007",
+    );
+
+    let report = verify(temp_dir.path(), None, &[])?;
+    let messages: Vec<String> = report.problems.iter().map(ToString::to_string).collect();
+    assert_eq!(messages.len(), 3, "{messages:?}");
+    assert!(messages[0].contains("hint has \"kex0005\""));
+    assert!(messages[1].contains("points \"kex0007\" here, the log has no such key"));
+    assert!(messages[2].contains("\"key0007\" is set here but missing"));
+    Ok(())
+}
+
+#[test]
+fn cli_exit_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("0 problems"));
+
+    fs::write(temp_dir.path().join("kv_00003.log"), "SET((key: \"a\"\n")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .code(1)
+        .stdout(contains("kv_00003.log at byte 0"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", "no/such/store"])
+        .assert()
+        .code(2);
+    Ok(())
+}